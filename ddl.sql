@@ -1,11 +1,36 @@
 CREATE SCHEMA IF NOT EXISTS codeharmony;
 
+DROP TABLE IF EXISTS codeharmony.session_question_vote;
+DROP TABLE IF EXISTS codeharmony.session_question;
+DROP TABLE IF EXISTS codeharmony.session_pulse;
+DROP TABLE IF EXISTS codeharmony.cluster_message;
+DROP TABLE IF EXISTS codeharmony.session_room_host;
+DROP TABLE IF EXISTS codeharmony.session_event;
+DROP TABLE IF EXISTS codeharmony.session_group_code;
+DROP TABLE IF EXISTS codeharmony.session_group_member;
+DROP TABLE IF EXISTS codeharmony.session_group;
+DROP TABLE IF EXISTS codeharmony.session_control_log;
+DROP TABLE IF EXISTS codeharmony.session_poll_answer;
+DROP TABLE IF EXISTS codeharmony.session_poll;
+DROP TABLE IF EXISTS codeharmony.session_help_request;
+DROP TABLE IF EXISTS codeharmony.session_chat_message;
 DROP TABLE IF EXISTS codeharmony.code_submission;
+DROP TABLE IF EXISTS codeharmony.lesson_session_class;
 DROP TABLE IF EXISTS codeharmony.lesson_session;
 DROP TABLE IF EXISTS codeharmony.lesson_plan_section;
 DROP TABLE IF EXISTS codeharmony.lesson_plan;
 DROP TABLE IF EXISTS codeharmony.published_lesson_plan_section;
 DROP TABLE IF EXISTS codeharmony.published_lesson_plan;
+DROP TABLE IF EXISTS codeharmony.api_token;
+DROP TABLE IF EXISTS codeharmony.user_session;
+DROP TABLE IF EXISTS codeharmony.audit_log;
+DROP TABLE IF EXISTS codeharmony.login_throttle;
+DROP TABLE IF EXISTS codeharmony.email_change;
+DROP TABLE IF EXISTS codeharmony.password_reset;
+DROP TABLE IF EXISTS codeharmony.teacher_join_request;
+DROP TABLE IF EXISTS codeharmony.class_member;
+DROP TABLE IF EXISTS codeharmony.teacher_code;
+DROP TABLE IF EXISTS codeharmony.teacher_class;
 DROP TABLE IF EXISTS codeharmony.student_teacher;
 DROP TABLE IF EXISTS codeharmony.users;
 
@@ -14,9 +39,16 @@ CREATE TABLE codeharmony.users (
 	username VARCHAR(32) NOT NULL UNIQUE,
 	hash CHAR(96) not null,
 	email VARCHAR(32) not null unique,
+	display_name VARCHAR(64),
+	-- Set for accounts made by a teacher, until the student picks their own password
+	must_change_password BOOLEAN NOT NULL DEFAULT false,
+	role VARCHAR(16) NOT NULL DEFAULT 'student',
+	-- Moved on to log the account out everywhere
+	session_epoch INT4 NOT NULL DEFAULT 0,
 
 	CONSTRAINT users_pk PRIMARY KEY (username),
-    CONSTRAINT username_min_length CHECK (length(username) >= 3)
+    CONSTRAINT username_min_length CHECK (length(username) >= 3),
+	CONSTRAINT users_role_check CHECK (role IN ('student', 'teacher', 'admin'))
 );
 
 CREATE TABLE codeharmony.lesson_plan (
@@ -24,7 +56,7 @@ CREATE TABLE codeharmony.lesson_plan (
 	username VARCHAR(32) NOT NULL,
 
 	CONSTRAINT lesson_plan_pk PRIMARY KEY (plan_name,username),
-	CONSTRAINT lesson_plan_username_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE
+	CONSTRAINT lesson_plan_username_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
 );
 
 CREATE TABLE codeharmony.published_lesson_plan (
@@ -33,7 +65,7 @@ CREATE TABLE codeharmony.published_lesson_plan (
 	description VARCHAR(300) NOT NULL DEFAULT '',
 
 	CONSTRAINT published_lesson_plan_pk PRIMARY KEY (plan_name,username),
-	CONSTRAINT published_lesson_plan_username_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE
+	CONSTRAINT published_lesson_plan_username_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
 );
 
 CREATE TABLE codeharmony.lesson_session (
@@ -43,7 +75,7 @@ CREATE TABLE codeharmony.lesson_session (
     username VARCHAR(32) NOT NULL,
 	
 	CONSTRAINT lesson_session_pk PRIMARY KEY (session_name,plan_name,username),
-	CONSTRAINT lesson_session_plan_name_fk FOREIGN KEY (plan_name,username) REFERENCES codeharmony.lesson_plan(plan_name,username) ON DELETE CASCADE
+	CONSTRAINT lesson_session_plan_name_fk FOREIGN KEY (plan_name,username) REFERENCES codeharmony.lesson_plan(plan_name,username) ON DELETE CASCADE ON UPDATE CASCADE
 );
 
 CREATE TABLE codeharmony.lesson_plan_section (
@@ -56,7 +88,7 @@ CREATE TABLE codeharmony.lesson_plan_section (
 	section_type CHAR(8) NOT NULL,
 
 	CONSTRAINT lesson_plan_section_pk PRIMARY KEY (plan_name,username,section_name),
-	CONSTRAINT lesson_plan_section_plan_fk FOREIGN KEY (username,plan_name) REFERENCES codeharmony.lesson_plan(username,plan_name) ON DELETE CASCADE,
+	CONSTRAINT lesson_plan_section_plan_fk FOREIGN KEY (username,plan_name) REFERENCES codeharmony.lesson_plan(username,plan_name) ON DELETE CASCADE ON UPDATE CASCADE,
 	CONSTRAINT plan_section_name_length CHECK (length(section_name) >= 1)
 );
 
@@ -70,19 +102,22 @@ CREATE TABLE codeharmony.published_lesson_plan_section (
 	section_type CHAR(8) NOT NULL,
 
 	CONSTRAINT published_lesson_plan_section_pk PRIMARY KEY (plan_name,username,section_name),
-	CONSTRAINT published_lesson_plan_section_plan_fk FOREIGN KEY (username,plan_name) REFERENCES codeharmony.published_lesson_plan(username,plan_name) ON DELETE CASCADE,
+	CONSTRAINT published_lesson_plan_section_plan_fk FOREIGN KEY (username,plan_name) REFERENCES codeharmony.published_lesson_plan(username,plan_name) ON DELETE CASCADE ON UPDATE CASCADE,
 	CONSTRAINT published_plan_section_name_length CHECK (length(section_name) >= 1)
 );
 
 CREATE TABLE codeharmony.student_teacher(
 	student_un VARCHAR (32) NOT NULL,
 	teacher_un VARCHAR (32) NOT NULL,
+	joined_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
 
 	CONSTRAINT student_teacher_pk PRIMARY KEY (student_un, teacher_un),
-	CONSTRAINT teacher_un_fk FOREIGN KEY (teacher_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE, 
-	CONSTRAINT student_un_fk FOREIGN KEY (student_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE
+	CONSTRAINT teacher_un_fk FOREIGN KEY (teacher_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE, 
+	CONSTRAINT student_un_fk FOREIGN KEY (student_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
 );
 
+-- A rename reaches submissions through three keys at once, so their checks can wait for the commit
+-- Submissions hang off the student rather than their link to the teacher, so they outlive the student leaving
 CREATE TABLE codeharmony.code_submission(
 	teacher_un VARCHAR (32) NOT NULL,
 	plan_name VARCHAR(128) NOT NULL,
@@ -92,12 +127,327 @@ CREATE TABLE codeharmony.code_submission(
 	code TEXT NOT NULL DEFAULT '',
 	correct BOOLEAN NOT NULL DEFAULT false,
 	CONSTRAINT code_submission_pk PRIMARY KEY (teacher_un, plan_name, section_name, session_name, student_un),
-	CONSTRAINT code_submission_plan_fk FOREIGN KEY (teacher_un,plan_name,section_name) REFERENCES codeharmony.lesson_plan_section(username,plan_name,section_name) ON DELETE CASCADE,
-	CONSTRAINT code_submission_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE,
-	CONSTRAINT code_submission_student_teacher_fk FOREIGN KEY (teacher_un, student_un) REFERENCES codeharmony.student_teacher(teacher_un, student_un) ON DELETE CASCADE
+	CONSTRAINT code_submission_plan_fk FOREIGN KEY (teacher_un,plan_name,section_name) REFERENCES codeharmony.lesson_plan_section(username,plan_name,section_name) ON DELETE CASCADE ON UPDATE CASCADE DEFERRABLE,
+	CONSTRAINT code_submission_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE DEFERRABLE,
+	CONSTRAINT code_submission_student_fk FOREIGN KEY (student_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE DEFERRABLE
 );
 
-INSERT INTO codeharmony.users (username,hash,email) VALUES('user1','$argon2id$v=19$m=4096,t=3,p=1$mthVV+FY4YjPzyui4crAUA$0Hp4NgFmf4fLJzrtyitrUYUxL07HDCMax0/9HX9TEps','zacxalot@gmail.com');
+CREATE TABLE codeharmony.session_chat_message(
+	message_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	sender_un VARCHAR (32) NOT NULL,
+	recipient_un VARCHAR (32),
+	group_name VARCHAR(64),
+	text VARCHAR(1000) NOT NULL,
+	sent_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	deleted_by VARCHAR (32),
+	deleted_at TIMESTAMP,
+	CONSTRAINT session_chat_message_pk PRIMARY KEY (message_id),
+	CONSTRAINT session_chat_message_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX session_chat_message_session_idx ON codeharmony.session_chat_message(teacher_un, plan_name, session_name, sent_at, message_id);
+
+CREATE TABLE codeharmony.session_help_request(
+	request_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	note VARCHAR(300) NOT NULL DEFAULT '',
+	raised_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	claimed_at TIMESTAMP,
+	resolved_at TIMESTAMP,
+	resolved_by VARCHAR (32),
+	CONSTRAINT session_help_request_pk PRIMARY KEY (request_id),
+	CONSTRAINT session_help_request_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_poll(
+	poll_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	kind VARCHAR(16) NOT NULL,
+	question VARCHAR(300) NOT NULL,
+	options JSONB NOT NULL DEFAULT '[]',
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	closed_at TIMESTAMP,
+	revealed BOOLEAN NOT NULL DEFAULT false,
+	CONSTRAINT session_poll_pk PRIMARY KEY (poll_id),
+	CONSTRAINT session_poll_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_poll_answer(
+	poll_id CHAR(36) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	answer INT2 NOT NULL,
+	answered_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT session_poll_answer_pk PRIMARY KEY (poll_id, student_un),
+	CONSTRAINT session_poll_answer_poll_fk FOREIGN KEY (poll_id) REFERENCES codeharmony.session_poll(poll_id) ON DELETE CASCADE
+);
+
+CREATE TABLE codeharmony.session_control_log(
+	log_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	action VARCHAR(16) NOT NULL,
+	student_un VARCHAR (32),
+	detail TEXT,
+	logged_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT session_control_log_pk PRIMARY KEY (log_id),
+	CONSTRAINT session_control_log_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_group(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	group_name VARCHAR(64) NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	ended_at TIMESTAMP,
+	CONSTRAINT session_group_pk PRIMARY KEY (teacher_un, plan_name, session_name, group_name),
+	CONSTRAINT session_group_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_group_member(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	group_name VARCHAR(64) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	CONSTRAINT session_group_member_pk PRIMARY KEY (teacher_un, plan_name, session_name, student_un),
+	CONSTRAINT session_group_member_group_fk FOREIGN KEY (teacher_un, plan_name, session_name, group_name) REFERENCES codeharmony.session_group(teacher_un, plan_name, session_name, group_name) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_group_code(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	group_name VARCHAR(64) NOT NULL,
+	section_name VARCHAR(64) NOT NULL,
+	code TEXT NOT NULL DEFAULT '',
+	CONSTRAINT session_group_code_pk PRIMARY KEY (teacher_un, plan_name, session_name, group_name, section_name),
+	CONSTRAINT session_group_code_group_fk FOREIGN KEY (teacher_un, plan_name, session_name, group_name) REFERENCES codeharmony.session_group(teacher_un, plan_name, session_name, group_name) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_event(
+	event_id BIGSERIAL NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	kind VARCHAR(16) NOT NULL,
+	username VARCHAR (32),
+	data JSONB NOT NULL DEFAULT '{}',
+	occurred_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT session_event_pk PRIMARY KEY (event_id),
+	CONSTRAINT session_event_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX session_event_session_idx ON codeharmony.session_event(teacher_un, plan_name, session_name, occurred_at, event_id);
+CREATE INDEX session_event_student_idx ON codeharmony.session_event(teacher_un, username, occurred_at);
+
+CREATE TABLE codeharmony.session_room_host(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	node_id VARCHAR(40) NOT NULL,
+	claimed_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT session_room_host_pk PRIMARY KEY (plan_name, session_name, teacher_un),
+	CONSTRAINT session_room_host_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.cluster_message(
+	message_id VARCHAR(36) NOT NULL,
+	payload TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT cluster_message_pk PRIMARY KEY (message_id)
+);
+
+CREATE TABLE codeharmony.session_pulse(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	section_index INT4 NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	state VARCHAR(8),
+	updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT session_pulse_pk PRIMARY KEY (plan_name, session_name, teacher_un, section_index, student_un),
+	CONSTRAINT session_pulse_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_question(
+	question_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	text VARCHAR(300) NOT NULL,
+	asked_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	answered_at TIMESTAMP,
+	dismissed_at TIMESTAMP,
+	CONSTRAINT session_question_pk PRIMARY KEY (question_id),
+	CONSTRAINT session_question_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.session_question_vote(
+	question_id CHAR(36) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	CONSTRAINT session_question_vote_pk PRIMARY KEY (question_id, student_un),
+	CONSTRAINT session_question_vote_question_fk FOREIGN KEY (question_id) REFERENCES codeharmony.session_question(question_id) ON DELETE CASCADE
+);
+
+CREATE TABLE codeharmony.teacher_class(
+	class_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	class_name VARCHAR(64) NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT teacher_class_pk PRIMARY KEY (class_id),
+	CONSTRAINT teacher_class_name_unique UNIQUE (teacher_un, class_name),
+	CONSTRAINT teacher_class_teacher_fk FOREIGN KEY (teacher_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE codeharmony.class_member(
+	class_id CHAR(36) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	joined_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT class_member_pk PRIMARY KEY (class_id, student_un),
+	CONSTRAINT class_member_class_fk FOREIGN KEY (class_id) REFERENCES codeharmony.teacher_class(class_id) ON DELETE CASCADE,
+	CONSTRAINT class_member_student_fk FOREIGN KEY (student_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- Sessions without any classes are open to all of the teacher's students
+CREATE TABLE codeharmony.lesson_session_class(
+	teacher_un VARCHAR (32) NOT NULL,
+	plan_name VARCHAR(128) NOT NULL,
+	session_name VARCHAR(128) NOT NULL,
+	class_id CHAR(36) NOT NULL,
+	CONSTRAINT lesson_session_class_pk PRIMARY KEY (plan_name, session_name, teacher_un, class_id),
+	CONSTRAINT lesson_session_class_session_fk FOREIGN KEY (plan_name, session_name, teacher_un) REFERENCES codeharmony.lesson_session(plan_name, session_name, username) ON DELETE CASCADE ON UPDATE CASCADE,
+	CONSTRAINT lesson_session_class_class_fk FOREIGN KEY (class_id) REFERENCES codeharmony.teacher_class(class_id) ON DELETE CASCADE
+);
+
+CREATE TABLE codeharmony.teacher_code(
+	code CHAR(6) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	-- Codes for a class also put the student in that class
+	class_id CHAR(36),
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	expires_at TIMESTAMP NOT NULL,
+	max_uses INT4,
+	uses INT4 NOT NULL DEFAULT 0,
+	revoked_at TIMESTAMP,
+	-- Students using the code have to be accepted by the teacher
+	requires_approval BOOLEAN NOT NULL DEFAULT false,
+	CONSTRAINT teacher_code_pk PRIMARY KEY (code),
+	CONSTRAINT teacher_code_teacher_fk FOREIGN KEY (teacher_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE,
+	CONSTRAINT teacher_code_class_fk FOREIGN KEY (class_id) REFERENCES codeharmony.teacher_class(class_id) ON DELETE CASCADE
+);
+
+-- A teacher only has one code at a time, and one for each class
+CREATE UNIQUE INDEX teacher_code_current_idx ON codeharmony.teacher_code(teacher_un, coalesce(class_id, '')) WHERE revoked_at IS NULL;
+
+-- Students waiting for a teacher to let them in
+CREATE TABLE codeharmony.teacher_join_request(
+	request_id CHAR(36) NOT NULL,
+	teacher_un VARCHAR (32) NOT NULL,
+	student_un VARCHAR (32) NOT NULL,
+	class_id CHAR(36),
+	requested_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT teacher_join_request_pk PRIMARY KEY (request_id),
+	CONSTRAINT teacher_join_request_teacher_fk FOREIGN KEY (teacher_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE,
+	CONSTRAINT teacher_join_request_student_fk FOREIGN KEY (student_un) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE,
+	CONSTRAINT teacher_join_request_class_fk FOREIGN KEY (class_id) REFERENCES codeharmony.teacher_class(class_id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX teacher_join_request_student_idx ON codeharmony.teacher_join_request(teacher_un, student_un, coalesce(class_id, ''));
+
+-- Only a hash of each token is kept
+CREATE TABLE codeharmony.password_reset(
+	token_hash CHAR(64) NOT NULL,
+	username VARCHAR (32) NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	expires_at TIMESTAMP NOT NULL,
+	used_at TIMESTAMP,
+	CONSTRAINT password_reset_pk PRIMARY KEY (token_hash),
+	CONSTRAINT password_reset_user_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- A new email waiting to be confirmed from its inbox, again only keeping a hash of the token
+CREATE TABLE codeharmony.email_change(
+	token_hash CHAR(64) NOT NULL,
+	username VARCHAR (32) NOT NULL,
+	email VARCHAR(32) NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	expires_at TIMESTAMP NOT NULL,
+	CONSTRAINT email_change_pk PRIMARY KEY (token_hash),
+	CONSTRAINT email_change_user_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- Failed logins, counted per username and per IP address
+-- Not linked to users, since guesses at usernames that don't exist count too
+CREATE TABLE codeharmony.login_throttle(
+	kind VARCHAR(8) NOT NULL,
+	subject VARCHAR(64) NOT NULL,
+	failures INT4 NOT NULL DEFAULT 0,
+	last_failure_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	locked_until TIMESTAMP,
+	CONSTRAINT login_throttle_pk PRIMARY KEY (kind, subject)
+);
+
+-- Security events worth looking back on, kept even after the account is gone
+CREATE TABLE codeharmony.audit_log(
+	log_id BIGSERIAL NOT NULL,
+	kind VARCHAR(32) NOT NULL,
+	username VARCHAR(32),
+	ip VARCHAR(64),
+	detail JSONB NOT NULL DEFAULT '{}',
+	logged_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+	CONSTRAINT audit_log_pk PRIMARY KEY (log_id)
+);
+
+CREATE INDEX audit_log_username_idx ON codeharmony.audit_log(username, logged_at);
+
+-- Logged in sessions, the cookie holds a key and only its hash is kept here
+-- username and the rest are copied out of state so a user's sessions can be listed
+CREATE TABLE codeharmony.user_session(
+	key_hash CHAR(64) NOT NULL,
+	session_id CHAR(36),
+	username VARCHAR (32),
+	state TEXT NOT NULL,
+	user_agent VARCHAR(256),
+	ip VARCHAR(64),
+	created_at TIMESTAMP NOT NULL,
+	last_seen TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+	CONSTRAINT user_session_pk PRIMARY KEY (key_hash),
+	CONSTRAINT user_session_user_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX user_session_username_idx ON codeharmony.user_session(username, last_seen);
+
+-- Personal API tokens for scripts, sent as bearer tokens and only kept as a hash
+-- scopes limit which routes a token works on, see Scope in principal.rs
+CREATE TABLE codeharmony.api_token(
+	token_id CHAR(36) NOT NULL,
+	username VARCHAR (32) NOT NULL,
+	name VARCHAR(64) NOT NULL,
+	token_hash CHAR(64) NOT NULL,
+	scopes VARCHAR(32)[] NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+	last_used_at TIMESTAMP,
+	CONSTRAINT api_token_pk PRIMARY KEY (token_id),
+	CONSTRAINT api_token_hash_unique UNIQUE (token_hash),
+	CONSTRAINT api_token_name_unique UNIQUE (username, name),
+	CONSTRAINT api_token_user_fk FOREIGN KEY (username) REFERENCES codeharmony.users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+INSERT INTO codeharmony.users (username,hash,email,role) VALUES('user1','$argon2id$v=19$m=4096,t=3,p=1$mthVV+FY4YjPzyui4crAUA$0Hp4NgFmf4fLJzrtyitrUYUxL07HDCMax0/9HX9TEps','zacxalot@gmail.com','admin');
+INSERT INTO codeharmony.student_teacher (student_un, teacher_un) VALUES('user1', 'user1');
 --INSERT INTO codeharmony.users (username) VALUES('SamG');
 --INSERT INTO codeharmony.users (username) VALUES('AlG');
 --INSERT INTO codeharmony.users (username) VALUES('FergieF');
@@ -0,0 +1,1145 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use actix::{
+    fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, Handler, MailboxError,
+    Message, Recipient, ResponseActFuture, ResponseFuture, StreamHandler, WrapFuture,
+};
+use deadpool_postgres::Pool;
+use futures::{
+    channel::{mpsc, oneshot},
+    future, stream, StreamExt,
+};
+use postgres_native_tls::MakeTlsConnector;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_postgres::{AsyncMessage, Client, Notification};
+
+use crate::{
+    actors::{
+        room_connection::dispatch_command,
+        sse_session::{stream_node, SendCommand, SseSessionManager},
+        ws_server::{
+            execute_in_background, rename::RenameUser, timers::CheckSubmissionOpen, GetStudentData,
+            Leave, RecordRunResult, SessionIdentifier, SessionServer, StudentData, WSResponse,
+        },
+    },
+    utils::error::CodeHarmonyResponseError,
+};
+
+// Every node listens on its own channel, and names its listening connection the same
+// so other nodes can tell whether it's still alive
+const CHANNEL_PREFIX: &str = "codeharmony_";
+
+// Longest node id kept, so the channel name fits in a postgres identifier
+const MAX_NODE_ID_LENGTH: usize = 40;
+
+// Notification payloads must be under 8000 bytes, anything bigger goes through a table
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+const MESSAGE_REF_PREFIX: &str = "ref:";
+
+// How long to wait before trying to listen again after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// How long the node hosting a room gets to answer a query before it's treated as unreachable
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Node ids end up in channel names, so only keep characters that are safe there
+pub fn sanitize_node_id(node_id: &str) -> String {
+    node_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .map(|c| {
+            if c == '-' {
+                '_'
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .take(MAX_NODE_ID_LENGTH)
+        .collect()
+}
+
+fn channel_name(node_id: &str) -> String {
+    format!("{}{}", CHANNEL_PREFIX, node_id)
+}
+
+// What gets sent between nodes
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Envelope {
+    // A client on the origin node sent a command to a room hosted here
+    Command {
+        origin: String,
+        client_id: String,
+        username: String,
+        command: String,
+        payload: String,
+    },
+    // A client on the origin node disconnected or moved to another room
+    Leave {
+        origin: String,
+        client_id: String,
+    },
+    // A room sent something to a client connected to the receiving node
+    Response {
+        client_id: String,
+        message: Option<String>,
+    },
+    // A room gave a client connected to the receiving node a new username
+    Renamed {
+        client_id: String,
+        username: String,
+    },
+    // An account changed its username, so rooms on every node need to follow it
+    // Its other login sessions were logged out at the same time
+    RenameUser {
+        old: String,
+        new: String,
+        keep_session: Option<String>,
+    },
+    // Some of an account's logins stopped working, so their connections need closing
+    LogOut {
+        username: String,
+        logged_out: LoggedOut,
+    },
+    // An endpoint on the origin node wants something from a room hosted here
+    Query {
+        origin: String,
+        query_id: String,
+        query: RoomQuery,
+    },
+    // A command was posted on the origin node for an event stream open here
+    // Answered with whether the stream was found
+    StreamCommand {
+        origin: String,
+        query_id: String,
+        command: SendCommand,
+    },
+    // The answer to a query this node sent
+    Reply {
+        query_id: String,
+        reply: QueryReply,
+    },
+}
+
+// What endpoints ask of a room, which might be hosted on another node
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "query")]
+enum RoomQuery {
+    CheckSubmissionOpen(CheckSubmissionOpen),
+    GetStudentData(GetStudentData),
+    RecordRunResult(RecordRunResult),
+}
+
+impl RoomQuery {
+    fn identifier(&self) -> &SessionIdentifier {
+        match self {
+            RoomQuery::CheckSubmissionOpen(msg) => &msg.identifier,
+            RoomQuery::GetStudentData(msg) => &msg.identifier,
+            RoomQuery::RecordRunResult(msg) => &msg.identifier,
+        }
+    }
+}
+
+type QueryReply = Result<serde_json::Value, CodeHarmonyResponseError>;
+
+fn from_reply<T: DeserializeOwned>(reply: QueryReply) -> Result<T, CodeHarmonyResponseError> {
+    serde_json::from_value(reply?).map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::WebsocketsUnavailable
+    })
+}
+
+fn to_reply<T: Serialize>(
+    reply: Result<Result<T, CodeHarmonyResponseError>, MailboxError>,
+) -> QueryReply {
+    let value = reply.map_err(|_| CodeHarmonyResponseError::WebsocketsUnavailable)??;
+    serde_json::to_value(value).map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::WebsocketsUnavailable
+    })
+}
+
+// Ask the rooms on this node
+async fn answer_query(session_server: Addr<SessionServer>, query: RoomQuery) -> QueryReply {
+    match query {
+        RoomQuery::CheckSubmissionOpen(msg) => to_reply(session_server.send(msg).await),
+        RoomQuery::GetStudentData(msg) => to_reply(session_server.send(msg).await),
+        RoomQuery::RecordRunResult(msg) => to_reply(session_server.send(msg).await.map(Ok)),
+    }
+}
+
+// Lets rooms be hosted on whichever node the teacher connected to,
+// with students on other nodes talking to them through Postgres notifications
+pub struct Cluster {
+    node_id: String,
+    pg_config: tokio_postgres::Config,
+    connector: MakeTlsConnector,
+    db_pool: Pool,
+    session_server: Addr<SessionServer>,
+    sse_sessions: Addr<SseSessionManager>,
+    // Clients on this node talking to rooms on other nodes
+    clients: HashMap<String, Recipient<WSResponse>>,
+    // Stand-ins for clients on other nodes talking to rooms here, by origin node and client id
+    proxies: HashMap<(String, String), Addr<RemoteClient>>,
+    listener: Option<Arc<Client>>,
+    outbox: mpsc::UnboundedSender<(String, String)>,
+    // Queries this node is waiting on other nodes to answer, by query id
+    queries: HashMap<String, oneshot::Sender<QueryReply>>,
+    // Every client connected to this node, by client id, so logging out can close them
+    connections: HashMap<String, OpenConnection>,
+}
+
+struct OpenConnection {
+    username: String,
+    login_session: Option<String>,
+    addr: Recipient<WSResponse>,
+}
+
+// Which of an account's connections to close once it's been logged out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LoggedOut {
+    // Everything, connections made with API tokens included
+    Everywhere,
+    // Connections made from one login session
+    Session(String),
+    // Connections made from any login session, except one if given
+    SessionsExcept(Option<String>),
+}
+
+impl LoggedOut {
+    fn covers(&self, login_session: Option<&str>) -> bool {
+        match (self, login_session) {
+            (LoggedOut::Everywhere, _) => true,
+            (LoggedOut::Session(session_id), Some(login_session)) => session_id == login_session,
+            (LoggedOut::SessionsExcept(keep), Some(login_session)) => {
+                keep.as_deref() != Some(login_session)
+            }
+            (_, None) => false,
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(
+        node_id: &str,
+        pg_config: tokio_postgres::Config,
+        connector: MakeTlsConnector,
+        db_pool: Pool,
+        session_server: Addr<SessionServer>,
+        sse_sessions: Addr<SseSessionManager>,
+    ) -> Self {
+        let (outbox, outgoing) = mpsc::unbounded();
+        actix::spawn(send_notifications(db_pool.clone(), outgoing));
+
+        Self {
+            node_id: sanitize_node_id(node_id),
+            pg_config,
+            connector,
+            db_pool,
+            session_server,
+            sse_sessions,
+            clients: HashMap::new(),
+            proxies: HashMap::new(),
+            listener: None,
+            outbox,
+            queries: HashMap::new(),
+            connections: HashMap::new(),
+        }
+    }
+
+    fn publish(&self, node_id: &str, envelope: &Envelope) {
+        match serde_json::to_string(envelope) {
+            Ok(payload) => {
+                if self
+                    .outbox
+                    .unbounded_send((channel_name(node_id), payload))
+                    .is_err()
+                {
+                    eprintln!("Cluster outbox closed");
+                }
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+
+    // Open a connection just for receiving notifications meant for this node
+    fn listen(&mut self, ctx: &mut Context<Self>) {
+        let channel = channel_name(&self.node_id);
+        let mut pg_config = self.pg_config.clone();
+        pg_config.application_name(&channel);
+        let connector = self.connector.clone();
+
+        let connect = async move { pg_config.connect(connector).await };
+        ctx.wait(connect.into_actor(self).map(move |result, act, ctx| {
+            let (client, mut connection) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    ctx.run_later(RECONNECT_DELAY, |act, ctx| act.listen(ctx));
+                    return;
+                }
+            };
+
+            // Polling the connection drives the client too, so it ends when either fails
+            let notifications = stream::poll_fn(move |cx| connection.poll_message(cx))
+                .take_while(|message| future::ready(message.is_ok()))
+                .filter_map(|message| {
+                    future::ready(match message {
+                        Ok(AsyncMessage::Notification(notification)) => Some(notification),
+                        _ => None,
+                    })
+                });
+            ctx.add_stream(notifications);
+
+            let client = Arc::new(client);
+            act.listener = Some(client.clone());
+            actix::spawn(async move {
+                if let Err(e) = client.batch_execute(&format!("LISTEN {}", channel)).await {
+                    eprintln!("{:?}", e);
+                }
+            });
+            println!("Cluster node {} listening", act.node_id);
+        }));
+    }
+
+    fn receive(&mut self, envelope: Envelope, ctx: &mut Context<Self>) {
+        match envelope {
+            Envelope::Command {
+                origin,
+                client_id,
+                username,
+                command,
+                payload,
+            } => {
+                let cluster = ctx.address();
+                let session_server = self.session_server.clone();
+                let proxy = self
+                    .proxies
+                    .entry((origin.to_owned(), client_id.to_owned()))
+                    .or_insert_with(|| {
+                        RemoteClient {
+                            origin,
+                            client_id,
+                            cluster,
+                            session_server,
+                            connected_session: None,
+                        }
+                        .start()
+                    });
+                proxy.do_send(RemoteCommand {
+                    username,
+                    command,
+                    payload,
+                });
+            }
+            Envelope::Leave { origin, client_id } => {
+                if let Some(proxy) = self.proxies.remove(&(origin, client_id)) {
+                    proxy.do_send(RemoteLeave);
+                }
+            }
+            Envelope::Response { client_id, message } => {
+                if let Some(addr) = self.clients.get(&client_id) {
+                    addr.do_send(match message {
+                        Some(message) => WSResponse::Msg(message),
+                        None => WSResponse::Close,
+                    });
+                }
+            }
+            Envelope::Renamed {
+                client_id,
+                username,
+            } => {
+                if let Some(addr) = self.clients.get(&client_id) {
+                    addr.do_send(WSResponse::Renamed(username));
+                }
+            }
+            Envelope::RenameUser {
+                old,
+                new,
+                keep_session,
+            } => self.rename_user(old, new, keep_session),
+            Envelope::LogOut {
+                username,
+                logged_out,
+            } => self.close_connections(&username, &logged_out),
+            Envelope::Query {
+                origin,
+                query_id,
+                query,
+            } => {
+                let answer = answer_query(self.session_server.clone(), query);
+                ctx.spawn(answer.into_actor(self).map(move |reply, act, _| {
+                    act.publish(&origin, &Envelope::Reply { query_id, reply });
+                }));
+            }
+            Envelope::StreamCommand {
+                origin,
+                query_id,
+                command,
+            } => {
+                let found = self.sse_sessions.send(command);
+                ctx.spawn(found.into_actor(self).map(move |found, act, _| {
+                    let reply = to_reply(found.map(Ok));
+                    act.publish(&origin, &Envelope::Reply { query_id, reply });
+                }));
+            }
+            Envelope::Reply { query_id, reply } => {
+                if let Some(sender) = self.queries.remove(&query_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+        }
+    }
+
+    fn rename_user(&mut self, old: String, new: String, keep_session: Option<String>) {
+        for connection in self.connections.values_mut() {
+            if connection.username == old {
+                connection.username = new.to_owned();
+            }
+        }
+        self.close_connections(&new, &LoggedOut::SessionsExcept(keep_session));
+        self.session_server.do_send(RenameUser { old, new });
+    }
+
+    // Close a user's logged out connections to this node
+    // They leave their rooms as they stop, like any other disconnection
+    fn close_connections(&self, username: &str, logged_out: &LoggedOut) {
+        for connection in self.connections.values() {
+            if connection.username == username
+                && logged_out.covers(connection.login_session.as_deref())
+            {
+                connection.addr.do_send(WSResponse::Close);
+            }
+        }
+    }
+
+    // Send something to every other node that's still running
+    fn broadcast(&mut self, envelope: Envelope, ctx: &mut Context<Self>) {
+        const STATEMENT: &str = "
+            SELECT substr(application_name, length($1) + 1) FROM pg_stat_activity
+            WHERE left(application_name, length($1)) = $1 AND application_name <> $2
+        ";
+
+        let db_pool = self.db_pool.clone();
+        let channel = channel_name(&self.node_id);
+        let nodes = async move {
+            let client = db_pool.get().await.map_err(|e| eprintln!("{:?}", e)).ok()?;
+            let rows = client
+                .query(STATEMENT, &[&CHANNEL_PREFIX, &channel])
+                .await
+                .map_err(|e| eprintln!("{:?}", e))
+                .ok()?;
+            Some(rows.iter().map(|row| row.get(0)).collect::<Vec<String>>())
+        };
+
+        ctx.spawn(nodes.into_actor(self).map(move |nodes, act, _| {
+            for node_id in nodes.unwrap_or_default() {
+                act.publish(&node_id, &envelope);
+            }
+        }));
+    }
+
+    // Ask a room something wherever it's hosted
+    // Fails if the host can't be looked up or doesn't answer in time, rather than guessing
+    fn ask_room<T: DeserializeOwned + 'static>(
+        &mut self,
+        query: RoomQuery,
+    ) -> ResponseActFuture<Self, Result<T, CodeHarmonyResponseError>> {
+        let locate = locate_room(
+            self.db_pool.clone(),
+            self.node_id.to_owned(),
+            query.identifier().clone(),
+        );
+
+        let reply = locate.into_actor(self).then(move |node_id, act, _| {
+            let reply: ResponseActFuture<Self, QueryReply> = match node_id {
+                Err(()) => Box::pin(fut::ready(Err(
+                    CodeHarmonyResponseError::WebsocketsUnavailable,
+                ))),
+                Ok(None) => {
+                    Box::pin(answer_query(act.session_server.clone(), query).into_actor(act))
+                }
+                Ok(Some(node_id)) => {
+                    let origin = act.node_id.to_owned();
+                    act.ask_node(&node_id, move |query_id| Envelope::Query {
+                        origin,
+                        query_id,
+                        query,
+                    })
+                }
+            };
+            reply
+        });
+
+        Box::pin(reply.map(|reply, _, _| from_reply(reply)))
+    }
+
+    // Send another node something it answers with a Reply carrying the same query id
+    fn ask_node(
+        &mut self,
+        node_id: &str,
+        envelope: impl FnOnce(String) -> Envelope,
+    ) -> ResponseActFuture<Self, QueryReply> {
+        let query_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.queries.insert(query_id.to_owned(), sender);
+        self.publish(node_id, &envelope(query_id.to_owned()));
+
+        Box::pin(
+            actix::clock::timeout(QUERY_TIMEOUT, receiver)
+                .into_actor(self)
+                .map(move |reply, act, _| {
+                    // Stop waiting on a node that didn't answer
+                    act.queries.remove(&query_id);
+                    match reply {
+                        Ok(Ok(reply)) => reply,
+                        _ => Err(CodeHarmonyResponseError::WebsocketsUnavailable),
+                    }
+                }),
+        )
+    }
+}
+
+impl Actor for Cluster {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.listen(ctx);
+    }
+}
+
+impl StreamHandler<Notification> for Cluster {
+    fn handle(&mut self, notification: Notification, ctx: &mut Self::Context) {
+        let payload = notification.payload();
+
+        // Big messages have to be fetched first, waiting keeps everything in order
+        if let Some(message_id) = payload.strip_prefix(MESSAGE_REF_PREFIX) {
+            let db_pool = self.db_pool.clone();
+            let message_id = message_id.to_owned();
+            ctx.wait(
+                take_stored_message(db_pool, message_id)
+                    .into_actor(self)
+                    .map(|payload, act, ctx| {
+                        if let Some(envelope) = payload.and_then(|payload| parse_envelope(&payload))
+                        {
+                            act.receive(envelope, ctx);
+                        }
+                    }),
+            );
+        } else if let Some(envelope) = parse_envelope(payload) {
+            self.receive(envelope, ctx);
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        eprintln!(
+            "Cluster node {} lost its listening connection",
+            self.node_id
+        );
+        self.listener = None;
+        ctx.run_later(RECONNECT_DELAY, |act, ctx| act.listen(ctx));
+    }
+}
+
+fn parse_envelope(payload: &str) -> Option<Envelope> {
+    serde_json::from_str(payload)
+        .map_err(|e| eprintln!("{:?}", e))
+        .ok()
+}
+
+async fn take_stored_message(db_pool: Pool, message_id: String) -> Option<String> {
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.cluster_message WHERE message_id = $1 RETURNING payload";
+
+    let client = db_pool.get().await.map_err(|e| eprintln!("{:?}", e)).ok()?;
+    let rows = client
+        .query(STATEMENT, &[&message_id])
+        .await
+        .map_err(|e| eprintln!("{:?}", e))
+        .ok()?;
+    rows.first().map(|row| row.get(0))
+}
+
+// Send notifications one at a time so they arrive in the order they were sent
+async fn send_notifications(
+    db_pool: Pool,
+    mut outgoing: mpsc::UnboundedReceiver<(String, String)>,
+) {
+    const NOTIFY: &str = "SELECT pg_notify($1, $2)";
+    // Anything left over for more than a minute was meant for a node that's gone
+    const STORE: &str = "
+        WITH expired AS (
+            DELETE FROM codeharmony.cluster_message WHERE created_at < current_timestamp - interval '1 minute'
+        )
+        INSERT INTO codeharmony.cluster_message(message_id, payload) VALUES ($1, $2)
+    ";
+
+    while let Some((channel, payload)) = outgoing.next().await {
+        let client = match db_pool.get().await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                continue;
+            }
+        };
+
+        let payload = if payload.len() > MAX_NOTIFY_PAYLOAD {
+            let message_id = uuid::Uuid::new_v4().to_string();
+            if let Err(e) = client.execute(STORE, &[&message_id, &payload]).await {
+                eprintln!("{:?}", e);
+                continue;
+            }
+            format!("{}{}", MESSAGE_REF_PREFIX, message_id)
+        } else {
+            payload
+        };
+
+        if let Err(e) = client.execute(NOTIFY, &[&channel, &payload]).await {
+            eprintln!("{:?}", e);
+        }
+    }
+}
+
+// Record that a room is hosted on this node, taking it over from wherever it was before
+// Ignored unless it's the room's teacher, and their account can still teach
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ClaimRoom {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+}
+
+impl Handler<ClaimRoom> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClaimRoom, _: &mut Self::Context) -> Self::Result {
+        if msg.username != msg.identifier.host {
+            return;
+        }
+
+        const STATEMENT: &str = "
+            INSERT INTO codeharmony.session_room_host(teacher_un, plan_name, session_name, node_id)
+            SELECT username, $2, $3, $4 FROM codeharmony.users
+            WHERE username = $1 AND role IN ('teacher', 'admin')
+            ON CONFLICT ON CONSTRAINT session_room_host_pk DO UPDATE SET node_id = $4, claimed_at = current_timestamp
+        ";
+        execute_in_background(
+            &self.db_pool,
+            STATEMENT,
+            vec![
+                Box::new(msg.identifier.host),
+                Box::new(msg.identifier.plan_name),
+                Box::new(msg.identifier.session_name),
+                Box::new(self.node_id.to_owned()),
+            ],
+        );
+    }
+}
+
+// Find the node hosting a room, if it's a different node that's still running
+#[derive(Message, Debug)]
+#[rtype(result = "Option<String>")]
+pub struct LocateRoom {
+    pub identifier: SessionIdentifier,
+}
+
+impl Handler<LocateRoom> for Cluster {
+    type Result = ResponseFuture<Option<String>>;
+
+    fn handle(&mut self, msg: LocateRoom, _: &mut Self::Context) -> Self::Result {
+        let locate = locate_room(
+            self.db_pool.clone(),
+            self.node_id.to_owned(),
+            msg.identifier,
+        );
+        Box::pin(async move { locate.await.ok().flatten() })
+    }
+}
+
+// Look up which other running node hosts a room, None if no other node does
+async fn locate_room(
+    db_pool: Pool,
+    node_id: String,
+    identifier: SessionIdentifier,
+) -> Result<Option<String>, ()> {
+    const STATEMENT: &str = "
+        SELECT node_id FROM codeharmony.session_room_host h
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3 AND node_id <> $4
+        AND EXISTS (SELECT 1 FROM pg_stat_activity WHERE application_name = $5 || h.node_id)
+    ";
+
+    let client = db_pool.get().await.map_err(|e| eprintln!("{:?}", e))?;
+    let rows = client
+        .query(
+            STATEMENT,
+            &[
+                &identifier.host,
+                &identifier.plan_name,
+                &identifier.session_name,
+                &node_id,
+                &CHANNEL_PREFIX,
+            ],
+        )
+        .await
+        .map_err(|e| eprintln!("{:?}", e))?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+impl Handler<CheckSubmissionOpen> for Cluster {
+    type Result = ResponseActFuture<Self, Result<(), CodeHarmonyResponseError>>;
+
+    fn handle(&mut self, msg: CheckSubmissionOpen, _: &mut Self::Context) -> Self::Result {
+        self.ask_room(RoomQuery::CheckSubmissionOpen(msg))
+    }
+}
+
+impl Handler<GetStudentData> for Cluster {
+    type Result = ResponseActFuture<Self, Result<Vec<StudentData>, CodeHarmonyResponseError>>;
+
+    fn handle(&mut self, msg: GetStudentData, _: &mut Self::Context) -> Self::Result {
+        self.ask_room(RoomQuery::GetStudentData(msg))
+    }
+}
+
+impl Handler<RecordRunResult> for Cluster {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: RecordRunResult, _: &mut Self::Context) -> Self::Result {
+        Box::pin(
+            self.ask_room::<()>(RoomQuery::RecordRunResult(msg))
+                .map(|reply, _, _| {
+                    if let Err(e) = reply {
+                        eprintln!("{:?}", e);
+                    }
+                }),
+        )
+    }
+}
+
+// Send a command from a client on this node to the node hosting its room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ForwardCommand {
+    pub node_id: String,
+    pub client_id: String,
+    pub username: String,
+    pub command: String,
+    pub payload: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<ForwardCommand> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForwardCommand, _: &mut Self::Context) -> Self::Result {
+        self.clients.insert(msg.client_id.to_owned(), msg.addr);
+        self.publish(
+            &msg.node_id,
+            &Envelope::Command {
+                origin: self.node_id.to_owned(),
+                client_id: msg.client_id,
+                username: msg.username,
+                command: msg.command,
+                payload: msg.payload,
+            },
+        );
+    }
+}
+
+// Tell the node hosting a client's room that the client has gone
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ForwardLeave {
+    pub node_id: String,
+    pub client_id: String,
+}
+
+impl Handler<ForwardLeave> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForwardLeave, _: &mut Self::Context) -> Self::Result {
+        self.clients.remove(&msg.client_id);
+        self.publish(
+            &msg.node_id,
+            &Envelope::Leave {
+                origin: self.node_id.to_owned(),
+                client_id: msg.client_id,
+            },
+        );
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct RelayResponse {
+    node_id: String,
+    client_id: String,
+    message: Option<String>,
+}
+
+impl Handler<RelayResponse> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: RelayResponse, _: &mut Self::Context) -> Self::Result {
+        self.publish(
+            &msg.node_id,
+            &Envelope::Response {
+                client_id: msg.client_id,
+                message: msg.message,
+            },
+        );
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct RelayRename {
+    node_id: String,
+    client_id: String,
+    username: String,
+}
+
+impl Handler<RelayRename> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: RelayRename, _: &mut Self::Context) -> Self::Result {
+        self.publish(
+            &msg.node_id,
+            &Envelope::Renamed {
+                client_id: msg.client_id,
+                username: msg.username,
+            },
+        );
+    }
+}
+
+// Pass a command to an event stream, on whichever node it's open
+// Not found if the stream has gone or belongs to someone else
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), CodeHarmonyResponseError>")]
+pub struct SendStreamCommand {
+    pub stream_id: String,
+    pub username: String,
+    pub text: String,
+}
+
+impl Handler<SendStreamCommand> for Cluster {
+    type Result = ResponseActFuture<Self, Result<(), CodeHarmonyResponseError>>;
+
+    fn handle(&mut self, msg: SendStreamCommand, _: &mut Self::Context) -> Self::Result {
+        let command = SendCommand {
+            stream_id: msg.stream_id,
+            username: msg.username,
+            text: msg.text,
+        };
+        let found: ResponseActFuture<Self, Result<bool, CodeHarmonyResponseError>> =
+            match stream_node(&command.stream_id).map(str::to_owned) {
+                None => Box::pin(fut::ready(Ok(false))),
+                Some(node_id) if node_id == self.node_id => Box::pin(
+                    self.sse_sessions
+                        .send(command)
+                        .into_actor(self)
+                        .map(|found, _, _| Ok(found.unwrap_or(false))),
+                ),
+                Some(node_id) => {
+                    let origin = self.node_id.to_owned();
+                    Box::pin(
+                        self.ask_node(&node_id, move |query_id| Envelope::StreamCommand {
+                            origin,
+                            query_id,
+                            command,
+                        })
+                        .map(|reply, _, _| from_reply(reply)),
+                    )
+                }
+            };
+
+        Box::pin(found.map(|found, _, _| match found? {
+            true => Ok(()),
+            false => Err(CodeHarmonyResponseError::NotFound),
+        }))
+    }
+}
+
+// Rename a user in the rooms on this node and every other node that's still running,
+// closing connections from the login sessions the rename logged out
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct BroadcastRename {
+    pub old: String,
+    pub new: String,
+    pub keep_session: Option<String>,
+}
+
+impl Handler<BroadcastRename> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: BroadcastRename, ctx: &mut Self::Context) -> Self::Result {
+        self.rename_user(
+            msg.old.to_owned(),
+            msg.new.to_owned(),
+            msg.keep_session.clone(),
+        );
+        self.broadcast(
+            Envelope::RenameUser {
+                old: msg.old,
+                new: msg.new,
+                keep_session: msg.keep_session,
+            },
+            ctx,
+        );
+    }
+}
+
+// Close a user's logged out connections on this node and every other node that's still running
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct BroadcastLogOut {
+    pub username: String,
+    pub logged_out: LoggedOut,
+}
+
+impl Handler<BroadcastLogOut> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: BroadcastLogOut, ctx: &mut Self::Context) -> Self::Result {
+        self.close_connections(&msg.username, &msg.logged_out);
+        self.broadcast(
+            Envelope::LogOut {
+                username: msg.username,
+                logged_out: msg.logged_out,
+            },
+            ctx,
+        );
+    }
+}
+
+// A client connected to this node, whichever room it ends up in
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RegisterConnection {
+    pub client_id: String,
+    pub username: String,
+    pub login_session: Option<String>,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<RegisterConnection> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterConnection, _: &mut Self::Context) -> Self::Result {
+        self.connections.insert(
+            msg.client_id,
+            OpenConnection {
+                username: msg.username,
+                login_session: msg.login_session,
+                addr: msg.addr,
+            },
+        );
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct UnregisterConnection {
+    pub client_id: String,
+}
+
+impl Handler<UnregisterConnection> for Cluster {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnregisterConnection, _: &mut Self::Context) -> Self::Result {
+        self.connections.remove(&msg.client_id);
+    }
+}
+
+// Stands in for a client connected to another node, so rooms here can treat it like any other
+pub struct RemoteClient {
+    origin: String,
+    client_id: String,
+    cluster: Addr<Cluster>,
+    session_server: Addr<SessionServer>,
+    connected_session: Option<SessionIdentifier>,
+}
+
+impl Actor for RemoteClient {
+    type Context = Context<Self>;
+}
+
+impl Handler<WSResponse> for RemoteClient {
+    type Result = ();
+
+    fn handle(&mut self, response: WSResponse, _: &mut Self::Context) -> Self::Result {
+        let message = match response {
+            WSResponse::Msg(message) => Some(message),
+            WSResponse::SetConnectedSession(identifier) => {
+                self.connected_session = Some(identifier);
+                return;
+            }
+            WSResponse::Renamed(username) => {
+                return self.cluster.do_send(RelayRename {
+                    node_id: self.origin.to_owned(),
+                    client_id: self.client_id.to_owned(),
+                    username,
+                });
+            }
+            WSResponse::Close => None,
+        };
+
+        self.cluster.do_send(RelayResponse {
+            node_id: self.origin.to_owned(),
+            client_id: self.client_id.to_owned(),
+            message,
+        });
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct RemoteCommand {
+    username: String,
+    command: String,
+    payload: String,
+}
+
+impl Handler<RemoteCommand> for RemoteClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoteCommand, ctx: &mut Self::Context) -> Self::Result {
+        dispatch_command(
+            &self.session_server,
+            self.connected_session.as_ref(),
+            msg.username,
+            ctx.address().recipient::<WSResponse>(),
+            &msg.command,
+            &msg.payload,
+        );
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct RemoteLeave;
+
+impl Handler<RemoteLeave> for RemoteClient {
+    type Result = ();
+
+    fn handle(&mut self, _: RemoteLeave, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(identifier) = self.connected_session.take() {
+            self.session_server.do_send(Leave {
+                identifier,
+                addr: ctx.address().recipient::<WSResponse>(),
+            });
+        }
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_ids_are_safe_channel_names() {
+        assert_eq!(sanitize_node_id("API-1"), "api_1");
+        assert_eq!(sanitize_node_id("node 2; DROP"), "node2drop");
+        assert_eq!(sanitize_node_id(&"a".repeat(100)).len(), MAX_NODE_ID_LENGTH);
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        let envelope = Envelope::Response {
+            client_id: "client".to_owned(),
+            message: Some("txt {}".to_owned()),
+        };
+        let payload = serde_json::to_string(&envelope).unwrap();
+        assert!(payload.contains("\"type\":\"Response\""));
+        assert!(matches!(
+            parse_envelope(&payload),
+            Some(Envelope::Response {
+                message: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn queries_round_trip() {
+        let envelope = Envelope::Query {
+            origin: "node".to_owned(),
+            query_id: "query".to_owned(),
+            query: RoomQuery::CheckSubmissionOpen(CheckSubmissionOpen {
+                identifier: SessionIdentifier {
+                    plan_name: "plan".to_owned(),
+                    session_name: "session".to_owned(),
+                    host: "teacher".to_owned(),
+                },
+                username: "student".to_owned(),
+                section_name: "section".to_owned(),
+            }),
+        };
+        let payload = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(
+            parse_envelope(&payload),
+            Some(Envelope::Query {
+                query: RoomQuery::CheckSubmissionOpen(_),
+                ..
+            })
+        ));
+
+        let envelope = Envelope::StreamCommand {
+            origin: "node".to_owned(),
+            query_id: "query".to_owned(),
+            command: SendCommand {
+                stream_id: "other.stream".to_owned(),
+                username: "student".to_owned(),
+                text: "txt hello".to_owned(),
+            },
+        };
+        let payload = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(
+            parse_envelope(&payload),
+            Some(Envelope::StreamCommand { command, .. }) if command.text == "txt hello"
+        ));
+
+        let envelope = Envelope::Reply {
+            query_id: "query".to_owned(),
+            reply: Err(CodeHarmonyResponseError::EditorLocked),
+        };
+        let payload = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(
+            parse_envelope(&payload),
+            Some(Envelope::Reply {
+                reply: Err(CodeHarmonyResponseError::EditorLocked),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn logging_out_picks_connections() {
+        let current = Some("current");
+        let other = Some("other");
+
+        assert!(LoggedOut::Everywhere.covers(None));
+        assert!(LoggedOut::Session("other".to_owned()).covers(other));
+        assert!(!LoggedOut::Session("other".to_owned()).covers(current));
+        assert!(!LoggedOut::Session("other".to_owned()).covers(None));
+
+        let keep_current = LoggedOut::SessionsExcept(Some("current".to_owned()));
+        assert!(keep_current.covers(other));
+        assert!(!keep_current.covers(current));
+        assert!(!keep_current.covers(None));
+        assert!(LoggedOut::SessionsExcept(None).covers(current));
+
+        let envelope = Envelope::LogOut {
+            username: "student".to_owned(),
+            logged_out: keep_current.clone(),
+        };
+        let payload = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(
+            parse_envelope(&payload),
+            Some(Envelope::LogOut { logged_out, .. }) if logged_out == keep_current
+        ));
+    }
+}
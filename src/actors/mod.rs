@@ -1,3 +1,6 @@
+pub mod cluster;
+pub mod room_connection;
+pub mod sse_session;
 pub mod teacher_code_manager;
 pub mod ws_server;
 pub mod ws_session;
@@ -0,0 +1,357 @@
+use actix::{
+    dev::ToEnvelope, Actor, ActorFutureExt, Addr, AsyncContext, Handler, Recipient, WrapFuture,
+};
+
+use crate::actors::cluster::{
+    ClaimRoom, Cluster, ForwardCommand, ForwardLeave, LocateRoom, RegisterConnection,
+    UnregisterConnection,
+};
+use crate::actors::ws_server::{
+    chat::{SendDirectMessage, SendTextMessage},
+    documents::{ApplyOperation, OpenDocument},
+    groups::SendGroupMessage,
+    help_queue::{LowerHand, RaiseHand},
+    polls::AnswerPoll,
+    pulse::{PulseState, SendPulse},
+    questions::{AskQuestion, UpvoteQuestion},
+    spotlight::{SetTeacherDoc, UpdateTeacherCode},
+    AcknowledgeSection, ControlInstruction, Leave, SessionIdentifier, SessionServer, SetStudentDoc,
+    StudentJoin, TeacherJoin, UpdateStudentCode, WSResponse,
+};
+use crate::utils::principal::Role;
+
+// A client's link to the room it's in, whichever transport it connected with
+pub struct RoomConnection {
+    pub addr: Addr<SessionServer>,
+    pub cluster: Addr<Cluster>,
+    pub client_id: String,
+    pub connected_session: Option<SessionIdentifier>,
+    // The node hosting the room, if it isn't this one
+    pub remote_node: Option<String>,
+    pub username: String,
+    pub role: Role,
+    // The login session it connected with, so revoking the session can close it
+    pub login_session: Option<String>,
+}
+
+impl RoomConnection {
+    pub fn new(
+        addr: Addr<SessionServer>,
+        cluster: Addr<Cluster>,
+        username: String,
+        role: Role,
+        login_session: Option<String>,
+    ) -> Self {
+        Self {
+            addr,
+            cluster,
+            client_id: uuid::Uuid::new_v4().to_string(),
+            connected_session: None,
+            remote_node: None,
+            username,
+            role,
+            login_session,
+        }
+    }
+
+    // Let the cluster close the connection if its login stops working
+    pub fn register(&self, addr: Recipient<WSResponse>) {
+        self.cluster.do_send(RegisterConnection {
+            client_id: self.client_id.to_owned(),
+            username: self.username.to_owned(),
+            login_session: self.login_session.clone(),
+            addr,
+        });
+    }
+
+    // Pass a command to the room, wherever it's hosted
+    fn send_command(&self, command: &str, payload: &str, addr: Recipient<WSResponse>) {
+        match &self.remote_node {
+            Some(node_id) => self.cluster.do_send(ForwardCommand {
+                node_id: node_id.to_owned(),
+                client_id: self.client_id.to_owned(),
+                username: self.username.clone(),
+                command: command.to_owned(),
+                payload: payload.to_owned(),
+                addr,
+            }),
+            None => dispatch_command(
+                &self.addr,
+                self.connected_session.as_ref(),
+                self.username.clone(),
+                addr,
+                command,
+                payload,
+            ),
+        }
+    }
+
+    fn leave_remote(&mut self) {
+        if let Some(node_id) = self.remote_node.take() {
+            self.cluster.do_send(ForwardLeave {
+                node_id,
+                client_id: self.client_id.to_owned(),
+            });
+        }
+    }
+
+    // Take the client out of its room when it disconnects
+    pub fn leave(&mut self, addr: Recipient<WSResponse>) {
+        self.cluster.do_send(UnregisterConnection {
+            client_id: self.client_id.to_owned(),
+        });
+        if self.remote_node.is_some() {
+            self.leave_remote();
+        } else if let Some(identifier) = self.connected_session.take() {
+            self.addr.do_send(Leave { identifier, addr });
+        }
+    }
+}
+
+// Handle a text command from a client actor holding a room connection
+pub fn handle_command<A>(act: &mut A, command: &str, payload: &str, ctx: &mut A::Context)
+where
+    A: Actor + Handler<WSResponse> + AsMut<RoomConnection>,
+    A::Context: AsyncContext<A> + ToEnvelope<A, WSResponse>,
+{
+    let addr = ctx.address().recipient::<WSResponse>();
+    if command == "tJoin" {
+        // Teachers always host their room on the node they connect to
+        // Only the room's own teacher can move it here
+        let connection = act.as_mut();
+        if let Some(identifier) = parse_identifier(payload) {
+            connection.leave_remote();
+            if connection.username == identifier.host && connection.role.can_teach() {
+                connection.cluster.do_send(ClaimRoom {
+                    identifier,
+                    username: connection.username.to_owned(),
+                });
+            }
+        }
+        connection.send_command(command, payload, addr);
+    } else if command == "sJoin" {
+        let identifier = match parse_identifier(payload) {
+            Some(identifier) => identifier,
+            None => return,
+        };
+
+        // Hold back anything else the student sends until we know where the room is
+        let (command, payload) = (command.to_owned(), payload.to_owned());
+        let locate = act.as_mut().cluster.send(LocateRoom { identifier });
+        ctx.wait(locate.into_actor(act).map(move |node_id, act, _| {
+            let connection = act.as_mut();
+            connection.leave_remote();
+            connection.remote_node = node_id.ok().flatten();
+            connection.send_command(&command, &payload, addr);
+        }));
+    } else {
+        act.as_mut().send_command(command, payload, addr);
+    }
+}
+
+// Rooms are identified by "plan_name:session_name:host"
+pub fn parse_identifier(payload: &str) -> Option<SessionIdentifier> {
+    let split_id: Vec<&str> = payload.splitn(3, ':').collect();
+    if split_id.len() != 3 {
+        return None;
+    }
+
+    Some(SessionIdentifier {
+        plan_name: split_id[0].to_owned(),
+        session_name: split_id[1].to_owned(),
+        host: split_id[2].to_owned(),
+    })
+}
+
+// Turn a text command from a client into a message for the session server
+// Shared by every connection a client can talk to a room through
+pub fn dispatch_command(
+    server: &Addr<SessionServer>,
+    connected_session: Option<&SessionIdentifier>,
+    username: String,
+    addr: Recipient<WSResponse>,
+    command: &str,
+    payload: &str,
+) {
+    if command == "tJoin" {
+        if let Some(identifier) = parse_identifier(payload) {
+            server.do_send(TeacherJoin {
+                identifier,
+                addr,
+                username,
+            })
+        }
+    } else if command == "sJoin" {
+        if let Some(identifier) = parse_identifier(payload) {
+            server.do_send(StudentJoin {
+                identifier,
+                addr,
+                username,
+            })
+        }
+    } else if command == "tInst" {
+        println!("{:?}", connected_session);
+        if let Some(identifier) = connected_session {
+            server.do_send(ControlInstruction {
+                instruction: payload.to_owned(),
+                identifier: identifier.clone(),
+                username,
+            });
+        }
+    } else if command == "sUpdate" {
+        if let Some(identifier) = connected_session {
+            server.do_send(UpdateStudentCode {
+                identifier: identifier.clone(),
+                username,
+                code: payload.to_owned(),
+                student_addr: addr,
+            });
+        }
+    } else if command == "sDoc" {
+        if let Some(identifier) = connected_session {
+            server.do_send(SetStudentDoc {
+                identifier: identifier.clone(),
+                username,
+                code: payload.to_owned(),
+                student_addr: addr,
+            });
+        }
+    } else if command == "sAck" {
+        if let Some(identifier) = connected_session {
+            if let Ok(section) = payload.parse::<usize>() {
+                server.do_send(AcknowledgeSection {
+                    identifier: identifier.clone(),
+                    username,
+                    section,
+                });
+            }
+        }
+    } else if command == "dm" {
+        // Direct messages are "dm <recipient> <text>"
+        if let (Some(identifier), Some((recipient, text))) =
+            (connected_session, payload.split_once(' '))
+        {
+            server.do_send(SendDirectMessage {
+                identifier: identifier.clone(),
+                username,
+                recipient: recipient.to_owned(),
+                text: text.to_owned(),
+                addr,
+            });
+        }
+    } else if command == "hand" {
+        if let Some(identifier) = connected_session {
+            server.do_send(RaiseHand {
+                identifier: identifier.clone(),
+                username,
+                note: payload.to_owned(),
+            });
+        }
+    } else if command == "lowerHand" {
+        if let Some(identifier) = connected_session {
+            server.do_send(LowerHand {
+                identifier: identifier.clone(),
+                username,
+            });
+        }
+    } else if command == "pollAns" {
+        // Poll answers are "pollAns <poll_id> <option>"
+        if let (Some(identifier), Some((poll_id, answer))) =
+            (connected_session, payload.split_once(' '))
+        {
+            if let Ok(answer) = answer.parse::<usize>() {
+                server.do_send(AnswerPoll {
+                    identifier: identifier.clone(),
+                    username,
+                    poll_id: poll_id.to_owned(),
+                    answer,
+                });
+            }
+        }
+    } else if command == "pulse" {
+        // Pulses are "pulse lost", "pulse good" or "pulse clear"
+        if let Some(identifier) = connected_session {
+            let state = match payload {
+                "lost" => Some(PulseState::Lost),
+                "good" => Some(PulseState::Good),
+                "clear" => None,
+                _ => return,
+            };
+            server.do_send(SendPulse {
+                identifier: identifier.clone(),
+                username,
+                state,
+                addr,
+            });
+        }
+    } else if command == "askQ" {
+        if let Some(identifier) = connected_session {
+            server.do_send(AskQuestion {
+                identifier: identifier.clone(),
+                username,
+                text: payload.to_owned(),
+                addr,
+            });
+        }
+    } else if command == "upvoteQ" {
+        if let Some(identifier) = connected_session {
+            server.do_send(UpvoteQuestion {
+                identifier: identifier.clone(),
+                username,
+                question_id: payload.to_owned(),
+            });
+        }
+    } else if command == "tUpdate" {
+        if let Some(identifier) = connected_session {
+            server.do_send(UpdateTeacherCode {
+                identifier: identifier.clone(),
+                username,
+                code: payload.to_owned(),
+            });
+        }
+    } else if command == "tDoc" {
+        if let Some(identifier) = connected_session {
+            server.do_send(SetTeacherDoc {
+                identifier: identifier.clone(),
+                username,
+                code: payload.to_owned(),
+            });
+        }
+    } else if command == "opDoc" {
+        if let Some(identifier) = connected_session {
+            server.do_send(OpenDocument {
+                identifier: identifier.clone(),
+                username,
+                payload: payload.to_owned(),
+                addr,
+            });
+        }
+    } else if command == "op" {
+        if let Some(identifier) = connected_session {
+            server.do_send(ApplyOperation {
+                identifier: identifier.clone(),
+                username,
+                payload: payload.to_owned(),
+                addr,
+            });
+        }
+    } else if command == "gtxt" {
+        if let Some(identifier) = connected_session {
+            server.do_send(SendGroupMessage {
+                identifier: identifier.clone(),
+                username,
+                text: payload.to_owned(),
+                addr,
+            });
+        }
+    } else if command == "txtm" {
+        if let Some(identifier) = connected_session {
+            server.do_send(SendTextMessage {
+                identifier: identifier.clone(),
+                username,
+                text: payload.to_owned(),
+                addr,
+            });
+        }
+    }
+}
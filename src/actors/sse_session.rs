@@ -0,0 +1,247 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::{Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message};
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::{
+        cluster::{sanitize_node_id, Cluster},
+        room_connection::{handle_command, RoomConnection},
+        ws_server::{SessionServer, WSResponse},
+    },
+    utils::principal::Role,
+};
+
+// Comments are ignored by EventSource but stop proxies timing the stream out,
+// and tell us when the client has gone
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Format a server sent event, splitting the data over as many lines as it needs
+pub fn format_event(event: Option<&str>, data: &str) -> Bytes {
+    let mut formatted = String::new();
+    if let Some(event) = event {
+        formatted.push_str(&format!("event: {}\n", event));
+    }
+    for line in data.split('\n') {
+        formatted.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+    }
+    formatted.push('\n');
+    Bytes::from(formatted)
+}
+
+// A client receiving room messages as server sent events, for networks that block websockets
+// Commands come in separately as POST requests
+pub struct SseClientSession {
+    connection: RoomConnection,
+    // What the client sends commands with, starting with the node holding the stream
+    stream_id: String,
+    sender: mpsc::UnboundedSender<Bytes>,
+    manager: Addr<SseSessionManager>,
+}
+
+impl SseClientSession {
+    // Stop as soon as the response stream has been dropped
+    fn send(&self, event: Bytes, ctx: &mut Context<Self>) {
+        if self.sender.unbounded_send(event).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl AsMut<RoomConnection> for SseClientSession {
+    fn as_mut(&mut self) -> &mut RoomConnection {
+        &mut self.connection
+    }
+}
+
+impl Actor for SseClientSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        println!("New SSE Connection");
+        self.connection
+            .register(ctx.address().recipient::<WSResponse>());
+
+        // The client needs its id to send commands
+        self.send(format_event(Some("connected"), &self.stream_id), ctx);
+        ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+            act.send(Bytes::from_static(b": keep-alive\n\n"), ctx);
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.connection
+            .leave(ctx.address().recipient::<WSResponse>());
+        self.manager.do_send(Unregister {
+            stream_id: self.stream_id.to_owned(),
+        });
+
+        println!("SSE Disconnection");
+    }
+}
+
+// Messages from server to client
+impl Handler<WSResponse> for SseClientSession {
+    type Result = ();
+
+    fn handle(&mut self, response: WSResponse, ctx: &mut Self::Context) -> Self::Result {
+        match response {
+            WSResponse::Msg(message) => self.send(format_event(None, &message), ctx),
+            WSResponse::SetConnectedSession(identifier) => {
+                self.connection.connected_session = Some(identifier)
+            }
+            WSResponse::Renamed(username) => self.connection.username = username,
+            WSResponse::Close => {
+                self.send(format_event(Some("close"), ""), ctx);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct ClientCommand {
+    command: String,
+    payload: String,
+}
+
+impl Handler<ClientCommand> for SseClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientCommand, ctx: &mut Self::Context) -> Self::Result {
+        handle_command(self, &msg.command, &msg.payload, ctx);
+    }
+}
+
+// Keeps track of the event streams open on this node so commands can find them
+// Commands posted to another node are passed here by the cluster, going by the stream id
+pub struct SseSessionManager {
+    node_id: String,
+    sessions: HashMap<String, (String, Addr<SseClientSession>)>,
+}
+
+impl SseSessionManager {
+    pub fn new(node_id: &str) -> SseSessionManager {
+        SseSessionManager {
+            node_id: sanitize_node_id(node_id),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+// The node holding a stream, as long as the id is one of ours
+pub fn stream_node(stream_id: &str) -> Option<&str> {
+    stream_id
+        .split_once('.')
+        .map(|(node_id, _)| node_id)
+        .filter(|node_id| !node_id.is_empty())
+}
+
+impl Actor for SseSessionManager {
+    type Context = Context<Self>;
+}
+
+// Start a client streaming events to the sender
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OpenStream {
+    pub addr: Addr<SessionServer>,
+    pub cluster: Addr<Cluster>,
+    pub username: String,
+    pub role: Role,
+    pub login_session: Option<String>,
+    pub sender: mpsc::UnboundedSender<Bytes>,
+}
+
+impl Handler<OpenStream> for SseSessionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: OpenStream, ctx: &mut Self::Context) -> Self::Result {
+        let stream_id = format!("{}.{}", self.node_id, uuid::Uuid::new_v4());
+        let client = SseClientSession {
+            connection: RoomConnection::new(
+                msg.addr,
+                msg.cluster,
+                msg.username.to_owned(),
+                msg.role,
+                msg.login_session,
+            ),
+            stream_id: stream_id.to_owned(),
+            sender: msg.sender,
+            manager: ctx.address(),
+        };
+        self.sessions
+            .insert(stream_id, (msg.username, client.start()));
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct Unregister {
+    stream_id: String,
+}
+
+impl Handler<Unregister> for SseSessionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unregister, _: &mut Self::Context) -> Self::Result {
+        self.sessions.remove(&msg.stream_id);
+    }
+}
+
+// Pass a "command payload" line to a stream's client
+// Returns false if there's no such stream belonging to the user
+#[derive(Message, Serialize, Deserialize, Debug)]
+#[rtype(result = "bool")]
+pub struct SendCommand {
+    pub stream_id: String,
+    pub username: String,
+    pub text: String,
+}
+
+impl Handler<SendCommand> for SseSessionManager {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SendCommand, _: &mut Self::Context) -> Self::Result {
+        match self.sessions.get(&msg.stream_id) {
+            Some((username, addr)) if *username == msg.username => {
+                let split: Vec<&str> = msg.text.splitn(2, ' ').collect();
+                if split.len() == 2 {
+                    addr.do_send(ClientCommand {
+                        command: split[0].to_owned(),
+                        payload: split[1].to_owned(),
+                    });
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_split_data_over_lines() {
+        assert_eq!(
+            format_event(None, "sDoc a\r\nb"),
+            Bytes::from("data: sDoc a\ndata: b\n\n")
+        );
+        assert_eq!(
+            format_event(Some("close"), ""),
+            Bytes::from("event: close\ndata: \n\n")
+        );
+    }
+
+    #[test]
+    fn stream_ids_name_their_node() {
+        assert_eq!(stream_node("node_a.1234"), Some("node_a"));
+        assert_eq!(stream_node(".1234"), None);
+        assert_eq!(stream_node("1234"), None);
+    }
+}
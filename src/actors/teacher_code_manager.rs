@@ -1,9 +1,41 @@
-use actix::{Actor, Context, Handler, Message};
-use bimap::BiMap;
+use std::env;
+
+use actix::{Actor, Context, Handler, Message, ResponseFuture};
+use chrono::{Duration, NaiveDateTime, Utc};
+use deadpool_postgres::{Pool, Transaction};
 use rand::Rng;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::utils::error::CodeHarmonyResponseError;
+
+// How long a code lasts unless the teacher asks for something else
+const DEFAULT_CODE_EXPIRY_HOURS: i64 = 24 * 7;
+const MAX_CODE_EXPIRY_HOURS: i64 = 24 * 365;
+const MAX_CODE_USES: i32 = 10000;
+
+// How many random codes to try before giving up on finding a free one
+const CODE_ATTEMPTS: usize = 10;
+
+// Old codes aren't handed out again until long after they stopped working,
+// so a code left on a whiteboard doesn't start pointing at someone else
+const CODE_REUSE_DAYS: i32 = 30;
 
+// A teacher's join code as it's shown to them
+#[derive(Serialize, Debug)]
+pub struct TeacherCode {
+    pub code: String,
+    pub class_id: Option<String>,
+    pub expires_at: i64,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub requires_approval: bool,
+}
+
+// Hands out join codes, keeping them in Postgres so they survive a restart
 pub struct TeacherCodeManager {
-    map: BiMap<String, String>,
+    db_pool: Pool,
+    default_expiry: Duration,
 }
 
 impl Actor for TeacherCodeManager {
@@ -14,48 +46,486 @@ impl Actor for TeacherCodeManager {
 }
 
 impl TeacherCodeManager {
-    pub fn new() -> TeacherCodeManager {
-        TeacherCodeManager { map: BiMap::new() }
+    pub fn new(db_pool: Pool) -> TeacherCodeManager {
+        let expiry_hours = env::var("TEACHER_CODE_EXPIRY_HOURS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_CODE_EXPIRY_HOURS);
+
+        TeacherCodeManager {
+            db_pool,
+            default_expiry: Duration::hours(expiry_hours),
+        }
     }
 }
 
+fn database_error(e: tokio_postgres::Error) -> CodeHarmonyResponseError {
+    eprintln!("{:?}", e);
+    CodeHarmonyResponseError::DatabaseQueryFailed
+}
+
+// Whether the teacher's last code needed them to accept students, so a new code can carry it on
+async fn approval_setting(
+    transaction: &Transaction<'_>,
+    username: &str,
+    class_id: &Option<String>,
+) -> Result<bool, CodeHarmonyResponseError> {
+    const STATEMENT: &str = "
+        SELECT requires_approval FROM codeharmony.teacher_code
+        WHERE teacher_un = $1 AND class_id IS NOT DISTINCT FROM $2
+        ORDER BY created_at DESC LIMIT 1
+    ";
+    let rows = transaction
+        .query(STATEMENT, &[&username, class_id])
+        .await
+        .map_err(database_error)?;
+    Ok(rows.first().map(|row| row.get(0)).unwrap_or(false))
+}
+
+fn random_code() -> String {
+    format!("{:0>6}", rand::thread_rng().gen_range(1..999999))
+}
+
+// Revoke the teacher's current code and make a new one, inside the caller's transaction
+async fn create_code(
+    transaction: &Transaction<'_>,
+    username: &str,
+    class_id: &Option<String>,
+    expires_at: NaiveDateTime,
+    max_uses: Option<i32>,
+    requires_approval: bool,
+) -> Result<TeacherCode, CodeHarmonyResponseError> {
+    const REVOKE_STATEMENT: &str = "
+        UPDATE codeharmony.teacher_code SET revoked_at = current_timestamp
+        WHERE teacher_un = $1 AND class_id IS NOT DISTINCT FROM $2 AND revoked_at IS NULL
+    ";
+    transaction
+        .execute(REVOKE_STATEMENT, &[&username, class_id])
+        .await
+        .map_err(database_error)?;
+
+    // Taking over a long dead code is fine, but never one that might still be in use
+    const INSERT_STATEMENT: &str = "
+        INSERT INTO codeharmony.teacher_code(code, teacher_un, class_id, expires_at, max_uses, requires_approval)
+        VALUES ($1, $2, $3, $4, $5, $7)
+        ON CONFLICT ON CONSTRAINT teacher_code_pk DO UPDATE
+        SET teacher_un = $2, class_id = $3, created_at = current_timestamp, expires_at = $4, max_uses = $5, uses = 0, revoked_at = NULL, requires_approval = $7
+        WHERE least(teacher_code.expires_at, coalesce(teacher_code.revoked_at, teacher_code.expires_at))
+            < current_timestamp - make_interval(days => $6)
+        RETURNING code
+    ";
+    for _ in 0..CODE_ATTEMPTS {
+        let code = random_code();
+        let rows = transaction
+            .query(
+                INSERT_STATEMENT,
+                &[
+                    &code,
+                    &username,
+                    class_id,
+                    &expires_at,
+                    &max_uses,
+                    &CODE_REUSE_DAYS,
+                    &requires_approval,
+                ],
+            )
+            .await
+            .map_err(database_error)?;
+
+        if !rows.is_empty() {
+            return Ok(TeacherCode {
+                code,
+                class_id: class_id.to_owned(),
+                expires_at: expires_at.timestamp_millis(),
+                max_uses,
+                uses: 0,
+                requires_approval,
+            });
+        }
+    }
+
+    Err(CodeHarmonyResponseError::InternalError(
+        0,
+        "Couldn't generate code".into(),
+    ))
+}
+
+// Get the teacher's working code, making a new one if the last has run out
+// Each class has its own code, separate from the teacher's
 #[derive(Message)]
-#[rtype(result = "String")]
+#[rtype(result = "Result<TeacherCode, CodeHarmonyResponseError>")]
 pub struct GetCode {
     pub username: String,
+    pub class_id: Option<String>,
 }
 
 impl Handler<GetCode> for TeacherCodeManager {
-    type Result = String;
+    type Result = ResponseFuture<Result<TeacherCode, CodeHarmonyResponseError>>;
 
     fn handle(&mut self, msg: GetCode, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(code) = self.map.get_by_left(&msg.username) {
-            // If there is already a code, return it
-            code.into()
-        } else {
-            // If it doesn't, generate, insert and return it
-            let mut rng = rand::thread_rng();
-            let code = format!("{:0>6}", rng.gen_range(1..999999));
-            self.map.insert(msg.username, code.clone());
-            code
-        }
+        let db_pool = self.db_pool.clone();
+        let expires_at = (Utc::now() + self.default_expiry).naive_utc();
+
+        Box::pin(async move {
+            let mut client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            let transaction = client
+                .transaction()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            // Lock the teacher so two requests can't both make a code
+            transaction
+                .execute(
+                    "SELECT 1 FROM codeharmony.users WHERE username = $1 FOR UPDATE",
+                    &[&msg.username],
+                )
+                .await
+                .map_err(database_error)?;
+
+            // If there is already a code that still works, return it
+            const STATEMENT: &str = "
+                SELECT code, expires_at, max_uses, uses, requires_approval FROM codeharmony.teacher_code
+                WHERE teacher_un = $1 AND class_id IS NOT DISTINCT FROM $2
+                AND revoked_at IS NULL AND expires_at > current_timestamp
+                AND (max_uses IS NULL OR uses < max_uses)
+            ";
+            let rows = transaction
+                .query(STATEMENT, &[&msg.username, &msg.class_id])
+                .await
+                .map_err(database_error)?;
+
+            let code = match rows.first() {
+                Some(row) => TeacherCode {
+                    code: row.get(0),
+                    class_id: msg.class_id.to_owned(),
+                    expires_at: row.get::<_, NaiveDateTime>(1).timestamp_millis(),
+                    max_uses: row.get(2),
+                    uses: row.get(3),
+                    requires_approval: row.get(4),
+                },
+                // If there isn't, generate, insert and return it
+                None => {
+                    let requires_approval =
+                        approval_setting(&transaction, &msg.username, &msg.class_id).await?;
+                    create_code(
+                        &transaction,
+                        &msg.username,
+                        &msg.class_id,
+                        expires_at,
+                        None,
+                        requires_approval,
+                    )
+                    .await?
+                }
+            };
+
+            transaction
+                .commit()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            Ok(code)
+        })
+    }
+}
+
+// Replace the teacher's code, optionally changing how long it lasts and how often it can be used
+// Leaving requires_approval out keeps whatever the last code had
+#[derive(Message)]
+#[rtype(result = "Result<TeacherCode, CodeHarmonyResponseError>")]
+pub struct RegenerateCode {
+    pub username: String,
+    pub class_id: Option<String>,
+    pub expires_in_hours: Option<i64>,
+    pub max_uses: Option<i32>,
+    pub requires_approval: Option<bool>,
+}
+
+impl Handler<RegenerateCode> for TeacherCodeManager {
+    type Result = ResponseFuture<Result<TeacherCode, CodeHarmonyResponseError>>;
+
+    fn handle(&mut self, msg: RegenerateCode, _ctx: &mut Self::Context) -> Self::Result {
+        let db_pool = self.db_pool.clone();
+        let expiry = msg
+            .expires_in_hours
+            .map(Duration::hours)
+            .unwrap_or(self.default_expiry);
+
+        Box::pin(async move {
+            if msg
+                .expires_in_hours
+                .is_some_and(|hours| !(1..=MAX_CODE_EXPIRY_HOURS).contains(&hours))
+            {
+                return Err(CodeHarmonyResponseError::BadRequest(
+                    0,
+                    "Codes must last between an hour and a year".into(),
+                ));
+            }
+            if msg
+                .max_uses
+                .is_some_and(|uses| !(1..=MAX_CODE_USES).contains(&uses))
+            {
+                return Err(CodeHarmonyResponseError::BadRequest(
+                    1,
+                    "Codes must allow between 1 and 10000 uses".into(),
+                ));
+            }
+
+            let mut client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            let transaction = client
+                .transaction()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            transaction
+                .execute(
+                    "SELECT 1 FROM codeharmony.users WHERE username = $1 FOR UPDATE",
+                    &[&msg.username],
+                )
+                .await
+                .map_err(database_error)?;
+
+            let requires_approval = match msg.requires_approval {
+                Some(requires_approval) => requires_approval,
+                None => approval_setting(&transaction, &msg.username, &msg.class_id).await?,
+            };
+
+            let expires_at = (Utc::now() + expiry).naive_utc();
+            let code = create_code(
+                &transaction,
+                &msg.username,
+                &msg.class_id,
+                expires_at,
+                msg.max_uses,
+                requires_approval,
+            )
+            .await?;
+
+            transaction
+                .commit()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            Ok(code)
+        })
     }
 }
 
+// Stop the teacher's code working without making a new one
 #[derive(Message)]
-#[rtype(result = "Option<String>")]
+#[rtype(result = "Result<(), CodeHarmonyResponseError>")]
+pub struct RevokeCode {
+    pub username: String,
+    pub class_id: Option<String>,
+}
+
+impl Handler<RevokeCode> for TeacherCodeManager {
+    type Result = ResponseFuture<Result<(), CodeHarmonyResponseError>>;
+
+    fn handle(&mut self, msg: RevokeCode, _ctx: &mut Self::Context) -> Self::Result {
+        let db_pool = self.db_pool.clone();
+
+        Box::pin(async move {
+            let client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            const STATEMENT: &str = "
+                UPDATE codeharmony.teacher_code SET revoked_at = current_timestamp
+                WHERE teacher_un = $1 AND class_id IS NOT DISTINCT FROM $2 AND revoked_at IS NULL
+            ";
+            client
+                .execute(STATEMENT, &[&msg.username, &msg.class_id])
+                .await
+                .map_err(database_error)?;
+            Ok(())
+        })
+    }
+}
+
+// Who a code was for, once it's been used
+#[derive(Serialize, Debug)]
+pub struct CodeOwner {
+    pub teacher_un: String,
+    pub class_id: Option<String>,
+    // The teacher still has to accept the student
+    pub pending: bool,
+}
+
+// Use a code to add its teacher to a student, and put them in the code's class if it has one
+// Codes needing approval leave a request for the teacher instead
+// Each successful use counts towards the code's limit
+#[derive(Message)]
+#[rtype(result = "Result<CodeOwner, CodeHarmonyResponseError>")]
 pub struct GetTeacher {
     pub code: String,
+    pub student_un: String,
 }
 
 impl Handler<GetTeacher> for TeacherCodeManager {
-    type Result = Option<String>;
+    type Result = ResponseFuture<Result<CodeOwner, CodeHarmonyResponseError>>;
 
     fn handle(&mut self, msg: GetTeacher, _ctx: &mut Self::Context) -> Self::Result {
-        // If code exists, return the teacher username
-        if let Some(username) = self.map.get_by_right(&msg.code) {
-            return Some(username.clone());
+        let db_pool = self.db_pool.clone();
+
+        Box::pin(async move {
+            let mut client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            let transaction = client
+                .transaction()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            // If the code is valid, hold onto it until the use has been counted
+            const CODE_STATEMENT: &str = "
+                SELECT teacher_un, class_id, requires_approval FROM codeharmony.teacher_code
+                WHERE code = $1 AND revoked_at IS NULL AND expires_at > current_timestamp
+                AND (max_uses IS NULL OR uses < max_uses)
+                FOR UPDATE
+            ";
+            let rows = transaction
+                .query(CODE_STATEMENT, &[&msg.code])
+                .await
+                .map_err(database_error)?;
+            let owner = match rows.first() {
+                Some(row) => CodeOwner {
+                    teacher_un: row.get(0),
+                    class_id: row.get(1),
+                    pending: row.get(2),
+                },
+                None => {
+                    return Err(CodeHarmonyResponseError::BadRequest(
+                        1,
+                        "Invalid code".into(),
+                    ))
+                }
+            };
+
+            if owner.pending {
+                request_to_join(&transaction, &owner, &msg.student_un).await?;
+            } else {
+                join_teacher(&transaction, &owner, &msg.student_un).await?;
+            }
+
+            transaction
+                .execute(
+                    "UPDATE codeharmony.teacher_code SET uses = uses + 1 WHERE code = $1",
+                    &[&msg.code],
+                )
+                .await
+                .map_err(database_error)?;
+
+            transaction
+                .commit()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            Ok(owner)
+        })
+    }
+}
+
+// Link a student to a teacher, and to the class if there is one, inside the caller's transaction
+async fn join_teacher(
+    transaction: &Transaction<'_>,
+    owner: &CodeOwner,
+    student_un: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    // Insert the record,
+    // Return teacher already added if not unique
+    // Class codes are still useful to students who already have the teacher
+    const INSERT_STATEMENT: &str = "
+        INSERT INTO codeharmony.student_teacher (teacher_un, student_un) VALUES ($1, $2)
+        ON CONFLICT ON CONSTRAINT student_teacher_pk DO NOTHING
+    ";
+    let inserted = transaction
+        .execute(INSERT_STATEMENT, &[&owner.teacher_un, &student_un])
+        .await
+        .map_err(database_error)?;
+
+    match &owner.class_id {
+        Some(class_id) => {
+            const CLASS_STATEMENT: &str = "
+                INSERT INTO codeharmony.class_member (class_id, student_un) VALUES ($1, $2)
+                ON CONFLICT ON CONSTRAINT class_member_pk DO NOTHING
+            ";
+            let joined = transaction
+                .execute(CLASS_STATEMENT, &[class_id, &student_un])
+                .await
+                .map_err(database_error)?;
+            if joined == 0 {
+                return Err(CodeHarmonyResponseError::BadRequest(
+                    2,
+                    "Already in this class".into(),
+                ));
+            }
         }
-        None
+        None if inserted == 0 => {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Teacher already added".into(),
+            ))
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// Leave a request for the teacher to accept, unless the student is already where the code would put them
+async fn request_to_join(
+    transaction: &Transaction<'_>,
+    owner: &CodeOwner,
+    student_un: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const MEMBER_STATEMENT: &str = "
+        SELECT 1 FROM codeharmony.student_teacher st
+        WHERE st.teacher_un = $1 AND st.student_un = $2
+        AND ($3::CHAR(36) IS NULL OR EXISTS (
+            SELECT 1 FROM codeharmony.class_member cm WHERE cm.class_id = $3 AND cm.student_un = $2
+        ))
+    ";
+    let rows = transaction
+        .query(
+            MEMBER_STATEMENT,
+            &[&owner.teacher_un, &student_un, &owner.class_id],
+        )
+        .await
+        .map_err(database_error)?;
+    if !rows.is_empty() {
+        return Err(match owner.class_id {
+            Some(_) => CodeHarmonyResponseError::BadRequest(2, "Already in this class".into()),
+            None => CodeHarmonyResponseError::BadRequest(0, "Teacher already added".into()),
+        });
+    }
+
+    const REQUEST_STATEMENT: &str = "
+        INSERT INTO codeharmony.teacher_join_request (request_id, teacher_un, student_un, class_id)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+    ";
+    let requested = transaction
+        .execute(
+            REQUEST_STATEMENT,
+            &[
+                &Uuid::new_v4().to_string(),
+                &owner.teacher_un,
+                &student_un,
+                &owner.class_id,
+            ],
+        )
+        .await
+        .map_err(database_error)?;
+    if requested == 0 {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            3,
+            "Already asked to join".into(),
+        ));
     }
+    Ok(())
 }
@@ -1,43 +1,92 @@
-use std::collections::HashMap;
-
-use crate::{actors::ws_session::WsClientSession, utils::error::CodeHarmonyResponseError};
-use actix::{Actor, Addr, Context, Handler, Message, Recipient};
-use actix_session::Session;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    actors::{cluster::Cluster, ws_session::WsClientSession},
+    endpoints::lesson_session::check_session_access,
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Role},
+    },
+};
+use actix::{
+    Actor, ActorFutureExt, Addr, AsyncContext, Context, Handler, Message, Recipient, WrapFuture,
+};
 use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use bimap::BiMap;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use uuid::Uuid;
+use tokio_postgres::types::ToSql;
+
+use self::{
+    chat::ChatState,
+    documents::{Documents, DOCUMENT_SAVE_INTERVAL},
+    editor_control::EditorControl,
+    events::{record_event, CodeSnapshots, CODE_SNAPSHOT_INTERVAL},
+    groups::Groups,
+    help_queue::HelpQueue,
+    polls::Polls,
+    pulse::Pulse,
+    questions::Questions,
+    spotlight::Spotlight,
+    timers::Timers,
+    watching::{WatchConfig, WatchList},
+};
+
+pub mod chat;
+pub mod documents;
+pub mod editor_control;
+pub mod events;
+pub mod groups;
+pub mod help_queue;
+pub mod polls;
+pub mod pulse;
+pub mod questions;
+pub mod rename;
+pub mod spotlight;
+pub mod timers;
+pub mod watching;
+
+// How often rooms are checked for idle students
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+// How long a student can go without activity before being marked idle
+const IDLE_TIMEOUT_SECONDS: i64 = 120;
 
 pub async fn session_service(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<SessionServer>>,
-    session: Session,
+    cluster: web::Data<Addr<Cluster>>,
+    Principal {
+        username,
+        role,
+        login_session,
+    }: Principal,
 ) -> Result<HttpResponse, CodeHarmonyResponseError> {
     println!("{:?}", &req);
     println!("WS Init request");
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        println!("Starting ws session");
-        return ws::start(
-            WsClientSession {
-                addr: srv.get_ref().clone(),
-                connected_session: None,
-                username,
-            },
-            &req,
-            stream,
-        )
-        .map_err(|e| {
-            eprintln!("{:?}", e);
-            CodeHarmonyResponseError::InternalError(
-                0,
-                "Couldn't create websocket connection".into(),
-            )
-        });
-    }
-
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    println!("Starting ws session");
+    ws::start(
+        WsClientSession::new(
+            srv.get_ref().clone(),
+            cluster.get_ref().clone(),
+            username,
+            role,
+            login_session,
+        ),
+        &req,
+        stream,
+    )
+    .map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(0, "Couldn't create websocket connection".into())
+    })
 }
 
 #[derive(PartialEq, Hash, Eq, Debug)]
@@ -46,44 +95,287 @@ pub struct User {
     username: String,
 }
 
-#[allow(dead_code)]
+// Everything the room knows about a student, kept after they disconnect
+// so the teacher can still see who has been in the room
+pub struct StudentPresence {
+    addr: Recipient<WSResponse>,
+    connected: bool,
+    idle: bool,
+    connected_since: DateTime<Utc>,
+    last_activity: DateTime<Utc>,
+    section_acknowledged: Option<usize>,
+    last_run_correct: Option<bool>,
+}
+
+impl StudentPresence {
+    fn new(addr: Recipient<WSResponse>) -> Self {
+        let now = Utc::now();
+        Self {
+            addr,
+            connected: true,
+            idle: false,
+            connected_since: now,
+            last_activity: now,
+            section_acknowledged: None,
+            last_run_correct: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StudentData {
+    username: String,
+    connected: bool,
+    idle: bool,
+    connected_since: i64,
+    last_activity: i64,
+    section_acknowledged: Option<usize>,
+    last_run_correct: Option<bool>,
+}
+
 pub struct SessionRoom {
     teacher: User,
-    students: BiMap<String, Recipient<WSResponse>>,
+    students: HashMap<String, StudentPresence>,
     current_section: usize,
-    current_student_username: Option<String>,
+    watch_list: WatchList,
+    chat: ChatState,
+    help_queue: HelpQueue,
+    polls: Polls,
+    spotlight: Option<Spotlight>,
+    documents: Documents,
+    editor_control: EditorControl,
+    timers: Timers,
+    groups: Groups,
+    pulse: Pulse,
+    questions: Questions,
+    code_snapshots: CodeSnapshots,
+    writer: RoomWriter,
 }
 
 impl SessionRoom {
-    fn new(teacher_addr: Recipient<WSResponse>, username: String) -> Self {
+    fn new(db_pool: &Pool, teacher_addr: Recipient<WSResponse>, username: String) -> Self {
         Self {
-            students: BiMap::new(),
+            students: HashMap::new(),
             teacher: User {
                 addr: teacher_addr,
                 username,
             },
             current_section: 0,
-            current_student_username: None,
+            watch_list: WatchList::new(),
+            chat: ChatState::new(),
+            help_queue: HelpQueue::new(),
+            polls: Polls::new(),
+            spotlight: None,
+            documents: Documents::new(),
+            editor_control: EditorControl::new(),
+            timers: Timers::new(),
+            groups: Groups::new(),
+            pulse: Pulse::new(),
+            questions: Questions::new(),
+            code_snapshots: CodeSnapshots::new(),
+            writer: RoomWriter::new(db_pool),
+        }
+    }
+
+    // Addresses of the students currently connected to the room
+    fn student_addrs(&self) -> impl Iterator<Item = &Recipient<WSResponse>> {
+        self.students
+            .values()
+            .filter(|student| student.connected)
+            .map(|student| &student.addr)
+    }
+
+    // Address of a student if they are currently connected
+    fn student_addr(&self, username: &str) -> Option<&Recipient<WSResponse>> {
+        self.students
+            .get(username)
+            .filter(|student| student.connected)
+            .map(|student| &student.addr)
+    }
+
+    // Push a presence event for a student to the teacher
+    fn notify_presence(&self, event: &str, username: &str) {
+        if let Some(student) = self.students.get(username) {
+            let response = json!({
+                "event": event,
+                "username": username,
+                "connected_since": student.connected_since.timestamp_millis(),
+            });
+            self.teacher
+                .addr
+                .do_send(WSResponse::Msg(format!("presence {}", response)));
+        }
+    }
+
+    // Record activity from a student, waking them up if they were idle
+    fn touch_student(&mut self, username: &str) {
+        if let Some(student) = self.students.get_mut(username) {
+            student.last_activity = Utc::now();
+            if student.idle {
+                student.idle = false;
+                self.notify_presence("active", username);
+            }
+        }
+    }
+
+    // Mark any students that haven't done anything for a while as idle
+    fn check_idle(&mut self) {
+        let now = Utc::now();
+        let newly_idle = self
+            .students
+            .iter_mut()
+            .filter(|(_, student)| {
+                student.connected
+                    && !student.idle
+                    && (now - student.last_activity).num_seconds() >= IDLE_TIMEOUT_SECONDS
+            })
+            .map(|(username, student)| {
+                student.idle = true;
+                username.to_owned()
+            })
+            .collect::<Vec<String>>();
+
+        for username in newly_idle {
+            self.notify_presence("idle", &username);
         }
     }
 }
 
 pub struct SessionServer {
     sessions: HashMap<SessionIdentifier, SessionRoom>,
+    db_pool: Pool,
+    watch_config: WatchConfig,
 }
 
 impl SessionServer {
-    pub fn new() -> SessionServer {
+    pub fn new(db_pool: Pool) -> SessionServer {
         let sessions: HashMap<SessionIdentifier, SessionRoom> = HashMap::new();
-        SessionServer { sessions }
+        SessionServer {
+            sessions,
+            db_pool,
+            watch_config: WatchConfig::from_env(),
+        }
     }
 }
 
 impl Actor for SessionServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |act, _| {
+            for room in act.sessions.values_mut() {
+                room.check_idle();
+            }
+        });
+
+        ctx.run_interval(self.watch_config.snapshot_interval, |act, _| {
+            for room in act.sessions.values_mut() {
+                room.flush_snapshots();
+            }
+        });
+
+        ctx.run_interval(DOCUMENT_SAVE_INTERVAL, |act, _| act.save_all_documents());
+
+        ctx.run_interval(CODE_SNAPSHOT_INTERVAL, |act, _| {
+            for (identifier, room) in act.sessions.iter_mut() {
+                room.code_snapshots.flush(&room.writer, identifier);
+            }
+        });
+    }
+}
+
+// Run a statement without waiting on it, logging if it fails
+// Writes that belong to a room go through its RoomWriter instead, so they keep their order
+pub fn execute_in_background(
+    db_pool: &Pool,
+    statement: &'static str,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+) {
+    let db_pool = db_pool.clone();
+
+    actix::spawn(async move {
+        let client = match db_pool.get().await {
+            Ok(client) => client,
+            Err(e) => return eprintln!("{:?}", e),
+        };
+
+        if let Err(e) = client.query(statement, &statement_params(&params)).await {
+            eprintln!("{:?}", e);
+        }
+    });
+}
+
+type Statement = (&'static str, Vec<Box<dyn ToSql + Sync + Send>>);
+
+// Saves what happens in a room in the order it happened
+// Everything goes through one task a write at a time, so an update can't overtake the insert it's
+// updating the way it can when each statement gets its own connection
+pub struct RoomWriter {
+    sender: UnboundedSender<Vec<Statement>>,
+}
+
+impl RoomWriter {
+    fn new(db_pool: &Pool) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded::<Vec<Statement>>();
+        let db_pool = db_pool.clone();
+
+        // Finishes once the room is gone and everything it sent has been written
+        actix::spawn(async move {
+            while let Some(statements) = receiver.next().await {
+                if let Err(e) = write_statements(&db_pool, statements).await {
+                    eprintln!("{:?}", e);
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    // Run a statement once everything the room sent before it has been written, logging if it fails
+    pub fn execute(&self, statement: &'static str, params: Vec<Box<dyn ToSql + Sync + Send>>) {
+        self.execute_all(vec![(statement, params)]);
+    }
+
+    // Run statements together in one transaction, after everything the room sent before them
+    pub fn execute_all(&self, statements: Vec<Statement>) {
+        if self.sender.unbounded_send(statements).is_err() {
+            eprintln!("Room writer stopped, dropping write");
+        }
+    }
+}
+
+fn statement_params(params: &[Box<dyn ToSql + Sync + Send>]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+        .collect()
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+// A single statement runs on its own, more than one runs in a transaction
+async fn write_statements(
+    db_pool: &Pool,
+    statements: Vec<Statement>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = db_pool.get().await?;
+
+    if let [(statement, params)] = statements.as_slice() {
+        client
+            .execute(*statement, &statement_params(params))
+            .await?;
+        return Ok(());
+    }
+
+    let transaction = client.transaction().await?;
+    for (statement, params) in &statements {
+        transaction
+            .execute(*statement, &statement_params(params))
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct SessionIdentifier {
     pub plan_name: String,
     pub session_name: String,
@@ -95,6 +387,8 @@ pub struct SessionIdentifier {
 pub enum WSResponse {
     Msg(String),
     SetConnectedSession(SessionIdentifier),
+    // The client's account was given a new username
+    Renamed(String),
     Close,
 }
 
@@ -110,21 +404,79 @@ pub struct TeacherJoin {
 impl Handler<TeacherJoin> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: TeacherJoin, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: TeacherJoin, ctx: &mut Self::Context) -> Self::Result {
+        // Only the room's own teacher can run it, as long as their account can still teach
+        if msg.username != msg.identifier.host {
+            msg.addr.do_send(WSResponse::Msg(format!(
+                "joinErr {}",
+                CodeHarmonyResponseError::Forbidden
+            )));
+            return;
+        }
+
+        let db_pool = self.db_pool.clone();
+        let username = msg.username.clone();
+        let role = async move {
+            // Get db client
+            let client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            const STATEMENT: &str = "SELECT role FROM codeharmony.users WHERE username = $1";
+            let rows = client.query(STATEMENT, &[&username]).await.map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            })?;
+            match rows.first().and_then(|row| Role::parse(row.get(0))) {
+                Some(role) if role.can_teach() => Ok(()),
+                _ => Err(CodeHarmonyResponseError::Forbidden),
+            }
+        };
+
+        ctx.spawn(role.into_actor(self).map(|role, act, _| match role {
+            // A room opened for a teacher who's already gone would never close
+            Ok(()) if !msg.addr.connected() => {}
+            Ok(()) => act.add_teacher(msg),
+            Err(e) => msg.addr.do_send(WSResponse::Msg(format!("joinErr {}", e))),
+        }));
+    }
+}
+
+impl SessionServer {
+    fn add_teacher(&mut self, msg: TeacherJoin) {
         // If the room exists, reset the teacher values
         if let Some(room) = self.sessions.get_mut(&msg.identifier) {
             room.teacher = User {
-                username: msg.username,
+                username: msg.username.clone(),
                 addr: msg.addr.clone(),
-            }
+            };
+            room.send_help_queue();
+            room.send_locks();
+            room.send_groups();
+            room.send_pulse();
+            room.send_questions(&msg.addr, &msg.username);
         } else {
             // If it doesn't create a new room with teacher details.
             self.sessions.insert(
                 msg.identifier.clone(),
-                SessionRoom::new(msg.addr.clone(), msg.username),
+                SessionRoom::new(&self.db_pool, msg.addr.clone(), msg.username.clone()),
             );
         }
 
+        if let Some(room) = self.sessions.get(&msg.identifier) {
+            record_event(
+                &room.writer,
+                &msg.identifier,
+                "join",
+                Some(&msg.username),
+                json!({ "role": "teacher" }),
+            );
+        }
+
+        // Catch the teacher up on the chat
+        self.send_chat_history(&msg.identifier, &msg.username, msg.addr.clone());
+
         // Set the active room for the teacher
         msg.addr
             .do_send(WSResponse::SetConnectedSession(msg.identifier));
@@ -143,20 +495,85 @@ pub struct StudentJoin {
 impl Handler<StudentJoin> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: StudentJoin, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: StudentJoin, ctx: &mut Self::Context) -> Self::Result {
+        // Only students who can see the session, through its classes, can join it
+        let db_pool = self.db_pool.clone();
+        let identifier = msg.identifier.clone();
+        let username = msg.username.clone();
+        let access = async move {
+            let client = db_pool
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+            check_session_access(
+                &client,
+                &username,
+                &identifier.host,
+                &identifier.plan_name,
+                &identifier.session_name,
+            )
+            .await
+        };
+
+        ctx.spawn(access.into_actor(self).map(|access, act, _| match access {
+            // The client might have gone while we checked, it'd never leave if added now
+            Ok(()) if !msg.addr.connected() => {}
+            Ok(()) => act.add_student(msg),
+            Err(e) => msg.addr.do_send(WSResponse::Msg(format!("joinErr {}", e))),
+        }));
+    }
+}
+
+impl SessionServer {
+    fn add_student(&mut self, msg: StudentJoin) {
         // If the room exists,
         if let Some(session) = self.sessions.get_mut(&msg.identifier) {
             // set the address in the students connection
             msg.addr
-                .do_send(WSResponse::SetConnectedSession(msg.identifier));
+                .do_send(WSResponse::SetConnectedSession(msg.identifier.clone()));
             println!("Student joined session");
 
             // Tell the student what section we're on
             msg.addr
                 .do_send(WSResponse::Msg(format!("sec {}", session.current_section)));
 
-            // Insert the student into the list
-            session.students.insert(msg.username, msg.addr);
+            // Let the student know if they can chat and what polls are running
+            session.chat.send_state(&msg.addr, &msg.username);
+            session.polls.send_open_polls(&msg.addr);
+            session.send_spotlight(&msg.addr);
+            session.send_lock_state(&msg.addr, &msg.username);
+            session.send_timers(&msg.addr, &msg.username);
+            session.send_group(&msg.addr, &msg.username);
+            session.send_own_pulse(&msg.addr, &msg.username);
+            session.send_questions(&msg.addr, &msg.username);
+
+            // Get the student streaming again if the teacher was watching them
+            if session.wants_code_from(&msg.username) {
+                msg.addr.do_send(WSResponse::Msg("subscribe".to_owned()));
+            }
+
+            // Insert the student into the list, or bring them back if they've been here before
+            let event = if session.students.contains_key(&msg.username) {
+                "reconnect"
+            } else {
+                "join"
+            };
+            session
+                .students
+                .insert(msg.username.clone(), StudentPresence::new(msg.addr.clone()));
+
+            session.notify_presence(event, &msg.username);
+            session.send_pulse();
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "join",
+                Some(&msg.username),
+                json!({"role": "student", "event": event}),
+            );
+
+            // Catch the student up on the chat
+            self.send_chat_history(&msg.identifier, &msg.username, msg.addr);
         }
     }
 }
@@ -173,13 +590,47 @@ impl Handler<Leave> for SessionServer {
     fn handle(&mut self, msg: Leave, _: &mut Self::Context) -> Self::Result {
         // If the room exists,
         if let Some(session) = self.sessions.get_mut(&msg.identifier) {
-            session.students.remove_by_right(&msg.addr);
-
             if session.teacher.addr == msg.addr {
-                for student in session.students.right_values() {
+                for student in session.student_addrs() {
                     student.do_send(WSResponse::Close)
                 }
+
+                // Don't lose any edits made since the last save
+                session.save_documents(&msg.identifier);
+                session
+                    .code_snapshots
+                    .flush(&session.writer, &msg.identifier);
+                record_event(
+                    &session.writer,
+                    &msg.identifier,
+                    "leave",
+                    Some(&session.teacher.username),
+                    json!({ "role": "teacher" }),
+                );
                 self.sessions.remove(&msg.identifier);
+                return;
+            }
+
+            // Keep the student's details but mark them as gone
+            let left = session
+                .students
+                .iter_mut()
+                .find(|(_, student)| student.connected && student.addr == msg.addr)
+                .map(|(username, student)| {
+                    student.connected = false;
+                    username.to_owned()
+                });
+
+            if let Some(username) = left {
+                session.notify_presence("leave", &username);
+                session.send_pulse();
+                record_event(
+                    &session.writer,
+                    &msg.identifier,
+                    "leave",
+                    Some(&username),
+                    json!({ "role": "student" }),
+                );
             }
         }
     }
@@ -190,141 +641,189 @@ impl Handler<Leave> for SessionServer {
 pub struct ControlInstruction {
     pub instruction: String,
     pub identifier: SessionIdentifier,
+    pub username: String,
 }
 
 impl Handler<ControlInstruction> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ControlInstruction, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ControlInstruction, ctx: &mut Self::Context) -> Self::Result {
         let instruction: Vec<&str> = msg.instruction.split(' ').collect();
 
         println!("Instruction is{:?}", instruction);
 
+        // Only the teacher running the room can control it
+        match self.sessions.get(&msg.identifier) {
+            Some(session) if session.teacher.username == msg.username => {}
+            _ => return,
+        }
+
         // If setSection, get new value and session room
         // Set current_section val and send the msg to students in room
         if instruction[0] == "setSection" && instruction.len() == 2 {
             if let Ok(new_value) = instruction[1].parse::<usize>() {
                 if let Some(session) = self.sessions.get_mut(&msg.identifier) {
                     session.current_section = new_value;
-                    let msg = format!("sec {}", new_value);
-                    println!("Student addresses {:?}", session.students);
-                    for addr in session.students.right_values() {
-                        addr.do_send(WSResponse::Msg(msg.to_owned()));
+                    let response = format!("sec {}", new_value);
+                    for addr in session.student_addrs() {
+                        addr.do_send(WSResponse::Msg(response.to_owned()));
                         println!("Sent instruction");
                     }
+                    session.send_pulse();
+
+                    record_event(
+                        &session.writer,
+                        &msg.identifier,
+                        "section",
+                        None,
+                        json!({ "section": new_value }),
+                    );
                 }
             }
-        } else if instruction[0] == "subscribe" && instruction.len() == 2 {
-            if let Some(session) = self.sessions.get_mut(&msg.identifier) {
-                if let Some(student_addr) = session.students.get_by_left(instruction[1]) {
-                    // Unsub the old student if there is one and it isn't the same student
-                    if let Some(to_unsub_username) = &session.current_student_username {
-                        if to_unsub_username != instruction[1] {
-                            if let Some(to_unsub_addr) =
-                                session.students.get_by_left(to_unsub_username)
-                            {
-                                to_unsub_addr.do_send(WSResponse::Msg("unsub".to_owned()));
-                            }
-                        }
-                    }
-
-                    session.current_student_username = Some(instruction[1].to_owned());
-                    student_addr.do_send(WSResponse::Msg("subscribe".to_owned()));
-                }
-            }
-        } else if instruction[0] == "unsub" {
-            if let Some(session) = self.sessions.get_mut(&msg.identifier) {
-                if let Some(to_unsub_username) = &session.current_student_username {
-                    if let Some(to_unsub_addr) = session.students.get_by_left(to_unsub_username) {
-                        to_unsub_addr.do_send(WSResponse::Msg("unsub".to_owned()));
-                    }
-                }
-            }
+        } else if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            session.chat_instruction(&msg.identifier, &instruction);
+            session.watch_instruction(&instruction, self.watch_config.max_watched);
+            session.help_queue_instruction(&instruction, self.watch_config.max_watched);
+            session.poll_instruction(&msg.identifier, &instruction);
+            session.spotlight_instruction(&instruction);
+            session.editor_control_instruction(&msg.identifier, &instruction);
+            session.group_instruction(&msg.identifier, &instruction);
+            session.pulse_instruction(&instruction);
+            session.question_instruction(&msg.identifier, &instruction);
+            self.reset_instruction(&msg.identifier, &instruction, ctx);
+            self.timer_instruction(&msg.identifier, &instruction, ctx);
         }
     }
 }
 
-#[derive(Message, Debug)]
-#[rtype(result = "Vec<String>")]
+#[derive(Message, Debug, Serialize, Deserialize)]
+#[rtype(result = "Result<Vec<StudentData>, CodeHarmonyResponseError>")]
 pub struct GetStudentData {
     pub identifier: SessionIdentifier,
     pub username: String,
 }
 
 impl Handler<GetStudentData> for SessionServer {
-    type Result = Vec<String>;
+    type Result = Result<Vec<StudentData>, CodeHarmonyResponseError>;
 
     fn handle(&mut self, msg: GetStudentData, _: &mut Self::Context) -> Self::Result {
         if let Some(session) = self.sessions.get(&msg.identifier) {
             if session.teacher.username == msg.username {
-                return session
+                return Ok(session
                     .students
-                    .left_values()
-                    .map(|username| username.to_owned())
-                    .collect::<Vec<String>>();
+                    .iter()
+                    .map(|(username, student)| StudentData {
+                        username: username.to_owned(),
+                        connected: student.connected,
+                        idle: student.idle,
+                        connected_since: student.connected_since.timestamp_millis(),
+                        last_activity: student.last_activity.timestamp_millis(),
+                        section_acknowledged: student.section_acknowledged,
+                        last_run_correct: student.last_run_correct,
+                    })
+                    .collect::<Vec<StudentData>>());
             }
         }
-        vec![]
+        Ok(vec![])
     }
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct UpdateStudentCode {
+pub struct AcknowledgeSection {
     pub identifier: SessionIdentifier,
     pub username: String,
-    pub code: String,
-    pub student_addr: Recipient<WSResponse>,
+    pub section: usize,
 }
 
-impl Handler<UpdateStudentCode> for SessionServer {
+impl Handler<AcknowledgeSection> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: UpdateStudentCode, _: &mut Self::Context) -> Self::Result {
-        // If the session exists and this is the student the room is looking at,
-        // send the code to the teacher
-        if let Some(session) = self.sessions.get(&msg.identifier) {
-            if let Some(current_student_username) = &session.current_student_username {
-                if current_student_username == &msg.username {
-                    session
-                        .teacher
-                        .addr
-                        .do_send(WSResponse::Msg(format!("sUpdate {}", msg.code)));
-                    return;
-                }
+    fn handle(&mut self, msg: AcknowledgeSection, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if let Some(student) = session.students.get_mut(&msg.username) {
+                student.section_acknowledged = Some(msg.section);
             }
+            session.touch_student(&msg.username);
         }
+    }
+}
 
-        // If not, tell the student we don't want to hear from them anymore
-        msg.student_addr
-            .do_send(WSResponse::Msg("unsub".to_owned()));
+#[derive(Message, Debug, Serialize, Deserialize)]
+#[rtype(result = "()")]
+pub struct RecordRunResult {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub section_name: String,
+    pub correct: bool,
+}
+
+impl Handler<RecordRunResult> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordRunResult, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if let Some(student) = session.students.get_mut(&msg.username) {
+                student.last_run_correct = Some(msg.correct);
+            }
+            session.touch_student(&msg.username);
+
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "run",
+                Some(&msg.username),
+                json!({"section": msg.section_name, "correct": msg.correct}),
+            );
+        }
     }
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct SetStudentDoc {
+pub struct UpdateStudentCode {
     pub identifier: SessionIdentifier,
     pub username: String,
     pub code: String,
     pub student_addr: Recipient<WSResponse>,
 }
 
-impl Handler<SetStudentDoc> for SessionServer {
+impl Handler<UpdateStudentCode> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: SetStudentDoc, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: UpdateStudentCode, _: &mut Self::Context) -> Self::Result {
         // If the session exists and this is the student the room is looking at,
-        // send the starting code to the teacher
-        if let Some(session) = self.sessions.get(&msg.identifier) {
-            if let Some(current_student_username) = &session.current_student_username {
-                if current_student_username == &msg.username {
-                    session
-                        .teacher
-                        .addr
-                        .do_send(WSResponse::Msg(format!("sDoc {}", msg.code)));
-                    return;
-                }
+        // send the code to the teacher
+        // If they're in the spotlight, send it to the class too
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            session.touch_student(&msg.username);
+            session.code_snapshots.update(
+                &session.writer,
+                &msg.identifier,
+                &msg.username,
+                &msg.code,
+                session.current_section,
+            );
+
+            let spotlighted = session.forward_spotlight(&msg.username, "spotUpdate", &msg.code);
+
+            // The focused student gets everything sent straight away,
+            // anyone else being watched goes in the next grid snapshot
+            if session.is_focused(&msg.username) {
+                session
+                    .teacher
+                    .addr
+                    .do_send(WSResponse::Msg(format!("sUpdate {}", msg.code)));
+                return;
+            }
+
+            if session.is_watched(&msg.username) {
+                session.queue_snapshot(&msg.username, &msg.code);
+                return;
+            }
+
+            if spotlighted {
+                return;
             }
         }
 
@@ -336,29 +835,54 @@ impl Handler<SetStudentDoc> for SessionServer {
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct SendTextMessage {
+pub struct SetStudentDoc {
     pub identifier: SessionIdentifier,
     pub username: String,
-    pub text: String,
+    pub code: String,
+    pub student_addr: Recipient<WSResponse>,
 }
 
-impl Handler<SendTextMessage> for SessionServer {
+impl Handler<SetStudentDoc> for SessionServer {
     type Result = ();
 
-    fn handle(&mut self, msg: SendTextMessage, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: SetStudentDoc, _: &mut Self::Context) -> Self::Result {
+        // If the session exists and this is the student the room is looking at,
+        // send the starting code to the teacher
+        // If they're in the spotlight, send it to the class too
         if let Some(session) = self.sessions.get_mut(&msg.identifier) {
-            let response = json!({"username":msg.username, "uuid":Uuid::new_v4().to_string(), "text": msg.text});
-            let msg = format!("txtm {}", response);
+            session.touch_student(&msg.username);
+            session.code_snapshots.update(
+                &session.writer,
+                &msg.identifier,
+                &msg.username,
+                &msg.code,
+                session.current_section,
+            );
 
-            println!("{}", response);
+            let spotlighted = session.forward_spotlight(&msg.username, "spotDoc", &msg.code);
+
+            // The focused student gets everything sent straight away,
+            // anyone else being watched goes in the next grid snapshot
+            if session.is_focused(&msg.username) {
+                session
+                    .teacher
+                    .addr
+                    .do_send(WSResponse::Msg(format!("sDoc {}", msg.code)));
+                return;
+            }
 
-            // Send message to all students
-            for addr in session.students.right_values() {
-                addr.do_send(WSResponse::Msg(msg.to_owned()));
+            if session.is_watched(&msg.username) {
+                session.queue_snapshot(&msg.username, &msg.code);
+                return;
             }
 
-            // And the teacher
-            session.teacher.addr.do_send(WSResponse::Msg(msg));
+            if spotlighted {
+                return;
+            }
         }
+
+        // If not, tell the student we don't want to hear from them anymore
+        msg.student_addr
+            .do_send(WSResponse::Msg("unsub".to_owned()));
     }
 }
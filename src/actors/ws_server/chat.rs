@@ -0,0 +1,483 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+};
+
+use actix::{Handler, Message, Recipient};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use deadpool_postgres::Pool;
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{
+    events::record_event,
+    rename::{rename_key, rename_member},
+    RoomWriter, SessionIdentifier, SessionRoom, SessionServer, WSResponse,
+};
+
+// Longest message we'll accept
+pub const MAX_MESSAGE_LENGTH: usize = 1000;
+
+// How many messages a user can send within the rate limit window
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW_SECONDS: i64 = 10;
+
+// How many messages are replayed to someone joining the room
+const HISTORY_REPLAY_LENGTH: i64 = 50;
+
+// Chat settings and moderation state for a room
+pub struct ChatState {
+    enabled: bool,
+    student_messages_allowed: bool,
+    muted: HashSet<String>,
+    recent_messages: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl ChatState {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            student_messages_allowed: false,
+            muted: HashSet::new(),
+            recent_messages: HashMap::new(),
+        }
+    }
+
+    // Carry a student's mute and rate limit over to their new username
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        rename_member(&mut self.muted, old, new);
+        rename_key(&mut self.recent_messages, old, new);
+    }
+
+    // Record a message for the user, returning false if they've sent too many recently
+    pub fn within_rate_limit(&mut self, username: &str) -> bool {
+        let now = Utc::now();
+        let sent = self.recent_messages.entry(username.to_owned()).or_default();
+
+        while let Some(oldest) = sent.front() {
+            if (now - *oldest).num_seconds() >= RATE_LIMIT_WINDOW_SECONDS {
+                sent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if sent.len() >= RATE_LIMIT_MESSAGES {
+            return false;
+        }
+
+        sent.push_back(now);
+        true
+    }
+
+    pub fn is_muted(&self, username: &str) -> bool {
+        self.muted.contains(username)
+    }
+
+    // Tell a user whether they can currently chat
+    pub fn send_state(&self, addr: &Recipient<WSResponse>, username: &str) {
+        let response = json!({
+            "enabled": self.enabled,
+            "muted": self.muted.contains(username),
+            "student_messages_allowed": self.student_messages_allowed,
+        });
+        addr.do_send(WSResponse::Msg(format!("chat {}", response)));
+    }
+}
+
+#[derive(pg_mapper::TryFromRow)]
+pub struct ChatMessage {
+    pub message_id: String,
+    pub sender_un: String,
+    pub recipient_un: Option<String>,
+    pub group_name: Option<String>,
+    pub text: String,
+    pub sent_at: NaiveDateTime,
+}
+
+impl ChatMessage {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "username": self.sender_un,
+            "recipient": self.recipient_un,
+            "group": self.group_name,
+            "uuid": self.message_id,
+            "text": self.text,
+            "sent_at": self.sent_at.timestamp_millis(),
+        })
+    }
+}
+
+pub fn send_chat_error(addr: &Recipient<WSResponse>, reason: &str) {
+    addr.do_send(WSResponse::Msg(format!(
+        "txte {}",
+        json!({ "reason": reason })
+    )));
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SendTextMessage {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub text: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<SendTextMessage> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendTextMessage, _: &mut Self::Context) -> Self::Result {
+        self.post_chat_message(
+            msg.identifier,
+            msg.username,
+            ChatTarget::Room,
+            msg.text,
+            msg.addr,
+        );
+    }
+}
+
+// A message addressed to a single person in the room
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SendDirectMessage {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub recipient: String,
+    pub text: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<SendDirectMessage> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendDirectMessage, _: &mut Self::Context) -> Self::Result {
+        self.post_chat_message(
+            msg.identifier,
+            msg.username,
+            ChatTarget::Direct(msg.recipient),
+            msg.text,
+            msg.addr,
+        );
+    }
+}
+
+// Who a chat message is for
+pub enum ChatTarget {
+    Room,
+    Direct(String),
+    // The breakout group the sender is talking to
+    Group,
+}
+
+impl SessionServer {
+    // Check a message is allowed, deliver it to whoever should see it and save it
+    pub fn post_chat_message(
+        &mut self,
+        identifier: SessionIdentifier,
+        username: String,
+        target: ChatTarget,
+        text: String,
+        addr: Recipient<WSResponse>,
+    ) {
+        if let Some(session) = self.sessions.get_mut(&identifier) {
+            session.touch_student(&username);
+
+            let is_teacher = session.teacher.username == username;
+            let (recipient, group_name) = match target {
+                ChatTarget::Room => (None, None),
+                ChatTarget::Direct(recipient) => (Some(recipient), None),
+                ChatTarget::Group => match session.chat_group_of(&username) {
+                    Some(group_name) => (None, Some(group_name)),
+                    None => return send_chat_error(&addr, "You're not in a group"),
+                },
+            };
+            let to_teacher = recipient.as_ref() == Some(&session.teacher.username);
+
+            // Students can always ask the teacher something privately,
+            // anything else is blocked if chat is off or they've been muted
+            if !is_teacher && !to_teacher {
+                if !session.chat.enabled {
+                    return send_chat_error(&addr, "Chat is turned off");
+                }
+                if session.chat.muted.contains(&username) {
+                    return send_chat_error(&addr, "You have been muted");
+                }
+                if recipient.is_some() && !session.chat.student_messages_allowed {
+                    return send_chat_error(&addr, "You can only message the teacher");
+                }
+            }
+            if let Some(recipient) = &recipient {
+                if *recipient == username
+                    || (!to_teacher && !session.students.contains_key(recipient))
+                {
+                    return send_chat_error(&addr, "Recipient not in session");
+                }
+            }
+            if text.chars().count() > MAX_MESSAGE_LENGTH {
+                return send_chat_error(&addr, "Message too long");
+            }
+            if !session.chat.within_rate_limit(&username) {
+                return send_chat_error(&addr, "Sending messages too quickly");
+            }
+
+            let message = ChatMessage {
+                message_id: Uuid::new_v4().to_string(),
+                sender_un: username,
+                recipient_un: recipient,
+                group_name,
+                text,
+                sent_at: Utc::now().naive_utc(),
+            };
+
+            match (&message.recipient_un, &message.group_name) {
+                // Private messages only go to the sender and recipient
+                (Some(recipient), _) => {
+                    let response = format!("txtp {}", message.to_json());
+                    let recipient_addr = if to_teacher {
+                        Some(&session.teacher.addr)
+                    } else {
+                        session.student_addr(recipient)
+                    };
+
+                    if let Some(recipient_addr) = recipient_addr {
+                        recipient_addr.do_send(WSResponse::Msg(response.to_owned()));
+                    }
+                    addr.do_send(WSResponse::Msg(response));
+                }
+                // Group messages go to everyone in the group
+                (None, Some(group_name)) => {
+                    let response = format!("gtxt {}", message.to_json());
+                    for addr in session.group_addrs(group_name) {
+                        addr.do_send(WSResponse::Msg(response.to_owned()));
+                    }
+                }
+                (None, None) => {
+                    let response = format!("txtm {}", message.to_json());
+
+                    // Send message to all students
+                    for addr in session.student_addrs() {
+                        addr.do_send(WSResponse::Msg(response.to_owned()));
+                    }
+
+                    // And the teacher
+                    session.teacher.addr.do_send(WSResponse::Msg(response));
+                }
+            }
+
+            record_event(
+                &session.writer,
+                &identifier,
+                "chat",
+                Some(&message.sender_un),
+                json!({
+                    "message_id": message.message_id,
+                    "recipient": message.recipient_un,
+                    "group": message.group_name,
+                    "text": message.text,
+                }),
+            );
+            save_chat_message(&session.writer, identifier, message);
+        }
+    }
+
+    // Replay the most recent messages in the room to a user who just joined
+    pub fn send_chat_history(
+        &self,
+        identifier: &SessionIdentifier,
+        username: &str,
+        addr: Recipient<WSResponse>,
+    ) {
+        let db_pool = self.db_pool.clone();
+        let identifier = identifier.clone();
+        let username = username.to_owned();
+
+        actix::spawn(async move {
+            match get_chat_history(
+                &db_pool,
+                &identifier,
+                &username,
+                None,
+                HISTORY_REPLAY_LENGTH,
+            )
+            .await
+            {
+                Ok(mut messages) => {
+                    // History comes back newest first, replay it in order
+                    messages.reverse();
+                    let history = messages
+                        .iter()
+                        .map(ChatMessage::to_json)
+                        .collect::<Vec<_>>();
+                    addr.do_send(WSResponse::Msg(format!(
+                        "txth {}",
+                        serde_json::Value::from(history)
+                    )));
+                }
+                Err(e) => eprintln!("{:?}", e),
+            }
+        });
+    }
+}
+
+impl SessionRoom {
+    // Handle the chat moderation instructions from the teacher
+    pub fn chat_instruction(&mut self, identifier: &SessionIdentifier, instruction: &[&str]) {
+        if instruction[0] == "delMsg" && instruction.len() == 2 {
+            let message_id = instruction[1].to_owned();
+            let response = format!("txtd {}", json!({ "uuid": message_id }));
+
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+            self.teacher.addr.do_send(WSResponse::Msg(response));
+
+            delete_chat_message(
+                &self.writer,
+                identifier,
+                message_id,
+                self.teacher.username.to_owned(),
+            );
+        } else if (instruction[0] == "mute" || instruction[0] == "unmute") && instruction.len() == 2
+        {
+            let username = instruction[1];
+            if instruction[0] == "mute" {
+                self.chat.muted.insert(username.to_owned());
+            } else {
+                self.chat.muted.remove(username);
+            }
+
+            if let Some(addr) = self.student_addr(username) {
+                self.chat.send_state(addr, username);
+            }
+        } else if (instruction[0] == "chat" || instruction[0] == "studentDm")
+            && instruction.len() == 2
+        {
+            if instruction[0] == "chat" {
+                self.chat.enabled = instruction[1] == "on";
+            } else {
+                self.chat.student_messages_allowed = instruction[1] == "on";
+            }
+
+            for (username, student) in self.students.iter() {
+                if student.connected {
+                    self.chat.send_state(&student.addr, username);
+                }
+            }
+        }
+    }
+}
+
+fn save_chat_message(writer: &RoomWriter, identifier: SessionIdentifier, message: ChatMessage) {
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.session_chat_message(message_id, teacher_un, plan_name, session_name, sender_un, recipient_un, group_name, text, sent_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ";
+
+    writer.execute(
+        STATEMENT,
+        vec![
+            Box::new(message.message_id),
+            Box::new(identifier.host),
+            Box::new(identifier.plan_name),
+            Box::new(identifier.session_name),
+            Box::new(message.sender_un),
+            Box::new(message.recipient_un),
+            Box::new(message.group_name),
+            Box::new(message.text),
+            Box::new(message.sent_at),
+        ],
+    );
+}
+
+// Messages are only hidden so they're still available for safeguarding review
+fn delete_chat_message(
+    writer: &RoomWriter,
+    identifier: &SessionIdentifier,
+    message_id: String,
+    deleted_by: String,
+) {
+    const STATEMENT: &str = "
+        UPDATE codeharmony.session_chat_message SET deleted_by = $1, deleted_at = current_timestamp
+        WHERE message_id = $2 AND teacher_un = $3 AND plan_name = $4 AND session_name = $5
+    ";
+
+    writer.execute(
+        STATEMENT,
+        vec![
+            Box::new(deleted_by),
+            Box::new(message_id),
+            Box::new(identifier.host.to_owned()),
+            Box::new(identifier.plan_name.to_owned()),
+            Box::new(identifier.session_name.to_owned()),
+        ],
+    );
+}
+
+// Get a page of messages a user can see in a session, newest first
+// Group messages are seen by the teacher and whoever is in that group now
+// before is the id of the oldest message already seen, messages sent in the same instant are
+// told apart by their id so none get skipped between pages
+pub async fn get_chat_history(
+    db_pool: &Pool,
+    identifier: &SessionIdentifier,
+    username: &str,
+    before: Option<&str>,
+    limit: i64,
+) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error>> {
+    const STATEMENT: &str = "
+        SELECT message_id, sender_un, recipient_un, group_name, text, sent_at FROM codeharmony.session_chat_message
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3 AND deleted_at IS NULL
+        AND (
+            (recipient_un IS NULL AND group_name IS NULL) OR sender_un = $6 OR recipient_un = $6
+            OR (group_name IS NOT NULL AND teacher_un = $6)
+            OR group_name IN (
+                SELECT group_name FROM codeharmony.session_group_member
+                WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3 AND student_un = $6
+            )
+        )
+        AND ($4::CHAR(36) IS NULL OR (sent_at, message_id) < (
+            SELECT sent_at, message_id FROM codeharmony.session_chat_message WHERE message_id = $4
+        ))
+        ORDER BY sent_at DESC, message_id DESC
+        LIMIT $5
+    ";
+
+    let client = db_pool.get().await?;
+    let rows = client
+        .query(
+            STATEMENT,
+            &[
+                &identifier.host,
+                &identifier.plan_name,
+                &identifier.session_name,
+                &before,
+                &limit,
+                &username,
+            ],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(ChatMessage::try_from)
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut chat = ChatState::new();
+
+        // Everyone gets their own allowance
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            assert!(chat.within_rate_limit("student_a"));
+        }
+        assert!(!chat.within_rate_limit("student_a"));
+        assert!(chat.within_rate_limit("student_b"));
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use actix::{Handler, Message, Recipient};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::utils::operational_transform::Operation;
+
+use super::{
+    events::record_event, groups::GROUP_OWNER_PREFIX, RoomWriter, SessionIdentifier, SessionRoom,
+    SessionServer, WSResponse,
+};
+
+// How often changed documents are saved to code_submission
+pub const DOCUMENT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How many past operations are kept to transform late edits against
+const MAX_HISTORY: usize = 200;
+
+// Edits are recorded for the replay a batch at a time rather than one event per keystroke
+// A batch is recorded once it's this old or this big, and whenever the documents are saved
+const OPERATION_BATCH_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_OPERATION_BATCH: usize = 100;
+
+// Same limit as saving code through the API
+const MAX_CODE_LENGTH: usize = 10000;
+
+// Each student has their own document for each section
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct DocumentKey {
+    owner: String,
+    section: String,
+}
+
+// The server's copy of a document, which everyone editing it syncs against
+struct SharedDocument {
+    text: String,
+    revision: usize,
+    // The operations that got the document to its current revision, oldest first
+    history: VecDeque<Operation>,
+    // Whether there are changes that haven't been saved yet
+    dirty: bool,
+    // Operations applied since the last batch was recorded, and when the first of them was
+    unrecorded: Vec<Operation>,
+    unrecorded_since: Option<Instant>,
+}
+
+impl SharedDocument {
+    fn new(text: String) -> Self {
+        Self {
+            text,
+            revision: 0,
+            history: VecDeque::new(),
+            dirty: false,
+            unrecorded: vec![],
+            unrecorded_since: None,
+        }
+    }
+
+    // Bring an operation made against an earlier revision up to date and apply it
+    // Returns the operation as it was applied
+    fn receive(
+        &mut self,
+        revision: usize,
+        operation: Operation,
+    ) -> Result<Operation, &'static str> {
+        let oldest_revision = self.revision - self.history.len();
+        if revision > self.revision {
+            return Err("Unknown revision");
+        }
+        if revision < oldest_revision {
+            return Err("Revision too old");
+        }
+
+        // No document gets longer than MAX_CODE_LENGTH, so counts past it can't be right
+        operation
+            .check_fits(MAX_CODE_LENGTH)
+            .map_err(|_| "Operation doesn't match the document")?;
+
+        let mut operation = operation;
+        for concurrent in self.history.iter().skip(revision - oldest_revision) {
+            operation = Operation::transform(&operation, concurrent)
+                .map_err(|_| "Operation doesn't match the document")?
+                .0;
+        }
+
+        if operation
+            .target_len()
+            .is_none_or(|len| len > MAX_CODE_LENGTH)
+        {
+            return Err("Code too long!");
+        }
+        let text = operation
+            .apply(&self.text)
+            .map_err(|_| "Operation doesn't match the document")?;
+
+        self.text = text;
+        self.revision += 1;
+        self.dirty = true;
+        self.history.push_back(operation.clone());
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.unrecorded.push(operation.clone());
+        self.unrecorded_since.get_or_insert_with(Instant::now);
+
+        Ok(operation)
+    }
+
+    fn batch_full(&self) -> bool {
+        self.unrecorded.len() >= MAX_OPERATION_BATCH
+            || self
+                .unrecorded_since
+                .is_some_and(|since| since.elapsed() >= OPERATION_BATCH_INTERVAL)
+    }
+}
+
+// Record the operations a document has had since the last batch as one event
+// revision is the one the document is at after the last of them
+fn record_operations(
+    writer: &RoomWriter,
+    identifier: &SessionIdentifier,
+    key: &DocumentKey,
+    document: &mut SharedDocument,
+) {
+    if document.unrecorded.is_empty() {
+        return;
+    }
+    document.unrecorded_since = None;
+
+    record_event(
+        writer,
+        identifier,
+        "op",
+        None,
+        json!({
+            "owner": key.owner,
+            "section": key.section,
+            "revision": document.revision,
+            "operations": std::mem::take(&mut document.unrecorded),
+        }),
+    );
+}
+
+// Every document being edited in the room
+pub struct Documents {
+    documents: HashMap<DocumentKey, SharedDocument>,
+}
+
+impl Documents {
+    pub fn new() -> Self {
+        Self {
+            documents: HashMap::new(),
+        }
+    }
+
+    // Move a user's documents over to their new username, keeping unsaved edits
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        let keys = self
+            .documents
+            .keys()
+            .filter(|key| key.owner == old)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some(document) = self.documents.remove(&key) {
+                let key = DocumentKey {
+                    owner: new.to_owned(),
+                    section: key.section,
+                };
+                self.documents.insert(key, document);
+            }
+        }
+    }
+}
+
+// What a client sends to start syncing a document
+// Students can only open their own, the teacher has to say whose they want
+#[derive(Deserialize, Debug)]
+struct OpenDocumentRequest {
+    owner: Option<String>,
+    section: String,
+    code: Option<String>,
+}
+
+// An edit to a document, made against the revision the client last saw
+#[derive(Deserialize, Debug)]
+struct OperationRequest {
+    owner: Option<String>,
+    section: String,
+    revision: usize,
+    ops: Operation,
+}
+
+impl SessionRoom {
+    // Work out whose document someone means, checking they're allowed to edit it
+    fn document_key(
+        &self,
+        username: &str,
+        owner: Option<String>,
+        section: String,
+    ) -> Option<DocumentKey> {
+        let owner = if self.teacher.username == username {
+            owner?
+        } else if !self.students.contains_key(username) {
+            return None;
+        } else if let Some(group_name) = owner
+            .as_deref()
+            .and_then(|owner| owner.strip_prefix(GROUP_OWNER_PREFIX))
+        {
+            // Students can only edit the shared document of their own group
+            if self.groups.group_of(username) != Some(group_name) {
+                return None;
+            }
+            format!("{}{}", GROUP_OWNER_PREFIX, group_name)
+        } else {
+            username.to_owned()
+        };
+
+        Some(DocumentKey { owner, section })
+    }
+
+    // Send someone the full document and the revision it's at
+    fn send_document(&self, addr: &Recipient<WSResponse>, key: &DocumentKey) {
+        if let Some(document) = self.documents.documents.get(key) {
+            let response = json!({
+                "owner": key.owner,
+                "section": key.section,
+                "revision": document.revision,
+                "code": document.text,
+            });
+            addr.do_send(WSResponse::Msg(format!("opInit {}", response)));
+        }
+    }
+
+    fn send_document_error(&self, addr: &Recipient<WSResponse>, key: &DocumentKey, reason: &str) {
+        let response = json!({"owner": key.owner, "section": key.section, "reason": reason});
+        addr.do_send(WSResponse::Msg(format!("opErr {}", response)));
+
+        // Get the client back in sync
+        self.send_document(addr, key);
+    }
+
+    // Pass an applied operation on to everyone else who has the document open
+    fn forward_operation(&mut self, author: &str, key: &DocumentKey, operation: &Operation) {
+        let (revision, text) = match self.documents.documents.get(key) {
+            Some(document) => (document.revision, document.text.to_owned()),
+            None => return,
+        };
+
+        let response = format!(
+            "op {}",
+            json!({
+                "owner": key.owner,
+                "section": key.section,
+                "revision": revision,
+                "ops": operation,
+                "author": author,
+            })
+        );
+
+        if let Some(group_name) = key.owner.strip_prefix(GROUP_OWNER_PREFIX) {
+            return self.forward_group_operation(group_name, author, &response);
+        }
+
+        if author == self.teacher.username {
+            if let Some(addr) = self.student_addr(&key.owner) {
+                addr.do_send(WSResponse::Msg(response));
+            }
+        } else if self.is_focused(&key.owner) {
+            self.teacher.addr.do_send(WSResponse::Msg(response));
+        } else if self.is_watched(&key.owner) {
+            self.queue_snapshot(&key.owner, &text);
+        }
+
+        self.forward_spotlight(&key.owner, "spotDoc", &text);
+    }
+
+    // Change a student's document for the teacher, going round the editor lock and the time limit
+    // The edit is made against the latest revision and reaches the student like any other op
+    fn edit_for_teacher(
+        &mut self,
+        identifier: &SessionIdentifier,
+        key: &DocumentKey,
+        edit: impl FnOnce(&str) -> Operation,
+    ) -> Result<(), &'static str> {
+        let document = self
+            .documents
+            .documents
+            .get_mut(key)
+            .ok_or("Document not open")?;
+        let operation = document.receive(document.revision, edit(&document.text))?;
+        record_operations(&self.writer, identifier, key, document);
+
+        let author = self.teacher.username.to_owned();
+        self.forward_operation(&author, key, &operation);
+
+        // The teacher's view of the student didn't make the edit so it needs catching up
+        if self.is_focused(&key.owner) {
+            self.send_document(&self.teacher.addr, key);
+        }
+        Ok(())
+    }
+
+    // Put a student's code for a section back to how it started
+    pub fn reset_document(
+        &mut self,
+        identifier: &SessionIdentifier,
+        owner: &str,
+        section: &str,
+        code: String,
+    ) -> Result<(), &'static str> {
+        if code.chars().count() > MAX_CODE_LENGTH {
+            return Err("Code too long!");
+        }
+        let key = DocumentKey {
+            owner: owner.to_owned(),
+            section: section.to_owned(),
+        };
+
+        // If the student hasn't opened it yet they get the starting code when they do
+        if !self.documents.documents.contains_key(&key) {
+            record_event(
+                &self.writer,
+                identifier,
+                "doc",
+                Some(&self.teacher.username),
+                json!({"owner": key.owner, "section": key.section, "code": code}),
+            );
+            let mut document = SharedDocument::new(code);
+            document.dirty = true;
+            self.documents.documents.insert(key, document);
+            return Ok(());
+        }
+
+        self.edit_for_teacher(identifier, &key, |text| {
+            let mut operation = Operation::new();
+            operation.delete(text.chars().count()).insert(&code);
+            operation
+        })
+    }
+
+    // Add some code to the end of a student's document
+    pub fn push_to_document(
+        &mut self,
+        identifier: &SessionIdentifier,
+        owner: &str,
+        section: &str,
+        code: &str,
+    ) -> Result<(), &'static str> {
+        let key = DocumentKey {
+            owner: owner.to_owned(),
+            section: section.to_owned(),
+        };
+        self.edit_for_teacher(identifier, &key, |text| {
+            let mut operation = Operation::new();
+            operation.retain(text.chars().count()).insert(code);
+            operation
+        })
+    }
+
+    // Save any documents that have changed since they were last saved
+    pub fn save_documents(&mut self, identifier: &SessionIdentifier) {
+        for (key, document) in self.documents.documents.iter_mut() {
+            record_operations(&self.writer, identifier, key, document);
+            if !document.dirty {
+                continue;
+            }
+            document.dirty = false;
+
+            // Group documents are saved against the group instead of a student
+            if let Some(group_name) = key.owner.strip_prefix(GROUP_OWNER_PREFIX) {
+                const STATEMENT: &str = "
+                    INSERT INTO codeharmony.session_group_code(teacher_un, plan_name, session_name, group_name, section_name, code)
+                    VALUES($1,$2,$3,$4,$5,$6)
+                    ON CONFLICT ON CONSTRAINT session_group_code_pk
+                    DO UPDATE SET code=$6
+                ";
+                self.writer.execute(
+                    STATEMENT,
+                    vec![
+                        Box::new(identifier.host.to_owned()),
+                        Box::new(identifier.plan_name.to_owned()),
+                        Box::new(identifier.session_name.to_owned()),
+                        Box::new(group_name.to_owned()),
+                        Box::new(key.section.to_owned()),
+                        Box::new(document.text.to_owned()),
+                    ],
+                );
+                continue;
+            }
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.code_submission(teacher_un, plan_name, section_name, session_name, student_un, code)
+                VALUES($1,$2,$3,$4,$5,$6)
+                ON CONFLICT ON CONSTRAINT code_submission_pk
+                DO UPDATE SET code=$6
+            ";
+            self.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(identifier.host.to_owned()),
+                    Box::new(identifier.plan_name.to_owned()),
+                    Box::new(key.section.to_owned()),
+                    Box::new(identifier.session_name.to_owned()),
+                    Box::new(key.owner.to_owned()),
+                    Box::new(document.text.to_owned()),
+                ],
+            );
+        }
+    }
+}
+
+impl SessionServer {
+    // Save the documents in every room
+    pub fn save_all_documents(&mut self) {
+        for (identifier, room) in self.sessions.iter_mut() {
+            room.save_documents(identifier);
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct OpenDocument {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub payload: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<OpenDocument> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: OpenDocument, _: &mut Self::Context) -> Self::Result {
+        let request = match serde_json::from_str::<OpenDocumentRequest>(&msg.payload) {
+            Ok(request) => request,
+            Err(e) => return eprintln!("{:?}", e),
+        };
+
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            let key = match session.document_key(&msg.username, request.owner, request.section) {
+                Some(key) => key,
+                None => return,
+            };
+
+            // The student's copy only counts if the server doesn't have one yet,
+            // otherwise they pick up where they left off
+            if !session.documents.documents.contains_key(&key) {
+                if session.teacher.username == msg.username {
+                    let response = json!({"owner": key.owner, "section": key.section, "reason": "Document not open"});
+                    return msg
+                        .addr
+                        .do_send(WSResponse::Msg(format!("opErr {}", response)));
+                }
+
+                let code = request.code.unwrap_or_default();
+                if code.chars().count() > MAX_CODE_LENGTH {
+                    return session.send_document_error(&msg.addr, &key, "Code too long!");
+                }
+                record_event(
+                    &session.writer,
+                    &msg.identifier,
+                    "doc",
+                    Some(&msg.username),
+                    json!({"owner": key.owner, "section": key.section, "code": code}),
+                );
+                session
+                    .documents
+                    .documents
+                    .insert(key.clone(), SharedDocument::new(code));
+            }
+
+            session.touch_student(&msg.username);
+            session.send_document(&msg.addr, &key);
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ApplyOperation {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub payload: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<ApplyOperation> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ApplyOperation, _: &mut Self::Context) -> Self::Result {
+        let request = match serde_json::from_str::<OperationRequest>(&msg.payload) {
+            Ok(request) => request,
+            Err(e) => return eprintln!("{:?}", e),
+        };
+
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            let key = match session.document_key(&msg.username, request.owner, request.section) {
+                Some(key) => key,
+                None => return,
+            };
+            session.touch_student(&msg.username);
+
+            if session.is_locked(&msg.username) {
+                return session.send_document_error(&msg.addr, &key, "Editor is locked");
+            }
+            if !session.submissions_open(&key.owner, &key.section) {
+                return session.send_document_error(&msg.addr, &key, "Time is up");
+            }
+
+            let applied = match session.documents.documents.get_mut(&key) {
+                Some(document) => document.receive(request.revision, request.ops),
+                None => Err("Document not open"),
+            };
+
+            match applied {
+                Ok(operation) => {
+                    if let Some(document) = session.documents.documents.get_mut(&key) {
+                        let response = json!({"owner": key.owner, "section": key.section, "revision": document.revision});
+                        msg.addr
+                            .do_send(WSResponse::Msg(format!("opAck {}", response)));
+
+                        if document.batch_full() {
+                            record_operations(&session.writer, &msg.identifier, &key, document);
+                        }
+                    }
+                    session.forward_operation(&msg.username, &key, &operation);
+                }
+                Err(reason) => session.send_document_error(&msg.addr, &key, reason),
+            }
+        }
+    }
+}
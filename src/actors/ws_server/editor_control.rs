@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+
+use actix::{ActorFutureExt, AsyncContext, Context, Recipient, WrapFuture};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{
+    rename::rename_member, RoomWriter, SessionIdentifier, SessionRoom, SessionServer, WSResponse,
+};
+
+// Which student editors the teacher has frozen
+pub struct EditorControl {
+    all_locked: bool,
+    locked: HashSet<String>,
+}
+
+impl EditorControl {
+    pub fn new() -> Self {
+        Self {
+            all_locked: false,
+            locked: HashSet::new(),
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        rename_member(&mut self.locked, old, new);
+    }
+}
+
+// Code the teacher wants added to the end of a student's document
+#[derive(Deserialize)]
+struct PushRequest {
+    student: String,
+    section: String,
+    code: String,
+}
+
+fn send_control_event(addr: &Recipient<WSResponse>, event: serde_json::Value) {
+    addr.do_send(WSResponse::Msg(format!("ctrl {}", event)));
+}
+
+// Record something the teacher did to student editors
+// student is None when it was done to the whole class
+fn log_control_action(
+    writer: &RoomWriter,
+    identifier: &SessionIdentifier,
+    action: &str,
+    student: Option<&str>,
+    detail: Option<String>,
+) {
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.session_control_log(log_id, teacher_un, plan_name, session_name, action, student_un, detail)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+    ";
+    writer.execute(
+        STATEMENT,
+        vec![
+            Box::new(Uuid::new_v4().to_string()),
+            Box::new(identifier.host.to_owned()),
+            Box::new(identifier.plan_name.to_owned()),
+            Box::new(identifier.session_name.to_owned()),
+            Box::new(action.to_owned()),
+            Box::new(student.map(str::to_owned)),
+            Box::new(detail),
+        ],
+    );
+}
+
+impl SessionRoom {
+    // The teacher's editor is never locked, even when the whole class is
+    pub fn is_locked(&self, username: &str) -> bool {
+        self.teacher.username != username
+            && (self.editor_control.all_locked || self.editor_control.locked.contains(username))
+    }
+
+    // Tell a student who just joined if their editor is frozen
+    pub fn send_lock_state(&self, addr: &Recipient<WSResponse>, username: &str) {
+        if self.is_locked(username) {
+            send_control_event(addr, json!({"action": "lock"}));
+        }
+    }
+
+    // Let the teacher know whose editors are frozen
+    pub fn send_locks(&self) {
+        let response = json!({
+            "all": self.editor_control.all_locked,
+            "students": self.editor_control.locked,
+        });
+        self.teacher
+            .addr
+            .do_send(WSResponse::Msg(format!("locks {}", response)));
+    }
+
+    // Lock or unlock the named students, or everyone if no one is named
+    fn set_locked(&mut self, usernames: &[&str], locked: bool) {
+        if usernames.is_empty() {
+            self.editor_control.all_locked = locked;
+            self.editor_control.locked.clear();
+        } else {
+            // Unlocking some students while the class is locked leaves everyone else locked
+            if self.editor_control.all_locked && !locked {
+                self.editor_control.all_locked = false;
+                self.editor_control.locked = self.students.keys().cloned().collect();
+            }
+            for username in usernames {
+                if locked {
+                    self.editor_control.locked.insert((*username).to_owned());
+                } else {
+                    self.editor_control.locked.remove(*username);
+                }
+            }
+        }
+
+        let event = json!({"action": if locked { "lock" } else { "unlock" }});
+        for (username, student) in self.students.iter() {
+            if student.connected && (usernames.is_empty() || usernames.contains(&username.as_str()))
+            {
+                send_control_event(&student.addr, event.clone());
+            }
+        }
+        self.send_locks();
+    }
+
+    // Handle the editor control instructions from the teacher
+    // Reset is handled by the server since it has to look up the starting code first
+    pub fn editor_control_instruction(
+        &mut self,
+        identifier: &SessionIdentifier,
+        instruction: &[&str],
+    ) {
+        if instruction[0] == "lock" || instruction[0] == "unlock" {
+            let locked = instruction[0] == "lock";
+            self.set_locked(&instruction[1..], locked);
+
+            if instruction.len() == 1 {
+                log_control_action(&self.writer, identifier, instruction[0], None, None);
+            } else {
+                for username in &instruction[1..] {
+                    log_control_action(
+                        &self.writer,
+                        identifier,
+                        instruction[0],
+                        Some(username),
+                        None,
+                    );
+                }
+            }
+        } else if instruction[0] == "push" && instruction.len() > 1 {
+            let push = match serde_json::from_str::<PushRequest>(&instruction[1..].join(" ")) {
+                Ok(push) => push,
+                Err(e) => return eprintln!("{:?}", e),
+            };
+            let addr = match self.student_addr(&push.student) {
+                Some(addr) => addr.clone(),
+                None => return,
+            };
+
+            if let Err(reason) =
+                self.push_to_document(identifier, &push.student, &push.section, &push.code)
+            {
+                return self.send_control_error(&push.student, &push.section, reason);
+            }
+            send_control_event(&addr, json!({"action": "push", "section": push.section}));
+            log_control_action(
+                &self.writer,
+                identifier,
+                "push",
+                Some(&push.student),
+                Some(push.code),
+            );
+        }
+    }
+
+    // Let the teacher know a reset or push didn't go through
+    fn send_control_error(&self, username: &str, section: &str, reason: &str) {
+        let response = json!({"student": username, "section": section, "reason": reason});
+        self.teacher
+            .addr
+            .do_send(WSResponse::Msg(format!("ctrlErr {}", response)));
+    }
+}
+
+impl SessionServer {
+    // Put a student's code back to the section's starting code
+    // The code is changed in the room's copy of the document so it reaches the student as an op
+    pub fn reset_instruction(
+        &mut self,
+        identifier: &SessionIdentifier,
+        instruction: &[&str],
+        ctx: &mut Context<Self>,
+    ) {
+        if instruction[0] != "reset" || instruction.len() < 3 {
+            return;
+        }
+
+        // Section names can have spaces in them
+        let username = instruction[1].to_owned();
+        let section_name = instruction[2..].join(" ");
+        match self.sessions.get(identifier) {
+            Some(session) if session.student_addr(&username).is_some() => {}
+            _ => return,
+        }
+
+        let db_pool = self.db_pool.clone();
+        let host = identifier.host.to_owned();
+        let plan_name = identifier.plan_name.to_owned();
+        let section = section_name.to_owned();
+        let starting_code = async move {
+            let client = db_pool.get().await?;
+
+            const STATEMENT: &str = "
+                SELECT coding_data->>'startingCode' FROM codeharmony.lesson_plan_section
+                WHERE username = $1 AND plan_name = $2 AND section_name = $3
+            ";
+            let rows = client
+                .query(STATEMENT, &[&host, &plan_name, &section])
+                .await?;
+
+            Ok::<_, Box<dyn std::error::Error>>(rows.first().map(|row| {
+                row.try_get::<usize, Option<String>>(0)
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default()
+            }))
+        };
+
+        let identifier = identifier.clone();
+        ctx.spawn(
+            starting_code
+                .into_actor(self)
+                .map(move |starting_code, act, _| {
+                    let code = match starting_code {
+                        Ok(Some(code)) => code,
+                        Ok(None) => return,
+                        Err(e) => return eprintln!("{:?}", e),
+                    };
+                    let session = match act.sessions.get_mut(&identifier) {
+                        Some(session) => session,
+                        None => return,
+                    };
+
+                    if let Err(reason) =
+                        session.reset_document(&identifier, &username, &section_name, code)
+                    {
+                        return session.send_control_error(&username, &section_name, reason);
+                    }
+                    if let Some(addr) = session.student_addr(&username) {
+                        send_control_event(
+                            addr,
+                            json!({"action": "reset", "section": section_name}),
+                        );
+                    }
+                    log_control_action(
+                        &session.writer,
+                        &identifier,
+                        "reset",
+                        Some(&username),
+                        Some(section_name),
+                    );
+                }),
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde_json::json;
+
+use super::{rename::rename_key, RoomWriter, SessionIdentifier};
+
+// Editors that send the whole code on every change get a snapshot recorded at most this often,
+// rather than one for every keystroke
+pub const CODE_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Save something that happened in a room so the session can be replayed later
+// username is whoever it happened to or was done by, if anyone
+pub fn record_event(
+    writer: &RoomWriter,
+    identifier: &SessionIdentifier,
+    kind: &str,
+    username: Option<&str>,
+    data: serde_json::Value,
+) {
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.session_event(teacher_un, plan_name, session_name, kind, username, data, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+    ";
+    writer.execute(
+        STATEMENT,
+        vec![
+            Box::new(identifier.host.to_owned()),
+            Box::new(identifier.plan_name.to_owned()),
+            Box::new(identifier.session_name.to_owned()),
+            Box::new(kind.to_owned()),
+            Box::new(username.map(str::to_owned)),
+            Box::new(data),
+            Box::new(Utc::now().naive_utc()),
+        ],
+    );
+}
+
+// The latest code students have sent, held back until it's time for another snapshot
+pub struct CodeSnapshots {
+    // Code and section index by student
+    pending: HashMap<String, (String, usize)>,
+    last_recorded: HashMap<String, Instant>,
+}
+
+impl CodeSnapshots {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            last_recorded: HashMap::new(),
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        rename_key(&mut self.pending, old, new);
+        rename_key(&mut self.last_recorded, old, new);
+    }
+
+    // Record the code now if the student hasn't had a snapshot for a while, otherwise hold on to it
+    // Moving section records what was held back first, so each section keeps its last code
+    pub fn update(
+        &mut self,
+        writer: &RoomWriter,
+        identifier: &SessionIdentifier,
+        username: &str,
+        code: &str,
+        section_index: usize,
+    ) {
+        if matches!(self.pending.get(username), Some((_, section)) if *section != section_index) {
+            self.record(writer, identifier, username);
+        }
+
+        let due = self
+            .last_recorded
+            .get(username)
+            .is_none_or(|at| at.elapsed() >= CODE_SNAPSHOT_INTERVAL);
+        self.pending
+            .insert(username.to_owned(), (code.to_owned(), section_index));
+        if due {
+            self.record(writer, identifier, username);
+        }
+    }
+
+    // Record everything that's been held back
+    pub fn flush(&mut self, writer: &RoomWriter, identifier: &SessionIdentifier) {
+        let usernames = self.pending.keys().cloned().collect::<Vec<_>>();
+        for username in usernames {
+            self.record(writer, identifier, &username);
+        }
+    }
+
+    fn record(&mut self, writer: &RoomWriter, identifier: &SessionIdentifier, username: &str) {
+        if let Some((code, section_index)) = self.pending.remove(username) {
+            record_event(
+                writer,
+                identifier,
+                "code",
+                Some(username),
+                json!({"code": code, "section_index": section_index}),
+            );
+            self.last_recorded
+                .insert(username.to_owned(), Instant::now());
+        }
+    }
+}
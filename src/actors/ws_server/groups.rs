@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+
+use actix::{Handler, Message, Recipient};
+use chrono::Utc;
+use rand::seq::SliceRandom;
+use serde_json::json;
+
+use super::{
+    chat::ChatTarget, rename::rename_member, SessionIdentifier, SessionRoom, SessionServer,
+    Statement, WSResponse,
+};
+
+// Shared group documents are owned by "group:<name>" rather than a student
+pub const GROUP_OWNER_PREFIX: &str = "group:";
+
+// Longest name a group can have, same as a section name
+const MAX_GROUP_NAME_LENGTH: usize = 64;
+
+// Breakout groups the teacher has split the room into
+pub struct Groups {
+    groups: HashMap<String, HashSet<String>>,
+    // The group the teacher has dropped in on, if any
+    teacher_group: Option<String>,
+}
+
+impl Groups {
+    pub fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+            teacher_group: None,
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for members in self.groups.values_mut() {
+            rename_member(members, old, new);
+        }
+    }
+
+    pub fn group_of(&self, username: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, members)| members.contains(username))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl SessionRoom {
+    // Addresses of everyone who should hear what's happening in a group
+    pub fn group_addrs(&self, group_name: &str) -> Vec<&Recipient<WSResponse>> {
+        let mut addrs = self
+            .groups
+            .groups
+            .get(group_name)
+            .map(|members| {
+                members
+                    .iter()
+                    .filter_map(|member| self.student_addr(member))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        if self.groups.teacher_group.as_deref() == Some(group_name) {
+            addrs.push(&self.teacher.addr);
+        }
+        addrs
+    }
+
+    // The group someone is talking to
+    // The teacher talks to whichever group they've dropped in on
+    pub fn chat_group_of(&self, username: &str) -> Option<String> {
+        if self.teacher.username == username {
+            self.groups.teacher_group.clone()
+        } else {
+            self.groups.group_of(username).map(str::to_owned)
+        }
+    }
+
+    // Tell a student which group they're in and who with
+    pub fn send_group(&self, addr: &Recipient<WSResponse>, username: &str) {
+        let response = match self.groups.group_of(username) {
+            Some(name) => json!({"name": name, "members": self.groups.groups[name]}),
+            None => json!({ "name": null }),
+        };
+        addr.do_send(WSResponse::Msg(format!("group {}", response)));
+    }
+
+    // Let the teacher see every group, and each student see their own
+    pub fn send_groups(&self) {
+        let response = json!({
+            "groups": self.groups.groups,
+            "teacher_group": self.groups.teacher_group,
+        });
+        self.teacher
+            .addr
+            .do_send(WSResponse::Msg(format!("groups {}", response)));
+
+        for (username, student) in self.students.iter() {
+            if student.connected {
+                self.send_group(&student.addr, username);
+            }
+        }
+    }
+
+    // Pass an edit to a group's shared document on to the rest of the group
+    pub fn forward_group_operation(&self, group_name: &str, author: &str, response: &str) {
+        let author_addr = if author == self.teacher.username {
+            Some(&self.teacher.addr)
+        } else {
+            self.student_addr(author)
+        };
+
+        for addr in self.group_addrs(group_name) {
+            if Some(addr) != author_addr {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+        }
+    }
+
+    // Put students in a group, taking them out of any group they were in before
+    // Returns the statement that saves the group, for the caller to write
+    fn assign_group(
+        &mut self,
+        identifier: &SessionIdentifier,
+        name: &str,
+        usernames: Vec<String>,
+    ) -> Statement {
+        for members in self.groups.groups.values_mut() {
+            for username in usernames.iter() {
+                members.remove(username);
+            }
+        }
+        self.groups.groups.retain(|_, members| !members.is_empty());
+        self.groups
+            .groups
+            .entry(name.to_owned())
+            .or_default()
+            .extend(usernames);
+
+        save_group(identifier, name, &self.groups.groups[name])
+    }
+
+    // Handle the breakout group instructions from the teacher
+    pub fn group_instruction(&mut self, identifier: &SessionIdentifier, instruction: &[&str]) {
+        if instruction[0] == "groups" && instruction.len() == 3 && instruction[1] == "random" {
+            let size = match instruction[2].parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return,
+            };
+
+            let mut usernames = self
+                .students
+                .iter()
+                .filter(|(_, student)| student.connected)
+                .map(|(username, _)| username.to_owned())
+                .collect::<Vec<_>>();
+            usernames.shuffle(&mut rand::thread_rng());
+
+            let mut chunks = usernames
+                .chunks(size)
+                .map(|chunk| chunk.to_vec())
+                .collect::<Vec<_>>();
+
+            // Nobody gets left on their own, they join the last group instead
+            if size > 1 && chunks.len() > 1 && chunks.last().is_some_and(|last| last.len() == 1) {
+                if let Some(last) = chunks.pop() {
+                    if let Some(previous) = chunks.last_mut() {
+                        previous.extend(last);
+                    }
+                }
+            }
+
+            // The old groups end and the new ones start together so they can't be written out of order
+            let mut statements = vec![end_groups(identifier)];
+            self.groups.groups.clear();
+            self.groups.teacher_group = None;
+            for (i, members) in chunks.into_iter().enumerate() {
+                statements.push(self.assign_group(
+                    identifier,
+                    &format!("group-{}", i + 1),
+                    members,
+                ));
+            }
+            self.writer.execute_all(statements);
+            self.send_groups();
+        } else if instruction[0] == "group" && instruction.len() >= 3 {
+            let name = instruction[1];
+            if name.chars().count() > MAX_GROUP_NAME_LENGTH {
+                return;
+            }
+
+            let usernames = instruction[2..]
+                .iter()
+                .filter(|username| self.students.contains_key(**username))
+                .map(|username| (*username).to_owned())
+                .collect::<Vec<_>>();
+            if usernames.is_empty() {
+                return;
+            }
+
+            let statement = self.assign_group(identifier, name, usernames);
+            self.writer.execute_all(vec![statement]);
+            self.send_groups();
+        } else if instruction[0] == "endGroups" {
+            self.groups.groups.clear();
+            self.groups.teacher_group = None;
+            self.writer.execute_all(vec![end_groups(identifier)]);
+            self.send_groups();
+        } else if instruction[0] == "joinGroup" && instruction.len() == 2 {
+            if self.groups.groups.contains_key(instruction[1]) {
+                self.groups.teacher_group = Some(instruction[1].to_owned());
+                self.send_groups();
+            }
+        } else if instruction[0] == "leaveGroup" {
+            self.groups.teacher_group = None;
+            self.send_groups();
+        } else if instruction[0] == "broadcastGroups" && instruction.len() > 1 {
+            let text = instruction[1..].join(" ");
+            for (name, members) in self.groups.groups.iter() {
+                let response = json!({
+                    "group": name,
+                    "username": self.teacher.username,
+                    "text": text,
+                    "sent_at": Utc::now().timestamp_millis(),
+                });
+                for member in members {
+                    if let Some(addr) = self.student_addr(member) {
+                        addr.do_send(WSResponse::Msg(format!("gtxt {}", response)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Record who's in a group, moving anyone who was in another group
+fn save_group(identifier: &SessionIdentifier, name: &str, members: &HashSet<String>) -> Statement {
+    const STATEMENT: &str = "
+        WITH created AS (
+            INSERT INTO codeharmony.session_group(teacher_un, plan_name, session_name, group_name)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT ON CONSTRAINT session_group_pk DO UPDATE SET ended_at = NULL
+        ), removed AS (
+            DELETE FROM codeharmony.session_group_member
+            WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3 AND group_name = $4
+            AND NOT (student_un = ANY($5::VARCHAR[]))
+        )
+        INSERT INTO codeharmony.session_group_member(teacher_un, plan_name, session_name, group_name, student_un)
+        SELECT $1, $2, $3, $4, unnest($5::VARCHAR[])
+        ON CONFLICT ON CONSTRAINT session_group_member_pk DO UPDATE SET group_name = $4
+    ";
+    (
+        STATEMENT,
+        vec![
+            Box::new(identifier.host.to_owned()),
+            Box::new(identifier.plan_name.to_owned()),
+            Box::new(identifier.session_name.to_owned()),
+            Box::new(name.to_owned()),
+            Box::new(members.iter().cloned().collect::<Vec<String>>()),
+        ],
+    )
+}
+
+// Groups are kept once they end so the shared code can still be looked at
+fn end_groups(identifier: &SessionIdentifier) -> Statement {
+    const STATEMENT: &str = "
+        UPDATE codeharmony.session_group SET ended_at = current_timestamp
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3 AND ended_at IS NULL
+    ";
+    (
+        STATEMENT,
+        vec![
+            Box::new(identifier.host.to_owned()),
+            Box::new(identifier.plan_name.to_owned()),
+            Box::new(identifier.session_name.to_owned()),
+        ],
+    )
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SendGroupMessage {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub text: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<SendGroupMessage> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendGroupMessage, _: &mut Self::Context) -> Self::Result {
+        self.post_chat_message(
+            msg.identifier,
+            msg.username,
+            ChatTarget::Group,
+            msg.text,
+            msg.addr,
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use actix::{Handler, Message};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{events::record_event, SessionIdentifier, SessionRoom, SessionServer, WSResponse};
+
+// Longest note a student can attach to a raised hand
+const MAX_NOTE_LENGTH: usize = 300;
+
+struct HelpRequest {
+    request_id: String,
+    username: String,
+    note: String,
+    raised_at: DateTime<Utc>,
+    claimed_at: Option<DateTime<Utc>>,
+}
+
+impl HelpRequest {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "request_id": self.request_id,
+            "username": self.username,
+            "note": self.note,
+            "raised_at": self.raised_at.timestamp_millis(),
+            "claimed_at": self.claimed_at.map(|claimed_at| claimed_at.timestamp_millis()),
+        })
+    }
+}
+
+// Open help requests in the room, in the order they were raised
+pub struct HelpQueue {
+    requests: Vec<HelpRequest>,
+}
+
+impl HelpQueue {
+    pub fn new() -> Self {
+        Self { requests: vec![] }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for request in self
+            .requests
+            .iter_mut()
+            .filter(|request| request.username == old)
+        {
+            request.username = new.to_owned();
+        }
+    }
+}
+
+impl SessionRoom {
+    // Send the queue to the teacher and let each waiting student know where they are
+    pub fn send_help_queue(&self) {
+        let queue = self
+            .help_queue
+            .requests
+            .iter()
+            .map(HelpRequest::to_json)
+            .collect::<Vec<_>>();
+        self.teacher.addr.do_send(WSResponse::Msg(format!(
+            "helpq {}",
+            serde_json::Value::from(queue)
+        )));
+
+        for (position, request) in self.help_queue.requests.iter().enumerate() {
+            if let Some(addr) = self.student_addr(&request.username) {
+                let response = json!({
+                    "request_id": request.request_id,
+                    "state": if request.claimed_at.is_some() { "claimed" } else { "raised" },
+                    "position": position,
+                });
+                addr.do_send(WSResponse::Msg(format!("hand {}", response)));
+            }
+        }
+    }
+
+    // Take a request out of the queue and tell the student it's done
+    fn resolve_help_request(&mut self, request_id: &str, resolved_by: &str) -> bool {
+        let position = self
+            .help_queue
+            .requests
+            .iter()
+            .position(|request| request.request_id == request_id);
+
+        if let Some(position) = position {
+            let request = self.help_queue.requests.remove(position);
+
+            if let Some(addr) = self.student_addr(&request.username) {
+                let response = json!({"request_id": request.request_id, "state": "resolved"});
+                addr.do_send(WSResponse::Msg(format!("hand {}", response)));
+            }
+
+            const STATEMENT: &str = "
+                UPDATE codeharmony.session_help_request
+                SET resolved_at = current_timestamp, resolved_by = $1
+                WHERE request_id = $2
+            ";
+            self.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(resolved_by.to_owned()),
+                    Box::new(request.request_id),
+                ],
+            );
+            return true;
+        }
+        false
+    }
+
+    // Handle the help queue instructions from the teacher
+    pub fn help_queue_instruction(&mut self, instruction: &[&str], max_watched: usize) {
+        if instruction[0] == "claim" && instruction.len() == 2 {
+            let claimed = self
+                .help_queue
+                .requests
+                .iter_mut()
+                .find(|request| {
+                    request.request_id == instruction[1] && request.claimed_at.is_none()
+                })
+                .map(|request| {
+                    let now = Utc::now();
+                    request.claimed_at = Some(now);
+                    (request.username.to_owned(), now)
+                });
+
+            if let Some((username, claimed_at)) = claimed {
+                // Start watching the student's code straight away
+                self.focus(&username, max_watched);
+                self.send_help_queue();
+
+                const STATEMENT: &str = "
+                    UPDATE codeharmony.session_help_request SET claimed_at = $1 WHERE request_id = $2
+                ";
+                self.writer.execute(
+                    STATEMENT,
+                    vec![
+                        Box::new(claimed_at.naive_utc()),
+                        Box::new(instruction[1].to_owned()),
+                    ],
+                );
+            }
+        } else if instruction[0] == "resolve" && instruction.len() == 2 {
+            let teacher = self.teacher.username.to_owned();
+            if self.resolve_help_request(instruction[1], &teacher) {
+                self.send_help_queue();
+            }
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RaiseHand {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub note: String,
+}
+
+impl Handler<RaiseHand> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaiseHand, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            // Only students in the room can ask for help, and only once at a time
+            if !session.students.contains_key(&msg.username)
+                || session
+                    .help_queue
+                    .requests
+                    .iter()
+                    .any(|request| request.username == msg.username)
+            {
+                return;
+            }
+
+            session.touch_student(&msg.username);
+
+            let request = HelpRequest {
+                request_id: Uuid::new_v4().to_string(),
+                username: msg.username,
+                note: msg.note.chars().take(MAX_NOTE_LENGTH).collect(),
+                raised_at: Utc::now(),
+                claimed_at: None,
+            };
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_help_request(request_id, teacher_un, plan_name, session_name, student_un, note, raised_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ";
+            session.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(request.request_id.to_owned()),
+                    Box::new(msg.identifier.host.to_owned()),
+                    Box::new(msg.identifier.plan_name.to_owned()),
+                    Box::new(msg.identifier.session_name.to_owned()),
+                    Box::new(request.username.to_owned()),
+                    Box::new(request.note.to_owned()),
+                    Box::new(request.raised_at.naive_utc()),
+                ],
+            );
+
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "hand",
+                Some(&request.username),
+                request.to_json(),
+            );
+            session.help_queue.requests.push(request);
+            session.send_help_queue();
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct LowerHand {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+}
+
+impl Handler<LowerHand> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LowerHand, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            let request_id = session
+                .help_queue
+                .requests
+                .iter()
+                .find(|request| request.username == msg.username)
+                .map(|request| request.request_id.to_owned());
+
+            if let Some(request_id) = request_id {
+                record_event(
+                    &session.writer,
+                    &msg.identifier,
+                    "lowerHand",
+                    Some(&msg.username),
+                    json!({ "request_id": request_id }),
+                );
+                session.resolve_help_request(&request_id, &msg.username);
+                session.send_help_queue();
+            }
+        }
+    }
+}
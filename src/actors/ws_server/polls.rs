@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use actix::{Handler, Message, Recipient};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{
+    events::record_event, rename::rename_key, SessionIdentifier, SessionRoom, SessionServer,
+    WSResponse,
+};
+
+// Longest question and most options a poll can have
+const MAX_QUESTION_LENGTH: usize = 300;
+const MAX_OPTIONS: usize = 10;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PollKind {
+    Choice,
+    YesNo,
+    Confidence,
+}
+
+impl PollKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PollKind::Choice => "choice",
+            PollKind::YesNo => "yesno",
+            PollKind::Confidence => "confidence",
+        }
+    }
+}
+
+// What the teacher sends to launch a poll
+#[derive(Deserialize, Debug)]
+struct NewPoll {
+    kind: PollKind,
+    question: String,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+struct Poll {
+    poll_id: String,
+    kind: PollKind,
+    question: String,
+    options: Vec<String>,
+    answers: HashMap<String, usize>,
+    closed: bool,
+    revealed: bool,
+}
+
+impl Poll {
+    // The poll as the students see it
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "poll_id": self.poll_id,
+            "kind": self.kind.as_str(),
+            "question": self.question,
+            "options": self.options,
+            "closed": self.closed,
+        })
+    }
+
+    // How many students picked each option
+    fn results_json(&self) -> serde_json::Value {
+        let mut counts = vec![0; self.options.len()];
+        for answer in self.answers.values() {
+            counts[*answer] += 1;
+        }
+
+        json!({
+            "poll_id": self.poll_id,
+            "counts": counts,
+            "total": self.answers.len(),
+        })
+    }
+}
+
+// Every poll launched in the room, in the order they were launched
+pub struct Polls {
+    polls: Vec<Poll>,
+}
+
+impl Polls {
+    pub fn new() -> Self {
+        Self { polls: vec![] }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for poll in self.polls.iter_mut() {
+            rename_key(&mut poll.answers, old, new);
+        }
+    }
+
+    // Send any polls still open to a student that just joined
+    pub fn send_open_polls(&self, addr: &Recipient<WSResponse>) {
+        for poll in self.polls.iter().filter(|poll| !poll.closed) {
+            addr.do_send(WSResponse::Msg(format!("poll {}", poll.to_json())));
+        }
+    }
+
+    fn get(&self, poll_id: &str) -> Option<&Poll> {
+        self.polls.iter().find(|poll| poll.poll_id == poll_id)
+    }
+
+    fn get_mut(&mut self, poll_id: &str) -> Option<&mut Poll> {
+        self.polls.iter_mut().find(|poll| poll.poll_id == poll_id)
+    }
+}
+
+impl SessionRoom {
+    // Push the latest results to the teacher, and the students if they've been revealed
+    fn send_poll_results(&self, poll: &Poll) {
+        let mut teacher_results = poll.results_json();
+        teacher_results["answers"] = json!(poll.answers);
+        self.teacher
+            .addr
+            .do_send(WSResponse::Msg(format!("pollRes {}", teacher_results)));
+
+        if poll.revealed {
+            let response = format!("pollRes {}", poll.results_json());
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+        }
+    }
+
+    // Handle the poll instructions from the teacher
+    pub fn poll_instruction(&mut self, identifier: &SessionIdentifier, instruction: &[&str]) {
+        if instruction[0] == "poll" && instruction.len() > 1 {
+            let new_poll = match serde_json::from_str::<NewPoll>(&instruction[1..].join(" ")) {
+                Ok(new_poll) => new_poll,
+                Err(e) => return eprintln!("{:?}", e),
+            };
+
+            let options = match new_poll.kind {
+                PollKind::Choice => new_poll.options,
+                PollKind::YesNo => vec!["Yes".to_owned(), "No".to_owned()],
+                PollKind::Confidence => vec!["Thumbs up".to_owned(), "Thumbs down".to_owned()],
+            };
+
+            if options.len() < 2
+                || options.len() > MAX_OPTIONS
+                || new_poll.question.chars().count() > MAX_QUESTION_LENGTH
+            {
+                return;
+            }
+
+            let poll = Poll {
+                poll_id: Uuid::new_v4().to_string(),
+                kind: new_poll.kind,
+                question: new_poll.question,
+                options,
+                answers: HashMap::new(),
+                closed: false,
+                revealed: false,
+            };
+
+            let response = format!("poll {}", poll.to_json());
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+            self.teacher.addr.do_send(WSResponse::Msg(response));
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_poll(poll_id, teacher_un, plan_name, session_name, kind, question, options)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ";
+            self.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(poll.poll_id.to_owned()),
+                    Box::new(identifier.host.to_owned()),
+                    Box::new(identifier.plan_name.to_owned()),
+                    Box::new(identifier.session_name.to_owned()),
+                    Box::new(poll.kind.as_str()),
+                    Box::new(poll.question.to_owned()),
+                    Box::new(json!(poll.options)),
+                ],
+            );
+
+            record_event(&self.writer, identifier, "poll", None, poll.to_json());
+            self.polls.polls.push(poll);
+        } else if instruction[0] == "closePoll" && instruction.len() == 2 {
+            if let Some(poll) = self.polls.get_mut(instruction[1]) {
+                poll.closed = true;
+
+                let response = format!("pollClosed {}", json!({ "poll_id": poll.poll_id }));
+                for addr in self.student_addrs() {
+                    addr.do_send(WSResponse::Msg(response.to_owned()));
+                }
+
+                record_event(
+                    &self.writer,
+                    identifier,
+                    "pollClose",
+                    None,
+                    json!({ "poll_id": instruction[1] }),
+                );
+
+                const STATEMENT: &str = "
+                    UPDATE codeharmony.session_poll SET closed_at = current_timestamp WHERE poll_id = $1
+                ";
+                self.writer
+                    .execute(STATEMENT, vec![Box::new(instruction[1].to_owned())]);
+            }
+        } else if instruction[0] == "revealPoll" && instruction.len() == 2 {
+            if let Some(poll) = self.polls.get_mut(instruction[1]) {
+                poll.revealed = true;
+
+                const STATEMENT: &str =
+                    "UPDATE codeharmony.session_poll SET revealed = true WHERE poll_id = $1";
+                self.writer
+                    .execute(STATEMENT, vec![Box::new(instruction[1].to_owned())]);
+            }
+            if let Some(poll) = self.polls.get(instruction[1]) {
+                self.send_poll_results(poll);
+                record_event(
+                    &self.writer,
+                    identifier,
+                    "pollReveal",
+                    None,
+                    poll.results_json(),
+                );
+            }
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct AnswerPoll {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub poll_id: String,
+    pub answer: usize,
+}
+
+impl Handler<AnswerPoll> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AnswerPoll, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if !session.students.contains_key(&msg.username) {
+                return;
+            }
+            session.touch_student(&msg.username);
+
+            // Students can change their answer until the poll closes
+            match session.polls.get_mut(&msg.poll_id) {
+                Some(poll) if !poll.closed && msg.answer < poll.options.len() => {
+                    poll.answers.insert(msg.username.to_owned(), msg.answer);
+                }
+                _ => return,
+            }
+
+            if let Some(poll) = session.polls.get(&msg.poll_id) {
+                session.send_poll_results(poll);
+            }
+
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "pollAnswer",
+                Some(&msg.username),
+                json!({"poll_id": msg.poll_id, "answer": msg.answer}),
+            );
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_poll_answer(poll_id, student_un, answer, answered_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT ON CONSTRAINT session_poll_answer_pk
+                DO UPDATE SET answer = $3, answered_at = $4
+            ";
+            session.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(msg.poll_id),
+                    Box::new(msg.username),
+                    Box::new(msg.answer as i16),
+                    Box::new(Utc::now().naive_utc()),
+                ],
+            );
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use actix::{Handler, Message, Recipient};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    events::record_event, rename::rename_key, SessionIdentifier, SessionRoom, SessionServer,
+    WSResponse,
+};
+
+// How a student says they're getting on
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PulseState {
+    Lost,
+    Good,
+}
+
+impl PulseState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PulseState::Lost => "lost",
+            PulseState::Good => "good",
+        }
+    }
+}
+
+// The latest pulse from each student, kept separately for every section
+pub struct Pulse {
+    sections: HashMap<usize, HashMap<String, PulseState>>,
+}
+
+impl Pulse {
+    pub fn new() -> Self {
+        Self {
+            sections: HashMap::new(),
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for section in self.sections.values_mut() {
+            rename_key(section, old, new);
+        }
+    }
+
+    fn state_of(&self, section: usize, username: &str) -> Option<PulseState> {
+        self.sections
+            .get(&section)
+            .and_then(|pulses| pulses.get(username))
+            .copied()
+    }
+}
+
+impl SessionRoom {
+    // Let the teacher know how the class is getting on with the current section
+    // Only students still in the room are counted
+    pub fn send_pulse(&self) {
+        let (mut lost, mut good) = (0, 0);
+        if let Some(pulses) = self.pulse.sections.get(&self.current_section) {
+            for (username, state) in pulses.iter() {
+                if self.student_addr(username).is_none() {
+                    continue;
+                }
+                match state {
+                    PulseState::Lost => lost += 1,
+                    PulseState::Good => good += 1,
+                }
+            }
+        }
+
+        let response = json!({
+            "section": self.current_section,
+            "lost": lost,
+            "good": good,
+            "connected": self.student_addrs().count(),
+        });
+        self.teacher
+            .addr
+            .do_send(WSResponse::Msg(format!("pulse {}", response)));
+    }
+
+    // Remind a student what they last said about the current section
+    pub fn send_own_pulse(&self, addr: &Recipient<WSResponse>, username: &str) {
+        let response = json!({
+            "section": self.current_section,
+            "state": self.pulse.state_of(self.current_section, username),
+        });
+        addr.do_send(WSResponse::Msg(format!("myPulse {}", response)));
+    }
+
+    // Handle the pulse instructions from the teacher
+    pub fn pulse_instruction(&mut self, instruction: &[&str]) {
+        if instruction[0] == "resetPulse" {
+            self.pulse.sections.remove(&self.current_section);
+
+            let response = format!(
+                "myPulse {}",
+                json!({"section": self.current_section, "state": null})
+            );
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+            self.send_pulse();
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SendPulse {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    // None takes the student's pulse back
+    pub state: Option<PulseState>,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<SendPulse> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendPulse, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if !session.students.contains_key(&msg.username) {
+                return;
+            }
+            session.touch_student(&msg.username);
+
+            let section = session.current_section;
+            let pulses = session.pulse.sections.entry(section).or_default();
+            let previous = match msg.state {
+                Some(state) => pulses.insert(msg.username.to_owned(), state),
+                None => pulses.remove(&msg.username),
+            };
+            if previous == msg.state {
+                return;
+            }
+
+            session.send_pulse();
+            session.send_own_pulse(&msg.addr, &msg.username);
+
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "pulse",
+                Some(&msg.username),
+                json!({"section": section, "state": msg.state}),
+            );
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_pulse(teacher_un, plan_name, session_name, section_index, student_un, state, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT ON CONSTRAINT session_pulse_pk
+                DO UPDATE SET state = $6, updated_at = $7
+            ";
+            session.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(msg.identifier.host),
+                    Box::new(msg.identifier.plan_name),
+                    Box::new(msg.identifier.session_name),
+                    Box::new(section as i32),
+                    Box::new(msg.username),
+                    Box::new(msg.state.map(|state| state.as_str())),
+                    Box::new(Utc::now().naive_utc()),
+                ],
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashSet;
+
+use actix::{Handler, Message, Recipient};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{
+    events::record_event, rename::rename_member, SessionIdentifier, SessionRoom, SessionServer,
+    WSResponse,
+};
+
+// Longest question a student can post, same as a poll question
+const MAX_QUESTION_LENGTH: usize = 300;
+
+// Questions are shown without who asked them, but the asker is kept for safeguarding
+struct Question {
+    question_id: String,
+    username: String,
+    text: String,
+    asked_at: DateTime<Utc>,
+    votes: HashSet<String>,
+    answered: bool,
+}
+
+impl Question {
+    // The question as someone in the room sees it, never saying who asked
+    fn to_json(&self, viewer: &str) -> serde_json::Value {
+        json!({
+            "question_id": self.question_id,
+            "text": self.text,
+            "asked_at": self.asked_at.timestamp_millis(),
+            "votes": self.votes.len(),
+            "answered": self.answered,
+            "mine": self.username == viewer,
+            "voted": self.votes.contains(viewer),
+        })
+    }
+}
+
+// The anonymous question board for the room
+pub struct Questions {
+    questions: Vec<Question>,
+}
+
+impl Questions {
+    pub fn new() -> Self {
+        Self { questions: vec![] }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for question in self.questions.iter_mut() {
+            if question.username == old {
+                question.username = new.to_owned();
+            }
+            rename_member(&mut question.votes, old, new);
+        }
+    }
+
+    fn get(&self, question_id: &str) -> Option<&Question> {
+        self.questions
+            .iter()
+            .find(|question| question.question_id == question_id)
+    }
+
+    fn get_mut(&mut self, question_id: &str) -> Option<&mut Question> {
+        self.questions
+            .iter_mut()
+            .find(|question| question.question_id == question_id)
+    }
+}
+
+fn send_question_error(addr: &Recipient<WSResponse>, reason: &str) {
+    addr.do_send(WSResponse::Msg(format!(
+        "questionErr {}",
+        json!({ "reason": reason })
+    )));
+}
+
+impl SessionRoom {
+    // Send the whole board to someone joining the room
+    pub fn send_questions(&self, addr: &Recipient<WSResponse>, viewer: &str) {
+        let questions = self
+            .questions
+            .questions
+            .iter()
+            .map(|question| question.to_json(viewer))
+            .collect::<Vec<_>>();
+        addr.do_send(WSResponse::Msg(format!("questions {}", json!(questions))));
+    }
+
+    // Push a new or changed question to everyone, each seeing their own votes
+    fn broadcast_question(&self, question: &Question) {
+        self.teacher.addr.do_send(WSResponse::Msg(format!(
+            "question {}",
+            question.to_json(&self.teacher.username)
+        )));
+
+        for (username, student) in self.students.iter() {
+            if student.connected {
+                student.addr.do_send(WSResponse::Msg(format!(
+                    "question {}",
+                    question.to_json(username)
+                )));
+            }
+        }
+    }
+
+    // Handle the question board instructions from the teacher
+    pub fn question_instruction(&mut self, identifier: &SessionIdentifier, instruction: &[&str]) {
+        if instruction[0] == "answerQ" && instruction.len() == 2 {
+            let question_id = instruction[1];
+            match self.questions.get_mut(question_id) {
+                Some(question) if !question.answered => question.answered = true,
+                _ => return,
+            }
+            if let Some(question) = self.questions.get(question_id) {
+                self.broadcast_question(question);
+            }
+
+            record_event(
+                &self.writer,
+                identifier,
+                "questionAnswered",
+                None,
+                json!({ "question_id": question_id }),
+            );
+
+            const STATEMENT: &str = "
+                UPDATE codeharmony.session_question SET answered_at = current_timestamp WHERE question_id = $1
+            ";
+            self.writer
+                .execute(STATEMENT, vec![Box::new(question_id.to_owned())]);
+        } else if instruction[0] == "dismissQ" && instruction.len() == 2 {
+            // Takes a question off the board, but it's kept for safeguarding
+            let question_id = instruction[1];
+            let count = self.questions.questions.len();
+            self.questions
+                .questions
+                .retain(|question| question.question_id != question_id);
+            if self.questions.questions.len() == count {
+                return;
+            }
+
+            let response = format!("questionRemoved {}", json!({ "question_id": question_id }));
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+            self.teacher.addr.do_send(WSResponse::Msg(response));
+
+            record_event(
+                &self.writer,
+                identifier,
+                "questionDismissed",
+                None,
+                json!({ "question_id": question_id }),
+            );
+
+            const STATEMENT: &str = "
+                UPDATE codeharmony.session_question SET dismissed_at = current_timestamp WHERE question_id = $1
+            ";
+            self.writer
+                .execute(STATEMENT, vec![Box::new(question_id.to_owned())]);
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct AskQuestion {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub text: String,
+    pub addr: Recipient<WSResponse>,
+}
+
+impl Handler<AskQuestion> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AskQuestion, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if !session.students.contains_key(&msg.username) {
+                return;
+            }
+            session.touch_student(&msg.username);
+
+            let text = msg.text.trim();
+            if session.chat.is_muted(&msg.username) {
+                return send_question_error(&msg.addr, "You have been muted");
+            }
+            if text.is_empty() || text.chars().count() > MAX_QUESTION_LENGTH {
+                return send_question_error(&msg.addr, "Question must be 1-300 characters");
+            }
+            if !session.chat.within_rate_limit(&msg.username) {
+                return send_question_error(&msg.addr, "Asking questions too quickly");
+            }
+
+            let question = Question {
+                question_id: Uuid::new_v4().to_string(),
+                username: msg.username.to_owned(),
+                text: text.to_owned(),
+                asked_at: Utc::now(),
+                votes: HashSet::new(),
+                answered: false,
+            };
+            session.broadcast_question(&question);
+
+            // The event leaves out who asked, since students can replay the session
+            record_event(
+                &session.writer,
+                &msg.identifier,
+                "question",
+                None,
+                json!({"question_id": question.question_id, "text": question.text}),
+            );
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_question(question_id, teacher_un, plan_name, session_name, student_un, text, asked_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ";
+            session.writer.execute(
+                STATEMENT,
+                vec![
+                    Box::new(question.question_id.to_owned()),
+                    Box::new(msg.identifier.host.to_owned()),
+                    Box::new(msg.identifier.plan_name.to_owned()),
+                    Box::new(msg.identifier.session_name.to_owned()),
+                    Box::new(question.username.to_owned()),
+                    Box::new(question.text.to_owned()),
+                    Box::new(question.asked_at.naive_utc()),
+                ],
+            );
+
+            session.questions.questions.push(question);
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct UpvoteQuestion {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub question_id: String,
+}
+
+impl Handler<UpvoteQuestion> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpvoteQuestion, _: &mut Self::Context) -> Self::Result {
+        if let Some(session) = self.sessions.get_mut(&msg.identifier) {
+            if !session.students.contains_key(&msg.username) {
+                return;
+            }
+            session.touch_student(&msg.username);
+
+            // Students can vote for each question once, but not for their own
+            let voted = match session.questions.get_mut(&msg.question_id) {
+                Some(question) if question.username != msg.username => {
+                    question.votes.insert(msg.username.to_owned())
+                }
+                _ => false,
+            };
+            if !voted {
+                return;
+            }
+            if let Some(question) = session.questions.get(&msg.question_id) {
+                session.broadcast_question(question);
+            }
+
+            const STATEMENT: &str = "
+                INSERT INTO codeharmony.session_question_vote(question_id, student_un)
+                VALUES ($1, $2)
+                ON CONFLICT ON CONSTRAINT session_question_vote_pk DO NOTHING
+            ";
+            session.writer.execute(
+                STATEMENT,
+                vec![Box::new(msg.question_id), Box::new(msg.username)],
+            );
+        }
+    }
+}
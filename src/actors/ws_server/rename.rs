@@ -0,0 +1,102 @@
+use std::collections::{HashMap, HashSet};
+
+use actix::{Context, Handler, Message};
+use serde_json::json;
+
+use super::{SessionIdentifier, SessionRoom, SessionServer, WSResponse};
+
+// Move whatever a map holds for a user over to their new username
+pub fn rename_key<V>(map: &mut HashMap<String, V>, old: &str, new: &str) {
+    if let Some(value) = map.remove(old) {
+        map.insert(new.to_owned(), value);
+    }
+}
+
+pub fn rename_member(set: &mut HashSet<String>, old: &str, new: &str) {
+    if set.remove(old) {
+        set.insert(new.to_owned());
+    }
+}
+
+impl SessionRoom {
+    // Swap the username everywhere the room remembers it, the teacher's own documents included
+    fn rename_user(&mut self, old: &str, new: &str) {
+        rename_key(&mut self.students, old, new);
+        self.chat.rename_user(old, new);
+        self.code_snapshots.rename_user(old, new);
+        self.documents.rename_user(old, new);
+        self.editor_control.rename_user(old, new);
+        self.groups.rename_user(old, new);
+        self.help_queue.rename_user(old, new);
+        self.polls.rename_user(old, new);
+        self.pulse.rename_user(old, new);
+        self.questions.rename_user(old, new);
+        self.timers.rename_user(old, new);
+        self.watch_list.rename_user(old, new);
+        if let Some(spotlight) = &mut self.spotlight {
+            spotlight.rename_user(old, new);
+        }
+    }
+}
+
+// An account has changed its username, so live rooms need to follow it
+// Rooms it's hosting move to an identifier with the new host
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RenameUser {
+    pub old: String,
+    pub new: String,
+}
+
+impl Handler<RenameUser> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenameUser, ctx: &mut Context<Self>) -> Self::Result {
+        let notice = format!("renamed {}", json!({ "old": msg.old, "new": msg.new }));
+
+        let hosted = self
+            .sessions
+            .keys()
+            .filter(|identifier| identifier.host == msg.old)
+            .cloned()
+            .collect::<Vec<_>>();
+        for old_identifier in hosted {
+            let mut room = match self.sessions.remove(&old_identifier) {
+                Some(room) => room,
+                None => continue,
+            };
+            let identifier = SessionIdentifier {
+                host: msg.new.to_owned(),
+                ..old_identifier
+            };
+
+            room.teacher.username = msg.new.to_owned();
+            room.rename_user(&msg.old, &msg.new);
+            room.teacher
+                .addr
+                .do_send(WSResponse::Renamed(msg.new.to_owned()));
+            for addr in std::iter::once(&room.teacher.addr).chain(room.student_addrs()) {
+                addr.do_send(WSResponse::SetConnectedSession(identifier.clone()));
+                addr.do_send(WSResponse::Msg(notice.to_owned()));
+            }
+
+            self.sessions.insert(identifier.clone(), room);
+            self.reschedule_timers(&identifier, ctx);
+        }
+
+        for room in self.sessions.values_mut() {
+            if !room.students.contains_key(&msg.old) {
+                continue;
+            }
+
+            room.rename_user(&msg.old, &msg.new);
+            if let Some(addr) = room.student_addr(&msg.new) {
+                addr.do_send(WSResponse::Renamed(msg.new.to_owned()));
+                addr.do_send(WSResponse::Msg(notice.to_owned()));
+            }
+            room.teacher
+                .addr
+                .do_send(WSResponse::Msg(notice.to_owned()));
+        }
+    }
+}
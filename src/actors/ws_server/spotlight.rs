@@ -0,0 +1,190 @@
+use actix::{Handler, Message, Recipient};
+use serde_json::json;
+
+use super::{SessionIdentifier, SessionRoom, SessionServer, WSResponse};
+
+// What students see instead of the name when a spotlight is anonymous
+const ANONYMOUS_NAME: &str = "A classmate";
+
+enum SpotlightSource {
+    Teacher,
+    Student(String),
+}
+
+// Code being shown read-only to the whole class
+pub struct Spotlight {
+    source: SpotlightSource,
+    anonymous: bool,
+    // Latest full copy of the code so late joiners can catch up
+    code: Option<String>,
+}
+
+impl Spotlight {
+    fn is_student(&self, username: &str) -> bool {
+        matches!(&self.source, SpotlightSource::Student(source) if source == username)
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        if self.is_student(old) {
+            self.source = SpotlightSource::Student(new.to_owned());
+        }
+    }
+}
+
+impl SessionRoom {
+    // Whether a student's code is currently being shown to the class
+    pub fn is_spotlighted(&self, username: &str) -> bool {
+        self.spotlight
+            .as_ref()
+            .is_some_and(|spotlight| spotlight.is_student(username))
+    }
+
+    // Tell a student what's being spotlighted and send them the latest copy of it
+    pub fn send_spotlight(&self, addr: &Recipient<WSResponse>) {
+        if let Some(spotlight) = &self.spotlight {
+            let (source, name) = match &spotlight.source {
+                SpotlightSource::Teacher => ("teacher", self.teacher.username.as_str()),
+                SpotlightSource::Student(_) if spotlight.anonymous => ("student", ANONYMOUS_NAME),
+                SpotlightSource::Student(username) => ("student", username.as_str()),
+            };
+            let response = json!({"source": source, "name": name, "readonly": true});
+            addr.do_send(WSResponse::Msg(format!("spotStart {}", response)));
+
+            if let Some(code) = &spotlight.code {
+                addr.do_send(WSResponse::Msg(format!("spotDoc {}", code)));
+            }
+        }
+    }
+
+    // Stream spotlighted code out to every student except the one it came from
+    // kind is either spotDoc for a full document or spotUpdate for an update
+    fn broadcast_spotlight(&mut self, kind: &str, code: String) {
+        let response = format!("{} {}", kind, code);
+        for (username, student) in self.students.iter() {
+            if student.connected && !self.is_spotlighted(username) {
+                student.addr.do_send(WSResponse::Msg(response.to_owned()));
+            }
+        }
+
+        if let Some(spotlight) = self.spotlight.as_mut() {
+            spotlight.code = Some(code);
+        }
+    }
+
+    // Pass a student's code on to the class if they're in the spotlight
+    // Returns whether the code was wanted
+    pub fn forward_spotlight(&mut self, username: &str, kind: &str, code: &str) -> bool {
+        if self.is_spotlighted(username) {
+            self.broadcast_spotlight(kind, code.to_owned());
+            return true;
+        }
+        false
+    }
+
+    fn end_spotlight(&mut self) {
+        if let Some(spotlight) = self.spotlight.take() {
+            for addr in self.student_addrs() {
+                addr.do_send(WSResponse::Msg("spotEnd".to_owned()));
+            }
+
+            // Stop the student streaming unless the teacher is still watching them
+            if let SpotlightSource::Student(username) = spotlight.source {
+                if !self.is_watched(&username) {
+                    if let Some(addr) = self.student_addr(&username) {
+                        addr.do_send(WSResponse::Msg("unsub".to_owned()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Handle the spotlight instructions from the teacher
+    pub fn spotlight_instruction(&mut self, instruction: &[&str]) {
+        if instruction[0] == "spotlight" && (instruction.len() == 2 || instruction.len() == 3) {
+            let source = if instruction[1] == "teacher" {
+                SpotlightSource::Teacher
+            } else if self.student_addr(instruction[1]).is_some() {
+                SpotlightSource::Student(instruction[1].to_owned())
+            } else {
+                return;
+            };
+
+            // Ended first, or spotlighting the same student again would unsub them after subscribing
+            self.end_spotlight();
+            if let SpotlightSource::Student(username) = &source {
+                // Get the student streaming their code
+                if let Some(addr) = self.student_addr(username) {
+                    addr.do_send(WSResponse::Msg("subscribe".to_owned()));
+                }
+            }
+            self.spotlight = Some(Spotlight {
+                source,
+                anonymous: instruction.get(2) == Some(&"anon"),
+                code: None,
+            });
+
+            for addr in self.student_addrs() {
+                self.send_spotlight(addr);
+            }
+        } else if instruction[0] == "endSpotlight" {
+            self.end_spotlight();
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct UpdateTeacherCode {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub code: String,
+}
+
+impl Handler<UpdateTeacherCode> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateTeacherCode, _: &mut Self::Context) -> Self::Result {
+        self.forward_teacher_code(&msg.identifier, &msg.username, "spotUpdate", msg.code);
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SetTeacherDoc {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub code: String,
+}
+
+impl Handler<SetTeacherDoc> for SessionServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetTeacherDoc, _: &mut Self::Context) -> Self::Result {
+        self.forward_teacher_code(&msg.identifier, &msg.username, "spotDoc", msg.code);
+    }
+}
+
+impl SessionServer {
+    // Pass the teacher's code on to the class if their editor is in the spotlight
+    fn forward_teacher_code(
+        &mut self,
+        identifier: &SessionIdentifier,
+        username: &str,
+        kind: &str,
+        code: String,
+    ) {
+        if let Some(session) = self.sessions.get_mut(identifier) {
+            if session.teacher.username == username
+                && matches!(
+                    session
+                        .spotlight
+                        .as_ref()
+                        .map(|spotlight| &spotlight.source),
+                    Some(SpotlightSource::Teacher)
+                )
+            {
+                session.broadcast_spotlight(kind, code);
+            }
+        }
+    }
+}
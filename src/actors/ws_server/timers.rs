@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use actix::{AsyncContext, Context, Handler, Message, Recipient, SpawnHandle};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::utils::error::CodeHarmonyResponseError;
+
+use super::{rename::rename_key, SessionIdentifier, SessionRoom, SessionServer, WSResponse};
+
+// Longest a timed exercise can run for
+const MAX_TIMER_SECONDS: i64 = 6 * 60 * 60;
+
+// A countdown on a section, after which students can't submit code for it
+struct SectionTimer {
+    deadline: DateTime<Utc>,
+    expired: bool,
+    expiry: SpawnHandle,
+    // Students the teacher has given extra time, and when their time runs out
+    extensions: HashMap<String, DateTime<Utc>>,
+}
+
+// Every timer running in the room, keyed by section name
+pub struct Timers {
+    timers: HashMap<String, SectionTimer>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            timers: HashMap::new(),
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        for timer in self.timers.values_mut() {
+            rename_key(&mut timer.extensions, old, new);
+        }
+    }
+}
+
+impl SessionRoom {
+    // When a user's time on a section runs out, and whether it already has
+    fn deadline_for(&self, username: &str, section_name: &str) -> Option<(DateTime<Utc>, bool)> {
+        let timer = self.timers.timers.get(section_name)?;
+
+        match timer.extensions.get(username) {
+            Some(extension) if *extension > timer.deadline => {
+                Some((*extension, Utc::now() >= *extension))
+            }
+            _ => Some((
+                timer.deadline,
+                timer.expired || Utc::now() >= timer.deadline,
+            )),
+        }
+    }
+
+    // Whether a user can still save or run code for a section
+    pub fn submissions_open(&self, username: &str, section_name: &str) -> bool {
+        match self.deadline_for(username, section_name) {
+            Some((_, expired)) => !expired,
+            None => true,
+        }
+    }
+
+    fn send_timer_to(&self, addr: &Recipient<WSResponse>, username: &str, section_name: &str) {
+        if let Some((deadline, expired)) = self.deadline_for(username, section_name) {
+            let response = json!({
+                "section": section_name,
+                "username": username,
+                "state": if expired { "expired" } else { "running" },
+                "deadline": deadline.timestamp_millis(),
+                "server_time": Utc::now().timestamp_millis(),
+            });
+            addr.do_send(WSResponse::Msg(format!("timer {}", response)));
+        }
+    }
+
+    // Send everyone in the room the deadline for a section
+    fn broadcast_timer(&self, section_name: &str) {
+        self.send_timer_to(&self.teacher.addr, &self.teacher.username, section_name);
+        for (username, student) in self.students.iter() {
+            if student.connected {
+                self.send_timer_to(&student.addr, username, section_name);
+            }
+        }
+    }
+
+    // Send a student that just joined any timers running in the room
+    pub fn send_timers(&self, addr: &Recipient<WSResponse>, username: &str) {
+        for section_name in self.timers.timers.keys() {
+            self.send_timer_to(addr, username, section_name);
+        }
+    }
+}
+
+impl SessionServer {
+    // Start or move the countdown on a section, scheduling when it runs out
+    // Extensions given to students only carry over if the same countdown is being extended
+    fn set_deadline(
+        &mut self,
+        identifier: &SessionIdentifier,
+        section_name: &str,
+        deadline: DateTime<Utc>,
+        keep_extensions: bool,
+        ctx: &mut Context<Self>,
+    ) {
+        let delay = (deadline - Utc::now())
+            .to_std()
+            .unwrap_or(std::time::Duration::ZERO);
+        let expiry = {
+            let identifier = identifier.clone();
+            let section_name = section_name.to_owned();
+            ctx.run_later(delay, move |act, _| {
+                if let Some(session) = act.sessions.get_mut(&identifier) {
+                    if let Some(timer) = session.timers.timers.get_mut(&section_name) {
+                        timer.expired = true;
+                        session.broadcast_timer(&section_name);
+                    }
+                }
+            })
+        };
+
+        if let Some(session) = self.sessions.get_mut(identifier) {
+            let extensions = match session.timers.timers.remove(section_name) {
+                Some(timer) => {
+                    ctx.cancel_future(timer.expiry);
+                    if keep_extensions {
+                        timer.extensions
+                    } else {
+                        HashMap::new()
+                    }
+                }
+                None => HashMap::new(),
+            };
+
+            session.timers.timers.insert(
+                section_name.to_owned(),
+                SectionTimer {
+                    deadline,
+                    expired: false,
+                    expiry,
+                    extensions,
+                },
+            );
+            session.broadcast_timer(section_name);
+        }
+    }
+
+    // Point the countdowns in a room at the identifier it's kept under now, after its host was renamed
+    pub fn reschedule_timers(&mut self, identifier: &SessionIdentifier, ctx: &mut Context<Self>) {
+        let running = match self.sessions.get(identifier) {
+            Some(session) => session
+                .timers
+                .timers
+                .iter()
+                .filter(|(_, timer)| !timer.expired)
+                .map(|(section_name, timer)| (section_name.to_owned(), timer.deadline))
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+
+        for (section_name, deadline) in running {
+            self.set_deadline(identifier, &section_name, deadline, true, ctx);
+        }
+    }
+
+    // Handle the timer instructions from the teacher
+    // Section names can have spaces in them so they always come last
+    pub fn timer_instruction(
+        &mut self,
+        identifier: &SessionIdentifier,
+        instruction: &[&str],
+        ctx: &mut Context<Self>,
+    ) {
+        if (instruction[0] == "timer" || instruction[0] == "extend") && instruction.len() >= 3 {
+            let seconds = match instruction[1].parse::<i64>() {
+                Ok(seconds) if seconds > 0 && seconds <= MAX_TIMER_SECONDS => seconds,
+                _ => return,
+            };
+            let section_name = instruction[2..].join(" ");
+
+            // Extending adds on to whatever time is left, or starts again if it's run out
+            let start = match self
+                .sessions
+                .get(identifier)
+                .and_then(|session| session.timers.timers.get(&section_name))
+            {
+                Some(timer) if instruction[0] == "extend" => timer.deadline.max(Utc::now()),
+                _ => Utc::now(),
+            };
+
+            self.set_deadline(
+                identifier,
+                &section_name,
+                start + Duration::seconds(seconds),
+                instruction[0] == "extend",
+                ctx,
+            );
+        } else if instruction[0] == "grant" && instruction.len() >= 4 {
+            let username = instruction[1];
+            let seconds = match instruction[2].parse::<i64>() {
+                Ok(seconds) if seconds > 0 && seconds <= MAX_TIMER_SECONDS => seconds,
+                _ => return,
+            };
+            let section_name = instruction[3..].join(" ");
+
+            if let Some(session) = self.sessions.get_mut(identifier) {
+                if let Some(timer) = session.timers.timers.get_mut(&section_name) {
+                    let start = timer
+                        .extensions
+                        .get(username)
+                        .copied()
+                        .unwrap_or(timer.deadline)
+                        .max(Utc::now());
+                    timer
+                        .extensions
+                        .insert(username.to_owned(), start + Duration::seconds(seconds));
+
+                    session.send_timer_to(&session.teacher.addr, username, &section_name);
+                    if let Some(addr) = session.student_addr(username) {
+                        session.send_timer_to(addr, username, &section_name);
+                    }
+                }
+            }
+        } else if instruction[0] == "stopTimer" && instruction.len() >= 2 {
+            let section_name = instruction[1..].join(" ");
+
+            if let Some(session) = self.sessions.get_mut(identifier) {
+                if let Some(timer) = session.timers.timers.remove(&section_name) {
+                    ctx.cancel_future(timer.expiry);
+
+                    let response = format!(
+                        "timer {}",
+                        json!({"section": section_name, "state": "stopped"})
+                    );
+                    for addr in session.student_addrs() {
+                        addr.do_send(WSResponse::Msg(response.to_owned()));
+                    }
+                    session.teacher.addr.do_send(WSResponse::Msg(response));
+                }
+            }
+        }
+    }
+}
+
+// Asked before saving or running code, so nothing gets in after time is up
+// or while the teacher has the student's editor locked
+#[derive(Message, Debug, Serialize, Deserialize)]
+#[rtype(result = "Result<(), CodeHarmonyResponseError>")]
+pub struct CheckSubmissionOpen {
+    pub identifier: SessionIdentifier,
+    pub username: String,
+    pub section_name: String,
+}
+
+impl Handler<CheckSubmissionOpen> for SessionServer {
+    type Result = Result<(), CodeHarmonyResponseError>;
+
+    fn handle(&mut self, msg: CheckSubmissionOpen, _: &mut Self::Context) -> Self::Result {
+        match self.sessions.get(&msg.identifier) {
+            Some(session) if session.is_locked(&msg.username) => {
+                Err(CodeHarmonyResponseError::EditorLocked)
+            }
+            Some(session) if !session.submissions_open(&msg.username, &msg.section_name) => {
+                Err(CodeHarmonyResponseError::SubmissionsClosed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    time::Duration,
+};
+
+use serde_json::json;
+
+use super::{
+    rename::{rename_key, rename_member},
+    SessionRoom, WSResponse,
+};
+
+// How many students a teacher can watch at once unless MAX_WATCHED_STUDENTS is set
+const DEFAULT_MAX_WATCHED: usize = 12;
+
+// How often grid snapshots are sent unless WATCH_SNAPSHOT_INTERVAL_MS is set
+const DEFAULT_SNAPSHOT_INTERVAL_MS: u64 = 2000;
+
+// Limits on how much code the teacher is sent
+pub struct WatchConfig {
+    pub max_watched: usize,
+    pub snapshot_interval: Duration,
+}
+
+impl WatchConfig {
+    pub fn from_env() -> Self {
+        let max_watched = env::var("MAX_WATCHED_STUDENTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_WATCHED);
+        let snapshot_interval = env::var("WATCH_SNAPSHOT_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_MS);
+
+        Self {
+            max_watched,
+            snapshot_interval: Duration::from_millis(snapshot_interval),
+        }
+    }
+}
+
+// The students whose code the teacher is watching
+// Every watched student appears in the grid, the focused one gets every update
+pub struct WatchList {
+    watched: HashSet<String>,
+    focused: Option<String>,
+    // Latest code from grid students waiting for the next snapshot
+    pending: HashMap<String, String>,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Self {
+            watched: HashSet::new(),
+            focused: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        rename_member(&mut self.watched, old, new);
+        rename_key(&mut self.pending, old, new);
+        if self.focused.as_deref() == Some(old) {
+            self.focused = Some(new.to_owned());
+        }
+    }
+}
+
+impl SessionRoom {
+    pub fn is_watched(&self, username: &str) -> bool {
+        self.watch_list.watched.contains(username)
+    }
+
+    pub fn is_focused(&self, username: &str) -> bool {
+        self.watch_list.focused.as_deref() == Some(username)
+    }
+
+    // Whether the student should be streaming their code at all
+    pub fn wants_code_from(&self, username: &str) -> bool {
+        self.is_watched(username) || self.is_spotlighted(username)
+    }
+
+    // Start streaming a student's code to the teacher's grid
+    fn watch(&mut self, username: &str, max_watched: usize) -> bool {
+        if self.is_watched(username) {
+            return true;
+        }
+
+        if self.watch_list.watched.len() >= max_watched {
+            let response = json!({ "reason": "Watching too many students", "max": max_watched });
+            self.teacher
+                .addr
+                .do_send(WSResponse::Msg(format!("watchErr {}", response)));
+            return false;
+        }
+
+        if let Some(addr) = self.student_addr(username).cloned() {
+            self.watch_list.watched.insert(username.to_owned());
+            addr.do_send(WSResponse::Msg("subscribe".to_owned()));
+            return true;
+        }
+        false
+    }
+
+    // Stop streaming a student's code, unless the class still needs it
+    fn unwatch(&mut self, username: &str) {
+        self.watch_list.watched.remove(username);
+        self.watch_list.pending.remove(username);
+        if self.is_focused(username) {
+            self.watch_list.focused = None;
+        }
+
+        if !self.is_spotlighted(username) {
+            if let Some(addr) = self.student_addr(username) {
+                addr.do_send(WSResponse::Msg("unsub".to_owned()));
+            }
+        }
+    }
+
+    // Get every update from a student
+    pub fn focus(&mut self, username: &str, max_watched: usize) {
+        let already_watched = self.is_watched(username);
+
+        if self.watch(username, max_watched) {
+            self.watch_list.focused = Some(username.to_owned());
+
+            // Ask a student already in the grid for a full document straight away
+            if already_watched {
+                if let Some(addr) = self.student_addr(username) {
+                    addr.do_send(WSResponse::Msg("subscribe".to_owned()));
+                }
+            }
+        }
+    }
+
+    // Keep the latest code from a grid student for the next snapshot
+    pub fn queue_snapshot(&mut self, username: &str, code: &str) {
+        self.watch_list
+            .pending
+            .insert(username.to_owned(), code.to_owned());
+    }
+
+    // Send the teacher the latest code from any grid students that have changed
+    pub fn flush_snapshots(&mut self) {
+        for (username, code) in self.watch_list.pending.drain() {
+            let response = json!({"username": username, "code": code});
+            self.teacher
+                .addr
+                .do_send(WSResponse::Msg(format!("sSnap {}", response)));
+        }
+    }
+
+    // Handle the watching instructions from the teacher
+    pub fn watch_instruction(&mut self, instruction: &[&str], max_watched: usize) {
+        if instruction[0] == "subscribe" && instruction.len() == 2 {
+            // Subscribing swaps the focused student for a new one
+            if let Some(focused) = self.watch_list.focused.clone() {
+                if focused != instruction[1] {
+                    self.unwatch(&focused);
+                }
+            }
+            self.focus(instruction[1], max_watched);
+        } else if instruction[0] == "unsub" {
+            if let Some(focused) = self.watch_list.focused.clone() {
+                self.unwatch(&focused);
+            }
+        } else if instruction[0] == "watch" && instruction.len() == 2 {
+            self.watch(instruction[1], max_watched);
+        } else if instruction[0] == "unwatch" && instruction.len() == 2 {
+            self.unwatch(instruction[1]);
+        } else if instruction[0] == "focus" && instruction.len() == 2 {
+            self.focus(instruction[1], max_watched);
+        } else if instruction[0] == "unfocus" {
+            // Drop back to grid snapshots for the focused student
+            self.watch_list.focused = None;
+        }
+    }
+}
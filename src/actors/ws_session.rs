@@ -1,31 +1,51 @@
 use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, StreamHandler};
 use actix_web_actors::ws;
 
-use crate::actors::ws_server::{
-    ControlInstruction, Leave, SendTextMessage, SessionIdentifier, SessionServer, SetStudentDoc,
-    StudentJoin, TeacherJoin, UpdateStudentCode, WSResponse,
+use crate::{
+    actors::{
+        cluster::Cluster,
+        room_connection::{handle_command, RoomConnection},
+        ws_server::{SessionServer, WSResponse},
+    },
+    utils::principal::Role,
 };
 
 pub struct WsClientSession {
-    pub addr: Addr<SessionServer>,
-    pub connected_session: Option<SessionIdentifier>,
-    pub username: String,
+    pub connection: RoomConnection,
+}
+
+impl WsClientSession {
+    pub fn new(
+        addr: Addr<SessionServer>,
+        cluster: Addr<Cluster>,
+        username: String,
+        role: Role,
+        login_session: Option<String>,
+    ) -> Self {
+        Self {
+            connection: RoomConnection::new(addr, cluster, username, role, login_session),
+        }
+    }
+}
+
+impl AsMut<RoomConnection> for WsClientSession {
+    fn as_mut(&mut self) -> &mut RoomConnection {
+        &mut self.connection
+    }
 }
 
 impl Actor for WsClientSession {
     type Context = ws::WebsocketContext<Self>;
 
-    fn started(&mut self, _: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
         println!("New Connection");
+        self.connection
+            .register(ctx.address().recipient::<WSResponse>());
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
-        if let Some(connected_session) = &self.connected_session {
-            self.addr.do_send(Leave {
-                identifier: connected_session.clone(),
-                addr: ctx.address().recipient::<WSResponse>(),
-            });
-        }
+        self.connection
+            .leave(ctx.address().recipient::<WSResponse>());
 
         println!("Disconnection");
     }
@@ -46,68 +66,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsClientSession {
             ws::Message::Text(text) => {
                 let split: Vec<&str> = text.splitn(2, ' ').collect();
                 if split.len() == 2 {
-                    let addr = ctx.address().recipient::<WSResponse>();
-                    let username = self.username.clone();
-
-                    // Handle text commands
-                    if split[0] == "tJoin" {
-                        let split_id: Vec<&str> = split[1].splitn(3, ':').collect();
-
-                        self.addr.do_send(TeacherJoin {
-                            identifier: SessionIdentifier {
-                                plan_name: split_id[0].to_owned(),
-                                session_name: split_id[1].to_owned(),
-                                host: split_id[2].to_owned(),
-                            },
-                            addr,
-                            username,
-                        })
-                    } else if split[0] == "sJoin" {
-                        let split_id: Vec<&str> = split[1].splitn(3, ':').collect();
-                        self.addr.do_send(StudentJoin {
-                            identifier: SessionIdentifier {
-                                plan_name: split_id[0].to_owned(),
-                                session_name: split_id[1].to_owned(),
-                                host: split_id[2].to_owned(),
-                            },
-                            addr,
-                            username,
-                        })
-                    } else if split[0] == "tInst" {
-                        println!("{:?}", self.connected_session);
-                        if let Some(identifier) = self.connected_session.as_ref() {
-                            self.addr.do_send(ControlInstruction {
-                                instruction: split[1].to_owned(),
-                                identifier: identifier.clone(),
-                            });
-                        }
-                    } else if split[0] == "sUpdate" {
-                        if let Some(identifier) = self.connected_session.as_ref() {
-                            self.addr.do_send(UpdateStudentCode {
-                                identifier: identifier.clone(),
-                                username,
-                                code: split[1].to_owned(),
-                                student_addr: addr,
-                            });
-                        }
-                    } else if split[0] == "sDoc" {
-                        if let Some(identifier) = self.connected_session.as_ref() {
-                            self.addr.do_send(SetStudentDoc {
-                                identifier: identifier.clone(),
-                                username,
-                                code: split[1].to_owned(),
-                                student_addr: addr,
-                            });
-                        }
-                    } else if split[0] == "txtm" {
-                        if let Some(identifier) = self.connected_session.as_ref() {
-                            self.addr.do_send(SendTextMessage {
-                                identifier: identifier.clone(),
-                                username,
-                                text: split[1].to_owned(),
-                            });
-                        }
-                    }
+                    handle_command(self, split[0], split[1], ctx);
                 }
             }
             ws::Message::Close(reason) => {
@@ -130,9 +89,14 @@ impl Handler<WSResponse> for WsClientSession {
             WSResponse::Msg(message) => ctx.text(message),
             WSResponse::SetConnectedSession(identifier) => {
                 println!("SETTING CONNECTED SESSION");
-                self.connected_session = Some(identifier)
+                self.connection.connected_session = Some(identifier)
+            }
+            WSResponse::Renamed(username) => self.connection.username = username,
+            // Stop too, rather than trusting the client to close its end
+            WSResponse::Close => {
+                ctx.close(None);
+                ctx.stop();
             }
-            WSResponse::Close => ctx.close(None),
         }
     }
 }
@@ -1,7 +1,8 @@
 use std::convert::TryFrom;
 
+use actix::Addr;
 use actix_session::Session;
-use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
 use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
@@ -10,7 +11,15 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio_postgres::{error::SqlState, Row};
 
-use crate::utils::error::CodeHarmonyResponseError;
+use crate::{
+    actors::cluster::{BroadcastLogOut, Cluster, LoggedOut},
+    utils::{
+        error::CodeHarmonyResponseError,
+        login_throttle::{client_ip, record_failure, record_success, reserve_attempt},
+        principal::{Admin, Principal, Role},
+        session_store::start_session,
+    },
+};
 
 // Group all of the services together into a single init
 pub fn init(cfg: &mut web::ServiceConfig) {
@@ -18,7 +27,8 @@ pub fn init(cfg: &mut web::ServiceConfig) {
         .service(logout)
         .service(register)
         .service(check_logged_in)
-        .service(delete_account);
+        .service(delete_account)
+        .service(set_role);
 }
 
 #[derive(Deserialize, Serialize)]
@@ -31,66 +41,109 @@ struct LoginData {
 struct LoginDBData {
     username: String,
     hash: String,
+    must_change_password: bool,
+    session_epoch: i32,
+}
+
+// A real hash to check against when the username doesn't exist,
+// so a login takes just as long either way and can't be used to find accounts
+const DECOY_HASH: &str =
+    "$argon2id$v=19$m=4096,t=3,p=1$mthVV+FY4YjPzyui4crAUA$0Hp4NgFmf4fLJzrtyitrUYUxL07HDCMax0/9HX9TEps";
+
+fn login_failed() -> CodeHarmonyResponseError {
+    CodeHarmonyResponseError::BadRequest(0, "Incorrect username or password".to_owned())
 }
 
 // Login
+// Failures slow down and then lock out the username and address they came from
 #[post("/account/login")]
 async fn login(
+    req: HttpRequest,
     payload: web::Json<LoginData>,
     db_pool: web::Data<deadpool_postgres::Pool>,
     session: Session,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // No account has a username this long, and it wouldn't fit in the tables below
+    if payload.username.chars().count() > 32 {
+        return Err(login_failed());
+    }
+
     // Get db client
-    let client = db_pool.get().await.map_err(|e| {
+    let mut client = db_pool.get().await.map_err(|e| {
         eprintln!("{:?}", e);
         CodeHarmonyResponseError::DatabaseConnection
     })?;
 
+    let ip = client_ip(&req);
+    let attempt = reserve_attempt(&mut client, &payload.username, &ip).await?;
+
     // Get the username and hash
-    const STATEMENT: &str = "SELECT username, hash FROM codeharmony.users WHERE username=$1";
+    const STATEMENT: &str =
+        "SELECT username, hash, must_change_password, session_epoch FROM codeharmony.users WHERE username=$1";
     let rows: Vec<Row> = client
         .query(STATEMENT, &[&payload.username])
         .await
         .map_err(|e| {
-            eprint!("{:?}", e);
-            CodeHarmonyResponseError::BadRequest(0, "User not found".to_owned())
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
         })?;
-
-    // If the user exists and could be deserialized
-    // Verify the hash and login if it's correct
-    if let Some(Ok(user_data)) = rows.into_iter().map(LoginDBData::try_from).next() {
-        let parsed_hash = PasswordHash::new(&user_data.hash).map_err(|e| {
+    let user_data = rows
+        .into_iter()
+        .map(LoginDBData::try_from)
+        .next()
+        .transpose()
+        .map_err(|e| {
             eprintln!("{:?}", e);
-            CodeHarmonyResponseError::InternalError(1, "Couldn't decode password hash".to_owned())
+            CodeHarmonyResponseError::CouldntParseRows
         })?;
 
-        Argon2::default()
-            .verify_password(payload.password.as_bytes(), &parsed_hash)
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::BadRequest(3, "Incorrect password".to_owned())
-            })?;
-
-        session
-            .insert("username", &user_data.username)
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(5, "Couldn't save session".to_owned())
-            })?;
-
-        Ok(HttpResponse::Ok())
-    } else {
-        Err(CodeHarmonyResponseError::BadRequest(
-            0,
-            "User not found".to_owned(),
-        ))
-    }
+    // Verify the hash, checking the decoy when there's no such user
+    let parsed_hash = PasswordHash::new(
+        user_data
+            .as_ref()
+            .map(|user_data| user_data.hash.as_str())
+            .unwrap_or(DECOY_HASH),
+    )
+    .map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(1, "Couldn't decode password hash".to_owned())
+    })?;
+    let verified = Argon2::default()
+        .verify_password(payload.password.as_bytes(), &parsed_hash)
+        .is_ok();
+
+    let user_data = match user_data {
+        Some(user_data) if verified => user_data,
+        _ => {
+            record_failure(&client, &db_pool, &payload.username, &ip, attempt).await?;
+            return Err(login_failed());
+        }
+    };
+    record_success(&client, &user_data.username, &ip).await?;
+
+    start_session(&session, &req, &user_data.username, user_data.session_epoch)?;
+
+    // Accounts made by a teacher still have their one-time password
+    Ok(HttpResponse::Ok().json(json!({
+        "username": user_data.username,
+        "must_change_password": user_data.must_change_password,
+    })))
 }
 
-// Completes logout
+pub fn hash_password(password: &str) -> Result<String, CodeHarmonyResponseError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| {
+            CodeHarmonyResponseError::InternalError(0, "Couldn't hash password".to_owned())
+        })
+}
+
+// Completes logout, removing the session from the store so its cookie stops working
 #[post("/account/logout")]
 async fn logout(session: Session) -> Result<impl Responder, CodeHarmonyResponseError> {
-    session.remove("username");
+    session.purge();
     Ok(HttpResponse::Ok())
 }
 
@@ -102,8 +155,10 @@ struct RegisterData {
 }
 
 // Register
+// Everyone signs up as a student, an admin has to make them a teacher with set_role
 #[post("/account/register")]
 async fn register(
+    req: HttpRequest,
     payload: web::Json<RegisterData>,
     db_pool: web::Data<deadpool_postgres::Pool>,
     session: Session,
@@ -115,20 +170,20 @@ async fn register(
         .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
     // Generate hash for password
-    let salt = SaltString::generate(&mut OsRng);
-    let password_hash = Argon2::default()
-        .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|_| {
-            CodeHarmonyResponseError::InternalError(0, "Couldn't hash password".to_owned())
-        })?
-        .to_string();
+    let password_hash = hash_password(&payload.password)?;
 
     // Insert user into database
-    const STATEMENT: &str = "INSERT INTO codeharmony.users(username,hash,email) VALUES($1,$2,$3)";
+    const STATEMENT: &str =
+        "INSERT INTO codeharmony.users(username,hash,email,role) VALUES($1,$2,$3,$4)";
     client
         .query(
             STATEMENT,
-            &[&payload.username, &password_hash, &payload.email],
+            &[
+                &payload.username,
+                &password_hash,
+                &payload.email,
+                &Role::Student.as_str(),
+            ],
         )
         .await
         .map_err(|err| match err.as_db_error() {
@@ -142,61 +197,116 @@ async fn register(
         })?;
 
     // Log the user in
-    session.insert("username", &payload.username).map_err(|_| {
+    start_session(&session, &req, &payload.username, 0).map_err(|_| {
         CodeHarmonyResponseError::InternalError(
             2,
             "Couldn't save session, account still created".to_owned(),
         )
     })?;
 
-    // Add the user as their own teacher
-    const TEACHERSTATEMENT: &str =
-        "INSERT INTO codeharmony.student_teacher(teacher_un, student_un) VALUES($1,$2)";
+    Ok(HttpResponse::Ok())
+}
+
+// Add teachers as their own teacher, so they can join their own sessions
+async fn add_self_as_teacher(
+    client: &deadpool_postgres::Object,
+    username: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const TEACHERSTATEMENT: &str = "
+        INSERT INTO codeharmony.student_teacher(teacher_un, student_un) VALUES($1,$1)
+        ON CONFLICT ON CONSTRAINT student_teacher_pk DO NOTHING
+    ";
     client
-        .query(TEACHERSTATEMENT, &[&payload.username, &payload.username])
+        .query(TEACHERSTATEMENT, &[&username])
         .await
         .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-    Ok(HttpResponse::Ok())
+    Ok(())
 }
 
 // Check login
 #[get("/account/check")]
-async fn check_logged_in(session: Session) -> Result<impl Responder, CodeHarmonyResponseError> {
+async fn check_logged_in(principal: Principal) -> Result<impl Responder, CodeHarmonyResponseError> {
     // If the user exists, echo
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        Ok(HttpResponse::Ok().json(json!({ "username": username })))
-    } else {
-        Err(CodeHarmonyResponseError::InternalError(
-            0,
-            "Not logged in".to_owned(),
-        ))
-    }
+    Ok(HttpResponse::Ok().json(json!({
+        "username": principal.username,
+        "role": principal.role,
+    })))
 }
 
 // Delete User
 #[delete("/account")]
 async fn delete_account(
     db_pool: web::Data<deadpool_postgres::Pool>,
-    session: Session,
+    cluster: web::Data<Addr<Cluster>>,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        const STATEMENT: &str = "DELETE FROM codeharmony.users WHERE username = $1";
-        client.query(STATEMENT, &[&username]).await.map_err(|e| {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "DELETE FROM codeharmony.users WHERE username = $1";
+    client.query(STATEMENT, &[&username]).await.map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::DatabaseQueryFailed
+    })?;
+
+    // The account's live connections go with it, wherever they're connected
+    cluster.do_send(BroadcastLogOut {
+        username,
+        logged_out: LoggedOut::Everywhere,
+    });
+
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+struct RoleData {
+    role: Role,
+}
+
+// Change what another account is allowed to do
+#[put("/account/role/{username}")]
+async fn set_role(
+    path: web::Path<String>,
+    payload: web::Json<RoleData>,
+    db_pool: web::Data<deadpool_postgres::Pool>,
+    admin: Admin,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let username = path.into_inner();
+
+    // Stops the last admin locking everyone out
+    if username == admin.username {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "Can't change your own role".to_owned(),
+        ));
+    }
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "UPDATE codeharmony.users SET role = $1 WHERE username = $2";
+    let updated = client
+        .execute(STATEMENT, &[&payload.role.as_str(), &username])
+        .await
+        .map_err(|e| {
             eprintln!("{:?}", e);
             CodeHarmonyResponseError::DatabaseQueryFailed
         })?;
+    if updated == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
 
-        return Ok(HttpResponse::Ok());
+    if payload.role.can_teach() {
+        add_self_as_teacher(&client, &username).await?;
     }
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[cfg(test)]
@@ -205,17 +315,18 @@ mod tests {
 
     use super::*;
 
-    use crate::{create_cookie_session, create_postgres_pool};
+    use crate::{create_postgres_pool, create_session_middleware};
 
     const USERNAME: &str = "username_test";
     const PASSWORD: &str = "password_test";
 
     #[actix_web::test]
     async fn test_register() {
+        let (db_pool, _, _) = create_postgres_pool().await;
         let app = test::init_service(
             App::new()
-                .app_data(create_cookie_session())
-                .app_data(web::Data::new(create_postgres_pool()))
+                .wrap(create_session_middleware(db_pool.clone()))
+                .app_data(web::Data::new(db_pool))
                 .service(register),
         )
         .await;
@@ -247,10 +358,11 @@ mod tests {
 
     #[actix_web::test]
     async fn test_login_logout() {
+        let (db_pool, _, _) = create_postgres_pool().await;
         let app = test::init_service(
             App::new()
-                .app_data(create_cookie_session())
-                .app_data(web::Data::new(create_postgres_pool()))
+                .wrap(create_session_middleware(db_pool.clone()))
+                .app_data(web::Data::new(db_pool))
                 .service(login)
                 .service(logout),
         )
@@ -0,0 +1,183 @@
+use std::convert::TryFrom;
+
+use actix::Addr;
+use actix_session::Session;
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::cluster::{BroadcastLogOut, Cluster, LoggedOut},
+    utils::{
+        error::CodeHarmonyResponseError, principal::Principal, session_store::current_session_id,
+    },
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_sessions)
+        .service(revoke_session)
+        .service(revoke_sessions);
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct SessionRow {
+    session_id: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: NaiveDateTime,
+    last_seen: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct ActiveSession {
+    session_id: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: i64,
+    last_seen: i64,
+    // Whether it's the session asking
+    current: bool,
+}
+
+// Get everywhere the account is logged in, most recently used first
+#[get("/account/sessions")]
+async fn get_sessions(
+    db_pool: web::Data<Pool>,
+    session: Session,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT session_id, user_agent, ip, created_at, last_seen FROM codeharmony.user_session
+        WHERE username = $1 AND expires_at > $2
+        ORDER BY last_seen DESC
+    ";
+    let rows = client
+        .query(STATEMENT, &[&username, &Utc::now().naive_utc()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let current = current_session_id(&session);
+    let sessions = rows
+        .into_iter()
+        .map(SessionRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|row| ActiveSession {
+            current: row.session_id.is_some() && row.session_id == current,
+            session_id: row.session_id,
+            user_agent: row.user_agent,
+            ip: row.ip,
+            created_at: row.created_at.timestamp_millis(),
+            last_seen: row.last_seen.timestamp_millis(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// Log out one session, like a lost phone
+#[delete("/account/sessions/{session_id}")]
+async fn revoke_session(
+    session_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
+    session: Session,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.user_session WHERE username = $1 AND session_id = $2";
+    let deleted = client
+        .execute(STATEMENT, &[&username, &session_id.as_str()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    if deleted == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+
+    // Otherwise the end of this request would save it again
+    if current_session_id(&session).as_deref() == Some(session_id.as_str()) {
+        session.purge();
+    }
+
+    // Close whatever the session still has connected, wherever it's connected
+    cluster.do_send(BroadcastLogOut {
+        username,
+        logged_out: LoggedOut::Session(session_id.into_inner()),
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct RevokeQuery {
+    #[serde(default)]
+    keep_current: bool,
+}
+
+// Log out everywhere, or everywhere else with keep_current
+#[delete("/account/sessions")]
+async fn revoke_sessions(
+    query: web::Query<RevokeQuery>,
+    db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
+    session: Session,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    let keep = if query.keep_current {
+        current_session_id(&session)
+    } else {
+        None
+    };
+
+    const STATEMENT: &str = "
+        DELETE FROM codeharmony.user_session
+        WHERE username = $1 AND session_id IS DISTINCT FROM $2
+    ";
+    client
+        .execute(STATEMENT, &[&username, &keep])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    if keep.is_none() {
+        session.purge();
+    }
+
+    cluster.do_send(BroadcastLogOut {
+        username,
+        logged_out: LoggedOut::SessionsExcept(keep),
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,472 @@
+use std::env;
+
+use actix::Addr;
+use actix_session::Session;
+use actix_web::{post, put, web, HttpResponse, Responder};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use chrono::{Duration, Utc};
+use deadpool_postgres::{Pool, Transaction};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+
+use crate::{
+    actors::cluster::{BroadcastLogOut, BroadcastRename, Cluster, LoggedOut},
+    endpoints::{
+        account_management::hash_password,
+        password_reset::{check_new_password, generate_token, hash_token},
+    },
+    utils::{
+        error::CodeHarmonyResponseError,
+        mailer::{Email, Mailer},
+        principal::Principal,
+        session_store::current_session_id,
+    },
+};
+
+// How long a confirmation link works for unless EMAIL_CHANGE_EXPIRY_MINUTES says otherwise
+const DEFAULT_EMAIL_EXPIRY_MINUTES: i64 = 60;
+
+// Columns that hold a username without a foreign key, so a rename doesn't cascade to them
+const UNLINKED_USERNAME_COLUMNS: &[(&str, &str)] = &[
+    ("session_chat_message", "sender_un"),
+    ("session_chat_message", "recipient_un"),
+    ("session_chat_message", "deleted_by"),
+    ("session_help_request", "student_un"),
+    ("session_help_request", "resolved_by"),
+    ("session_poll_answer", "student_un"),
+    ("session_control_log", "student_un"),
+    ("session_group_member", "student_un"),
+    ("session_event", "username"),
+    ("session_pulse", "student_un"),
+    ("session_question", "student_un"),
+    ("session_question_vote", "student_un"),
+];
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(change_password)
+        .service(request_email_change)
+        .service(confirm_email_change)
+        .service(change_username);
+}
+
+fn database_error(e: tokio_postgres::Error) -> CodeHarmonyResponseError {
+    eprintln!("{:?}", e);
+    CodeHarmonyResponseError::DatabaseQueryFailed
+}
+
+pub fn check_username(username: &str) -> Result<(), &'static str> {
+    if !(3..=32).contains(&username.chars().count()) {
+        return Err("Username must be 3-32 characters");
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err("Username can only contain letters, numbers, _, - and .");
+    }
+    Ok(())
+}
+
+pub fn check_email(email: &str) -> Result<(), &'static str> {
+    if email.chars().count() > 32 || !email.contains('@') {
+        return Err("Email must be a valid address of at most 32 characters");
+    }
+    Ok(())
+}
+
+// Make sure it's really the account owner before changing anything important
+// Locks the account's row until the transaction ends
+async fn verify_password(
+    transaction: &Transaction<'_>,
+    username: &str,
+    password: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const STATEMENT: &str = "SELECT hash FROM codeharmony.users WHERE username = $1 FOR UPDATE";
+    let rows = transaction
+        .query(STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+    let hash = match rows.first() {
+        Some(row) => row.get::<_, String>(0),
+        None => return Err(CodeHarmonyResponseError::NotLoggedIn),
+    };
+
+    let parsed_hash = PasswordHash::new(&hash).map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(1, "Couldn't decode password hash".to_owned())
+    })?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .map_err(|_| CodeHarmonyResponseError::BadRequest(3, "Incorrect password".to_owned()))
+}
+
+// Log the account out everywhere but the session making the change
+async fn delete_other_sessions(
+    transaction: &Transaction<'_>,
+    username: &str,
+    session: &Session,
+) -> Result<(), CodeHarmonyResponseError> {
+    const STATEMENT: &str = "
+        DELETE FROM codeharmony.user_session
+        WHERE username = $1 AND session_id IS DISTINCT FROM $2
+    ";
+    transaction
+        .execute(STATEMENT, &[&username, &current_session_id(session)])
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+// Change password, logging the account out everywhere except here
+#[put("/account/password")]
+async fn change_password(
+    payload: web::Json<PasswordChange>,
+    db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
+    session: Session,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    check_new_password(&payload.new_password)?;
+    let password_hash = hash_password(&payload.new_password)?;
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    verify_password(&transaction, &username, &payload.current_password).await?;
+
+    const USER_STATEMENT: &str = "
+        UPDATE codeharmony.users
+        SET hash = $1, must_change_password = false, session_epoch = session_epoch + 1
+        WHERE username = $2
+        RETURNING session_epoch
+    ";
+    let rows = transaction
+        .query(USER_STATEMENT, &[&password_hash, &username])
+        .await
+        .map_err(database_error)?;
+    let epoch = rows
+        .first()
+        .map(|row| row.get::<_, i32>(0))
+        .ok_or(CodeHarmonyResponseError::NotLoggedIn)?;
+
+    delete_other_sessions(&transaction, &username, &session).await?;
+
+    // Reset links sent for the old password shouldn't outlive it
+    const RESET_STATEMENT: &str =
+        "DELETE FROM codeharmony.password_reset WHERE username = $1 AND used_at IS NULL";
+    transaction
+        .execute(RESET_STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Keep this session on the new epoch so only the others are logged out
+    session.insert("session_epoch", epoch).map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(5, "Couldn't save session".to_owned())
+    })?;
+
+    // Their live connections go too, wherever they're connected
+    cluster.do_send(BroadcastLogOut {
+        username,
+        logged_out: LoggedOut::SessionsExcept(current_session_id(&session)),
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct EmailChange {
+    email: String,
+    password: String,
+}
+
+// Send a link to the new address, which only changes the email once it's followed
+#[post("/account/email")]
+async fn request_email_change(
+    payload: web::Json<EmailChange>,
+    db_pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let new_email = payload.email.trim().to_owned();
+    check_email(&new_email)
+        .map_err(|reason| CodeHarmonyResponseError::BadRequest(1, reason.to_owned()))?;
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    verify_password(&transaction, &username, &payload.password).await?;
+
+    const TAKEN_STATEMENT: &str = "SELECT 1 FROM codeharmony.users WHERE email = $1";
+    let taken = transaction
+        .query(TAKEN_STATEMENT, &[&new_email])
+        .await
+        .map_err(database_error)?;
+    if !taken.is_empty() {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            2,
+            "Email already in use".to_owned(),
+        ));
+    }
+
+    // Only the newest link works
+    const CLEAR_STATEMENT: &str = "DELETE FROM codeharmony.email_change WHERE username = $1";
+    transaction
+        .execute(CLEAR_STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+
+    let expiry_minutes = env::var("EMAIL_CHANGE_EXPIRY_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(DEFAULT_EMAIL_EXPIRY_MINUTES);
+    let token = generate_token();
+
+    const INSERT_STATEMENT: &str = "
+        INSERT INTO codeharmony.email_change(token_hash, username, email, expires_at)
+        VALUES ($1, $2, $3, $4)
+    ";
+    transaction
+        .execute(
+            INSERT_STATEMENT,
+            &[
+                &hash_token(&token),
+                &username,
+                &new_email,
+                &(Utc::now() + Duration::minutes(expiry_minutes)).naive_utc(),
+            ],
+        )
+        .await
+        .map_err(database_error)?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    let confirm_url = env::var("EMAIL_CHANGE_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/confirm-email?token=".to_owned());
+    let email = Email {
+        to: new_email,
+        subject: "Confirm your new CodeHarmony email".to_owned(),
+        body: format!(
+            "Hi {},\n\nUse this link within {} minutes to start using this address \
+            for your account:\n\n{}{}\n\n\
+            If you didn't ask for this, you can ignore this email.",
+            username, expiry_minutes, confirm_url, token
+        ),
+    };
+
+    web::block(move || mailer.send(&email))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|sent| sent)
+        .map_err(|e| {
+            eprintln!("Couldn't send email change confirmation: {}", e);
+            CodeHarmonyResponseError::InternalError(0, "Couldn't send confirmation email".into())
+        })?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct EmailConfirm {
+    token: String,
+}
+
+// Switch to the new email, which works without being logged in since the link may be opened anywhere
+#[post("/account/email/confirm")]
+async fn confirm_email_change(
+    payload: web::Json<EmailConfirm>,
+    db_pool: web::Data<Pool>,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const TOKEN_STATEMENT: &str = "
+        DELETE FROM codeharmony.email_change
+        WHERE token_hash = $1 AND expires_at > current_timestamp
+        RETURNING username, email
+    ";
+    let rows = transaction
+        .query(TOKEN_STATEMENT, &[&hash_token(&payload.token)])
+        .await
+        .map_err(database_error)?;
+    let (username, email) = match rows.first() {
+        Some(row) => (row.get::<_, String>(0), row.get::<_, String>(1)),
+        None => {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Confirmation link is invalid or has expired".to_owned(),
+            ))
+        }
+    };
+
+    // Someone else could have taken the address since the link was sent
+    const USER_STATEMENT: &str = "UPDATE codeharmony.users SET email = $1 WHERE username = $2";
+    transaction
+        .execute(USER_STATEMENT, &[&email, &username])
+        .await
+        .map_err(|e| match e.code() {
+            Some(&SqlState::UNIQUE_VIOLATION) => {
+                CodeHarmonyResponseError::BadRequest(2, "Email already in use".to_owned())
+            }
+            _ => database_error(e),
+        })?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct UsernameChange {
+    username: String,
+    password: String,
+}
+
+// Rename the account, carrying its plans, sessions, links and history over
+// Other sessions are logged out, they'd still be holding the old username
+#[put("/account/username")]
+async fn change_username(
+    payload: web::Json<UsernameChange>,
+    db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
+    session: Session,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let new_username = payload.username.trim().to_owned();
+    check_username(&new_username)
+        .map_err(|reason| CodeHarmonyResponseError::BadRequest(1, reason.to_owned()))?;
+    if new_username == username {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "That's already your username".to_owned(),
+        ));
+    }
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Submissions hear about the rename through more than one key, let them all catch up first
+    transaction
+        .batch_execute("SET CONSTRAINTS ALL DEFERRED")
+        .await
+        .map_err(database_error)?;
+
+    verify_password(&transaction, &username, &payload.password).await?;
+
+    // Everything with a foreign key follows through ON UPDATE CASCADE
+    const USER_STATEMENT: &str = "UPDATE codeharmony.users SET username = $1 WHERE username = $2";
+    transaction
+        .execute(USER_STATEMENT, &[&new_username, &username])
+        .await
+        .map_err(|e| match e.code() {
+            Some(&SqlState::UNIQUE_VIOLATION) => {
+                CodeHarmonyResponseError::BadRequest(2, "Username already taken".to_owned())
+            }
+            _ => database_error(e),
+        })?;
+
+    delete_other_sessions(&transaction, &new_username, &session).await?;
+
+    for (table, column) in UNLINKED_USERNAME_COLUMNS {
+        let statement = format!(
+            "UPDATE codeharmony.{} SET {} = $1 WHERE {} = $2",
+            table, column, column
+        );
+        transaction
+            .execute(statement.as_str(), &[&new_username, &username])
+            .await
+            .map_err(database_error)?;
+    }
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    session.insert("username", &new_username).map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(5, "Couldn't save session".to_owned())
+    })?;
+
+    // Move live rooms over, wherever they're hosted
+    cluster.do_send(BroadcastRename {
+        old: username,
+        new: new_username.to_owned(),
+        keep_session: current_session_id(&session),
+    });
+
+    Ok(HttpResponse::Ok().json(json!({ "username": new_username })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_checked() {
+        assert!(check_username("amy.pond-11").is_ok());
+        assert!(check_username("am").is_err());
+        assert!(check_username(&"a".repeat(33)).is_err());
+        assert!(check_username("amy pond").is_err());
+        assert!(check_email("amy@school.org").is_ok());
+        assert!(check_email("amy").is_err());
+    }
+}
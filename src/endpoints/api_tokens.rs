@@ -0,0 +1,297 @@
+use std::convert::TryFrom;
+
+use actix_web::{delete, get, http::header, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, NaiveDateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+
+use crate::{
+    endpoints::password_reset::{generate_token, hash_token},
+    utils::{
+        audit::audit,
+        error::CodeHarmonyResponseError,
+        login_throttle::client_ip,
+        principal::{Principal, Scope},
+    },
+};
+
+// Put in front of every token so they're easy to spot if one gets pasted somewhere it shouldn't
+const TOKEN_PREFIX: &str = "ch_";
+
+const DEFAULT_EXPIRY_DAYS: i64 = 30;
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+// Most tokens one account can have at once
+const MAX_TOKENS: i64 = 20;
+
+// Tokens can't reach any of these routes, so minting and revoking always needs a logged in session
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_token)
+        .service(get_tokens)
+        .service(revoke_token);
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<i64>,
+}
+
+// Make a token for scripts to use as a bearer token
+// The token itself is only ever shown here
+#[post("/account/tokens")]
+async fn create_token(
+    req: HttpRequest,
+    data: web::Json<NewToken>,
+    db_pool: web::Data<Pool>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let NewToken {
+        name,
+        mut scopes,
+        expires_in_days,
+    } = data.into_inner();
+
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "Token name must be 1-64 characters".to_owned(),
+        ));
+    }
+    scopes.sort_unstable();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            1,
+            "A token needs at least one scope".to_owned(),
+        ));
+    }
+    let expires_in_days = expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if !(1..=MAX_EXPIRY_DAYS).contains(&expires_in_days) {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            2,
+            format!("Tokens must expire within 1-{} days", MAX_EXPIRY_DAYS),
+        ));
+    }
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Holds the account's row so tokens made at the same time are counted one after another
+    const LOCK_STATEMENT: &str = "SELECT 1 FROM codeharmony.users WHERE username = $1 FOR UPDATE";
+    // Tidies away the account's expired tokens while it's here
+    const COUNT_STATEMENT: &str = "
+        WITH expired AS (
+            DELETE FROM codeharmony.api_token WHERE username = $1 AND expires_at < $2
+        )
+        SELECT COUNT(*) FROM codeharmony.api_token WHERE username = $1 AND expires_at >= $2
+    ";
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.api_token
+        (token_id, username, name, token_hash, scopes, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+    ";
+
+    transaction
+        .execute(LOCK_STATEMENT, &[&username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let now = Utc::now().naive_utc();
+    let rows = transaction
+        .query(COUNT_STATEMENT, &[&username, &now])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    if rows.first().map(|row| row.get::<_, i64>(0)).unwrap_or(0) >= MAX_TOKENS {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            3,
+            format!("You can't have more than {} tokens", MAX_TOKENS),
+        ));
+    }
+
+    let token_id = uuid::Uuid::new_v4().to_string();
+    let token = format!("{}{}", TOKEN_PREFIX, generate_token());
+    let scope_names = scopes.iter().map(Scope::as_str).collect::<Vec<_>>();
+    let expires_at = now + Duration::days(expires_in_days);
+    transaction
+        .execute(
+            STATEMENT,
+            &[
+                &token_id,
+                &username,
+                &name,
+                &hash_token(&token),
+                &scope_names,
+                &now,
+                &expires_at,
+            ],
+        )
+        .await
+        .map_err(|err| match err.as_db_error() {
+            Some(db_err) if *db_err.code() == SqlState::UNIQUE_VIOLATION => {
+                CodeHarmonyResponseError::BadRequest(
+                    4,
+                    "You already have a token with this name".to_owned(),
+                )
+            }
+            _ => {
+                eprintln!("{:?}", err);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            }
+        })?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    audit(
+        &db_pool,
+        "api_token_created",
+        Some(&username),
+        Some(&client_ip(&req)),
+        json!({"token_id": token_id, "name": name, "scopes": scope_names}),
+    );
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::CACHE_CONTROL, "no-store"));
+    Ok(response.json(json!({
+        "token_id": token_id,
+        "token": token,
+        "name": name,
+        "scopes": scopes,
+        "expires_at": expires_at.timestamp_millis(),
+    })))
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct TokenRow {
+    token_id: String,
+    name: String,
+    scopes: Vec<String>,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+struct ApiToken {
+    token_id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    created_at: i64,
+    expires_at: i64,
+    last_used_at: Option<i64>,
+}
+
+// Get the account's tokens that haven't expired, without the tokens themselves
+#[get("/account/tokens")]
+async fn get_tokens(
+    db_pool: web::Data<Pool>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT token_id, name, scopes, created_at, expires_at, last_used_at
+        FROM codeharmony.api_token
+        WHERE username = $1 AND expires_at > $2
+        ORDER BY created_at DESC
+    ";
+    let rows = client
+        .query(STATEMENT, &[&username, &Utc::now().naive_utc()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let tokens = rows
+        .into_iter()
+        .map(TokenRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|row| ApiToken {
+            token_id: row.token_id,
+            name: row.name,
+            scopes: row
+                .scopes
+                .iter()
+                .filter_map(|scope| Scope::parse(scope))
+                .collect(),
+            created_at: row.created_at.timestamp_millis(),
+            expires_at: row.expires_at.timestamp_millis(),
+            last_used_at: row
+                .last_used_at
+                .map(|last_used_at| last_used_at.timestamp_millis()),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+// Revoke a token, it stops working straight away
+#[delete("/account/tokens/{token_id}")]
+async fn revoke_token(
+    req: HttpRequest,
+    token_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.api_token WHERE username = $1 AND token_id = $2";
+    let deleted = client
+        .execute(STATEMENT, &[&username, &token_id.as_str()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    if deleted == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+
+    audit(
+        &db_pool,
+        "api_token_revoked",
+        Some(&username),
+        Some(&client_ip(&req)),
+        json!({"token_id": token_id.as_str()}),
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,609 @@
+use std::convert::TryFrom;
+
+use actix::Addr;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::{Object, Pool};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+use uuid::Uuid;
+
+use crate::{
+    actors::teacher_code_manager::{GetCode, RegenerateCode, RevokeCode, TeacherCodeManager},
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Student, Teacher},
+    },
+};
+
+// Longest name a class can have
+const MAX_CLASS_NAME_LENGTH: usize = 64;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_class)
+        .service(get_classes)
+        .service(get_student_classes)
+        .service(rename_class)
+        .service(delete_class)
+        .service(get_class_students)
+        .service(add_class_students)
+        .service(remove_class_student)
+        .service(get_class_code)
+        .service(regenerate_class_code)
+        .service(revoke_class_code)
+        .service(get_session_classes)
+        .service(set_session_classes);
+}
+
+// Make sure a class belongs to the teacher asking about it
+pub async fn check_class_owner(
+    client: &Object,
+    username: &str,
+    class_id: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const STATEMENT: &str =
+        "SELECT 1 FROM codeharmony.teacher_class WHERE class_id = $1 AND teacher_un = $2";
+    let rows = client
+        .query(STATEMENT, &[&class_id, &username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    if rows.is_empty() {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+    Ok(())
+}
+
+fn class_name_error(err: tokio_postgres::Error) -> CodeHarmonyResponseError {
+    match err.as_db_error() {
+        Some(err) if *err.code() == SqlState::UNIQUE_VIOLATION => {
+            CodeHarmonyResponseError::BadRequest(0, "Class already exists under this name".into())
+        }
+        _ => CodeHarmonyResponseError::DatabaseQueryFailed,
+    }
+}
+
+fn check_class_name(name: &str) -> Result<(), CodeHarmonyResponseError> {
+    if name.trim().is_empty() || name.chars().count() > MAX_CLASS_NAME_LENGTH {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            1,
+            "Class names must be 1-64 characters".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ClassRequest {
+    name: String,
+}
+
+// Create a new class for the teacher
+#[post("class/new")]
+async fn create_class(
+    payload: web::Json<ClassRequest>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let name = payload.name.trim();
+    check_class_name(name)?;
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.teacher_class(class_id, teacher_un, class_name) VALUES ($1, $2, $3)
+    ";
+    let class_id = Uuid::new_v4().to_string();
+    client
+        .execute(STATEMENT, &[&class_id, &username, &name])
+        .await
+        .map_err(class_name_error)?;
+
+    Ok(HttpResponse::Ok().json(json!({"class_id": class_id, "class_name": name})))
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct ClassRow {
+    class_id: String,
+    class_name: String,
+    created_at: NaiveDateTime,
+    students: i64,
+}
+
+#[derive(Serialize)]
+struct ClassInfo {
+    class_id: String,
+    class_name: String,
+    created_at: i64,
+    students: i64,
+}
+
+// Get every class the teacher has, with how many students are in each
+#[get("class/list")]
+async fn get_classes(
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT c.class_id, c.class_name, c.created_at,
+        (SELECT count(*) FROM codeharmony.class_member m WHERE m.class_id = c.class_id) AS students
+        FROM codeharmony.teacher_class c
+        WHERE c.teacher_un = $1
+        ORDER BY c.class_name ASC
+    ";
+    let rows = client.query(STATEMENT, &[&username]).await.map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::DatabaseQueryFailed
+    })?;
+
+    let classes = rows
+        .into_iter()
+        .map(ClassRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|class| ClassInfo {
+            class_id: class.class_id,
+            class_name: class.class_name,
+            created_at: class.created_at.timestamp_millis(),
+            students: class.students,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(classes))
+}
+
+#[derive(pg_mapper::TryFromRow, Serialize)]
+struct StudentClass {
+    class_id: String,
+    class_name: String,
+    teacher_un: String,
+}
+
+// Get the classes a student is in
+#[get("account/classes")]
+async fn get_student_classes(
+    db_pool: web::Data<Pool>,
+    Student(Principal { username, .. }): Student,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT c.class_id, c.class_name, c.teacher_un FROM codeharmony.teacher_class c
+        JOIN codeharmony.class_member m ON c.class_id = m.class_id
+        WHERE m.student_un = $1
+        ORDER BY c.teacher_un, c.class_name
+    ";
+    let rows = client.query(STATEMENT, &[&username]).await.map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::DatabaseQueryFailed
+    })?;
+
+    let classes = rows
+        .into_iter()
+        .map(StudentClass::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    Ok(HttpResponse::Ok().json(classes))
+}
+
+#[put("class/{class_id}")]
+async fn rename_class(
+    class_id: web::Path<String>,
+    payload: web::Json<ClassRequest>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let name = payload.name.trim();
+    check_class_name(name)?;
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        UPDATE codeharmony.teacher_class SET class_name = $1 WHERE class_id = $2 AND teacher_un = $3
+    ";
+    let updated = client
+        .execute(STATEMENT, &[&name, &class_id.as_str(), &username])
+        .await
+        .map_err(class_name_error)?;
+
+    if updated == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Delete a class, its code stops working and its sessions open up to all the teacher's students
+#[delete("class/{class_id}")]
+async fn delete_class(
+    class_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.teacher_class WHERE class_id = $1 AND teacher_un = $2";
+    let deleted = client
+        .execute(STATEMENT, &[&class_id.as_str(), &username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    if deleted == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct ClassMemberRow {
+    student_un: String,
+    joined_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct ClassMember {
+    student_un: String,
+    joined_at: i64,
+}
+
+// Get the roster for one of the teacher's classes
+#[get("class/{class_id}/students")]
+async fn get_class_students(
+    class_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    const STATEMENT: &str = "
+        SELECT student_un, joined_at FROM codeharmony.class_member
+        WHERE class_id = $1
+        ORDER BY student_un ASC
+    ";
+    let rows = client
+        .query(STATEMENT, &[&class_id.as_str()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let members = rows
+        .into_iter()
+        .map(ClassMemberRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|member| ClassMember {
+            student_un: member.student_un,
+            joined_at: member.joined_at.timestamp_millis(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(members))
+}
+
+// Put some of the teacher's students into a class
+// Anyone who hasn't added the teacher is left out
+#[post("class/{class_id}/students")]
+async fn add_class_students(
+    class_id: web::Path<String>,
+    payload: web::Json<Vec<String>>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.class_member(class_id, student_un)
+        SELECT $1, student_un FROM codeharmony.student_teacher
+        WHERE teacher_un = $2 AND student_un = ANY($3::VARCHAR[])
+        ON CONFLICT ON CONSTRAINT class_member_pk DO NOTHING
+        RETURNING student_un
+    ";
+    let rows = client
+        .query(
+            STATEMENT,
+            &[&class_id.as_str(), &username, &payload.into_inner()],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let added = rows
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({ "added": added })))
+}
+
+#[delete("class/{class_id}/students/{student_un}")]
+async fn remove_class_student(
+    path: web::Path<(String, String)>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (class_id, student_un) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.class_member WHERE class_id = $1 AND student_un = $2";
+    client
+        .execute(STATEMENT, &[&class_id, &student_un])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Get the join code for a class, students using it are added to the class
+#[get("class/{class_id}/code")]
+async fn get_class_code(
+    class_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    code_manager: web::Data<Addr<TeacherCodeManager>>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    let code = code_manager
+        .send(GetCode {
+            username,
+            class_id: Some(class_id.into_inner()),
+        })
+        .await
+        .map_err(|_| {
+            CodeHarmonyResponseError::InternalError(0, "Couldn't generate code".into())
+        })??;
+    Ok(HttpResponse::Ok().json(code))
+}
+
+#[derive(Deserialize)]
+struct RegenerateCodeRequest {
+    expires_in_hours: Option<i64>,
+    max_uses: Option<i32>,
+    requires_approval: Option<bool>,
+}
+
+#[post("class/{class_id}/code/regenerate")]
+async fn regenerate_class_code(
+    class_id: web::Path<String>,
+    payload: Option<web::Json<RegenerateCodeRequest>>,
+    db_pool: web::Data<Pool>,
+    code_manager: web::Data<Addr<TeacherCodeManager>>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    let (expires_in_hours, max_uses, requires_approval) = payload
+        .map(|payload| {
+            (
+                payload.expires_in_hours,
+                payload.max_uses,
+                payload.requires_approval,
+            )
+        })
+        .unwrap_or_default();
+
+    let code = code_manager
+        .send(RegenerateCode {
+            username,
+            class_id: Some(class_id.into_inner()),
+            expires_in_hours,
+            max_uses,
+            requires_approval,
+        })
+        .await
+        .map_err(|_| {
+            CodeHarmonyResponseError::InternalError(0, "Couldn't generate code".into())
+        })??;
+    Ok(HttpResponse::Ok().json(code))
+}
+
+#[delete("class/{class_id}/code")]
+async fn revoke_class_code(
+    class_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    code_manager: web::Data<Addr<TeacherCodeManager>>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_class_owner(&client, &username, &class_id).await?;
+
+    code_manager
+        .send(RevokeCode {
+            username,
+            class_id: Some(class_id.into_inner()),
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::InternalError(0, "Couldn't revoke code".into()))??;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Get the classes one of the teacher's sessions is aimed at
+// No classes means it's open to all of the teacher's students
+#[get("session/classes/{plan_name}/{session_name}")]
+async fn get_session_classes(
+    path: web::Path<(String, String)>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT class_id FROM codeharmony.lesson_session_class
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+    ";
+    let rows = client
+        .query(STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let classes = rows
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(classes))
+}
+
+// Aim one of the teacher's sessions at some of their classes, replacing any it had before
+#[put("session/classes/{plan_name}/{session_name}")]
+async fn set_session_classes(
+    path: web::Path<(String, String)>,
+    payload: web::Json<Vec<String>>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+    let mut class_ids = payload.into_inner();
+    class_ids.sort();
+    class_ids.dedup();
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const DELETE_STATEMENT: &str = "
+        DELETE FROM codeharmony.lesson_session_class
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+    ";
+    transaction
+        .execute(DELETE_STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    // Only the teacher's own classes can be picked
+    const INSERT_STATEMENT: &str = "
+        INSERT INTO codeharmony.lesson_session_class(teacher_un, plan_name, session_name, class_id)
+        SELECT $1, $2, $3, class_id FROM codeharmony.teacher_class
+        WHERE teacher_un = $1 AND class_id = ANY($4::VARCHAR[])
+    ";
+    let inserted = transaction
+        .execute(
+            INSERT_STATEMENT,
+            &[&username, &plan_name, &session_name, &class_ids],
+        )
+        .await
+        .map_err(|e| match e.as_db_error() {
+            Some(err) if *err.code() == SqlState::FOREIGN_KEY_VIOLATION => {
+                CodeHarmonyResponseError::NotFound
+            }
+            _ => CodeHarmonyResponseError::DatabaseQueryFailed,
+        })?;
+
+    if inserted as usize != class_ids.len() {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "Unknown class".into(),
+        ));
+    }
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
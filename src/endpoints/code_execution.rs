@@ -1,5 +1,12 @@
-use crate::{endpoints::lesson_plan::CodingData, utils::error::CodeHarmonyResponseError};
-use actix_session::Session;
+use crate::{
+    actors::{
+        cluster::Cluster,
+        ws_server::{timers::CheckSubmissionOpen, RecordRunResult, SessionIdentifier},
+    },
+    endpoints::{lesson_plan::CodingData, lesson_session::check_session_access},
+    utils::{error::CodeHarmonyResponseError, principal::Principal},
+};
+use actix::Addr;
 use actix_web::{post, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use std::env;
@@ -56,99 +63,131 @@ pub struct SectionIdentifier {
 async fn execute_code(
     payload: web::Json<RunRequest>,
     db_pool: web::Data<deadpool_postgres::Pool>,
-    session: Session,
+    cluster: web::Data<Addr<Cluster>>,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Setup awc client
-        let piston_host = env::var("PISTON_HOST").unwrap_or_else(|_| "https://emkc.org".into());
-        let piston_path =
-            env::var("PISTON_PATH").unwrap_or_else(|_| "/api/v2/piston/execute".into());
-
-        // Stringify json data
-        let request_data = serde_json::to_string(&payload.piston).map_err(|_| {
-            CodeHarmonyResponseError::BadRequest(0, "Couldn't parse request data".to_owned())
-        })?;
-
-        let client = reqwest::Client::new();
-
-        //  Make request to piston API
-        let response = client
-            .post(format!("{}{}", piston_host, piston_path))
-            .header("content-type", "application/json")
-            .body(request_data)
-            .send()
-            .await
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(1, "Couldn't execute code".to_owned())
-            })?;
-
-        // Parse json body into struct
-        let body = response.json::<PistonResponse>().await.map_err(|e| {
+    let identifier = SessionIdentifier {
+        plan_name: payload.identifier.plan_name.to_owned(),
+        session_name: payload.session_name.to_owned(),
+        host: payload.identifier.host.to_owned(),
+    };
+
+    // Don't run late code if the section is being timed, or code from a locked editor
+    // Asks the room wherever it's hosted, and refuses to run if it can't
+    cluster
+        .send(CheckSubmissionOpen {
+            identifier: identifier.clone(),
+            username: username.to_owned(),
+            section_name: payload.identifier.section_name.to_owned(),
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::WebsocketsUnavailable)??;
+
+    // Get database client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_session_access(
+        &client,
+        &username,
+        &identifier.host,
+        &identifier.plan_name,
+        &identifier.session_name,
+    )
+    .await?;
+
+    // Setup awc client
+    let piston_host = env::var("PISTON_HOST").unwrap_or_else(|_| "https://emkc.org".into());
+    let piston_path = env::var("PISTON_PATH").unwrap_or_else(|_| "/api/v2/piston/execute".into());
+
+    // Stringify json data
+    let request_data = serde_json::to_string(&payload.piston).map_err(|_| {
+        CodeHarmonyResponseError::BadRequest(0, "Couldn't parse request data".to_owned())
+    })?;
+
+    let http_client = reqwest::Client::new();
+
+    //  Make request to piston API
+    let response = http_client
+        .post(format!("{}{}", piston_host, piston_path))
+        .header("content-type", "application/json")
+        .body(request_data)
+        .send()
+        .await
+        .map_err(|e| {
             println!("{:?}", e);
-            CodeHarmonyResponseError::InternalError(1, "Couldn't decode body".to_owned())
+            CodeHarmonyResponseError::InternalError(1, "Couldn't execute code".to_owned())
         })?;
 
-        // Get database client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Query db for requried coding data
-        const STATEMENT:&str =
-            "SELECT coding_data FROM codeharmony.lesson_plan_section lps 
-            LEFT JOIN codeharmony.student_teacher st ON lps.username=st.teacher_un
-            WHERE (student_un = $1 OR username = $1) AND plan_name = $2 AND section_name = $3 AND teacher_un = $4";
-        let rows = client
-            .query(
-                STATEMENT,
-                &[
-                    &username,
-                    &payload.identifier.plan_name,
-                    &payload.identifier.section_name,
-                    &payload.identifier.host,
-                ],
-            )
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseQueryFailed)?;
-
-        // Get the coding_data from the row
-        if let Some(row) = rows.into_iter().next() {
-            if let Ok(json) = row.try_get::<usize, Json<CodingData>>(0) {
-                // Return Accepted with the body if it's correct
-                if body.run.output.trim() == json.0.expectedOutput {
-                    // Save the correct mark on the students code before we finish
-                    const MARK_CORRECT_STATEMENT:&str =
-                    "INSERT INTO codeharmony.code_submission (teacher_un,student_un,plan_name,session_name,section_name,correct)
-                    VALUES ($1,$2,$3,$4,$5,true)
-                    ON CONFLICT ON CONSTRAINT code_submission_pk DO UPDATE SET correct = true";
-
-                    client
-                        .query(
-                            MARK_CORRECT_STATEMENT,
-                            &[
-                                &payload.identifier.host,
-                                &username,
-                                &payload.identifier.plan_name,
-                                &payload.session_name,
-                                &payload.identifier.section_name,
-                            ],
-                        )
-                        .await
-                        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-                    return Ok(HttpResponse::Accepted().json(body));
-                } else {
-                    // Just return Ok with body if it's wrong
-                    return Ok(HttpResponse::Ok().json(body));
-                }
+    // Parse json body into struct
+    let body = response.json::<PistonResponse>().await.map_err(|e| {
+        println!("{:?}", e);
+        CodeHarmonyResponseError::InternalError(1, "Couldn't decode body".to_owned())
+    })?;
+
+    // Query db for requried coding data
+    const STATEMENT:&str =
+        "SELECT coding_data FROM codeharmony.lesson_plan_section lps 
+        LEFT JOIN codeharmony.student_teacher st ON lps.username=st.teacher_un
+        WHERE (student_un = $1 OR username = $1) AND plan_name = $2 AND section_name = $3 AND teacher_un = $4";
+    let rows = client
+        .query(
+            STATEMENT,
+            &[
+                &username,
+                &payload.identifier.plan_name,
+                &payload.identifier.section_name,
+                &payload.identifier.host,
+            ],
+        )
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseQueryFailed)?;
+
+    // Get the coding_data from the row
+    if let Some(row) = rows.into_iter().next() {
+        if let Ok(json) = row.try_get::<usize, Json<CodingData>>(0) {
+            let correct = body.run.output.trim() == json.0.expectedOutput;
+
+            // Let the live session know how the run went
+            cluster.do_send(RecordRunResult {
+                identifier,
+                username: username.to_owned(),
+                section_name: payload.identifier.section_name.to_owned(),
+                correct,
+            });
+
+            // Return Accepted with the body if it's correct
+            if correct {
+                // Save the correct mark on the students code before we finish
+                const MARK_CORRECT_STATEMENT:&str =
+                "INSERT INTO codeharmony.code_submission (teacher_un,student_un,plan_name,session_name,section_name,correct)
+                VALUES ($1,$2,$3,$4,$5,true)
+                ON CONFLICT ON CONSTRAINT code_submission_pk DO UPDATE SET correct = true";
+
+                client
+                    .query(
+                        MARK_CORRECT_STATEMENT,
+                        &[
+                            &payload.identifier.host,
+                            &username,
+                            &payload.identifier.plan_name,
+                            &payload.session_name,
+                            &payload.identifier.section_name,
+                        ],
+                    )
+                    .await
+                    .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+                return Ok(HttpResponse::Accepted().json(body));
+            } else {
+                // Just return Ok with body if it's wrong
+                return Ok(HttpResponse::Ok().json(body));
             }
         }
-
-        // Couldn't find coding data
-        return Err(CodeHarmonyResponseError::NotFound);
     }
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Couldn't find coding data
+    Err(CodeHarmonyResponseError::NotFound)
 }
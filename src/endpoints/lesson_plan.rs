@@ -1,6 +1,8 @@
-use crate::utils::error::CodeHarmonyResponseError;
-use crate::utils::jsx_element::JSXElement;
-use actix_session::Session;
+use crate::utils::{
+    error::CodeHarmonyResponseError,
+    jsx_element::JSXElement,
+    principal::{Principal, Teacher},
+};
 use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder};
 use deadpool_postgres::{Object, Pool};
 use serde::{Deserialize, Serialize};
@@ -23,16 +25,6 @@ struct PlanInfoListItem {
     planName: String,
 }
 
-#[derive(Serialize)]
-struct PlanListResponse {
-    plans: Vec<PlanInfoListItem>,
-}
-
-#[derive(Serialize)]
-struct PlanSectionListResponse {
-    sections: Vec<PlanSection>,
-}
-
 #[derive(Serialize, Deserialize, Debug, pg_mapper::TryFromRow)]
 #[allow(non_snake_case)]
 pub struct CodingData {
@@ -119,103 +111,96 @@ struct NewPlanRequest {
 async fn create_lesson_plan(
     payload: web::Json<NewPlanRequest>,
     db_pool: web::Data<Pool>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let mut client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Start transaction
-        let transaction = client
-            .transaction()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Try to insert the new plan into to the db
-        transaction
-            .query(
-                "INSERT INTO codeharmony.lesson_plan(username,plan_name) VALUES ($1,$2)",
-                &[&username, &payload.planName],
-            )
-            .await
-            .map_err(|err| match err.as_db_error() {
-                Some(err) => match *err.code() {
-                    SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
-                        0,
-                        "Plan already exists under this name".to_string(),
-                    ),
-                    _ => CodeHarmonyResponseError::DatabaseConnection,
-                },
-                None => CodeHarmonyResponseError::DatabaseConnection,
-            })?;
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Try to insert the new plan into to the db
+    transaction
+        .query(
+            "INSERT INTO codeharmony.lesson_plan(username,plan_name) VALUES ($1,$2)",
+            &[&username, &payload.planName],
+        )
+        .await
+        .map_err(|err| match err.as_db_error() {
+            Some(err) => match *err.code() {
+                SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
+                    0,
+                    "Plan already exists under this name".to_string(),
+                ),
+                _ => CodeHarmonyResponseError::DatabaseConnection,
+            },
+            None => CodeHarmonyResponseError::DatabaseConnection,
+        })?;
 
-        // Get the resulting name of the plan
-        let inserted_plan_name: String = transaction
-            .query(
-                "SELECT plan_name FROM codeharmony.lesson_plan WHERE plan_name=$1 and username=$2",
-                &[&payload.planName, &username],
-            )
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?[0]
-            .get::<usize, String>(0);
+    // Get the resulting name of the plan
+    let inserted_plan_name: String = transaction
+        .query(
+            "SELECT plan_name FROM codeharmony.lesson_plan WHERE plan_name=$1 and username=$2",
+            &[&payload.planName, &username],
+        )
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?[0]
+        .get::<usize, String>(0);
 
-        // Commit transaction, everything went well
-        transaction
-            .commit()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Return ok with the new plan name
-        return Ok(HttpResponse::Ok().json(NewPlanResponse {
-            planName: inserted_plan_name,
-            msg: String::new(),
-        }));
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return ok with the new plan name
+    Ok(HttpResponse::Ok().json(NewPlanResponse {
+        planName: inserted_plan_name,
+        msg: String::new(),
+    }))
 }
 
 // Get list of plans and sessions for dashboard
 #[get("/plan/list")]
 async fn get_plan_and_session_list(
     db_pool: web::Data<Pool>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Get the list of plans from db
-        let plan_list: Vec<Row> = client
-            .query(
-                "SELECT plan_name FROM codeharmony.lesson_plan WHERE username=$1",
-                &[&username],
-            )
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Get the list of sessions from db
-        let session_list: Vec<Row> = client
-            .query(
-                "SELECT plan_name,session_name FROM codeharmony.lesson_session WHERE username=$1",
-                &[&username],
-            )
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Return list of plans and sessions
-        return Ok(HttpResponse::Ok().json(json!({
-            "plans":plan_list.iter().map(|x| PlanInfoListItem{planName:x.get(0)}).collect::<Vec<PlanInfoListItem>>(),
-            "sessions":session_list.iter().map(|row| SessionListItem{planName:row.get(0),sessionName:row.get(1)}).collect::<Vec<SessionListItem>>()
-        })));
-    }
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Get the list of plans from db
+    let plan_list: Vec<Row> = client
+        .query(
+            "SELECT plan_name FROM codeharmony.lesson_plan WHERE username=$1",
+            &[&username],
+        )
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Get the list of sessions from db
+    let session_list: Vec<Row> = client
+        .query(
+            "SELECT plan_name,session_name FROM codeharmony.lesson_session WHERE username=$1",
+            &[&username],
+        )
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return list of plans and sessions
+    Ok(HttpResponse::Ok().json(json!({
+        "plans":plan_list.iter().map(|x| PlanInfoListItem{planName:x.get(0)}).collect::<Vec<PlanInfoListItem>>(),
+        "sessions":session_list.iter().map(|row| SessionListItem{planName:row.get(0),sessionName:row.get(1)}).collect::<Vec<SessionListItem>>()
+    })))
 }
 
 // Get all associated information about a plan
@@ -223,26 +208,22 @@ async fn get_plan_and_session_list(
 async fn get_plan_info(
     db_pool: web::Data<Pool>,
     path: web::Path<String>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get plan name from uri
-        let plan_name = path.into_inner();
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Get plan name from uri
+    let plan_name = path.into_inner();
 
-        // Get sections from database
-        let sections = get_plan_info_query(&client, &plan_name, &username).await?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Return list of plans
-        return Ok(HttpResponse::Ok().json(sections));
-    }
+    // Get sections from database
+    let sections = get_plan_info_query(&client, &plan_name, &username).await?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return list of plans
+    Ok(HttpResponse::Ok().json(sections))
 }
 
 // Get a plan for a student, checking that they have the teacher added
@@ -250,47 +231,43 @@ async fn get_plan_info(
 async fn get_plan_info_student(
     db_pool: web::Data<Pool>,
     path: web::Path<(String, String)>,
-    session: Session,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get plan name from uri
-        let (plan_name, teacher_name) = path.into_inner();
+    // Get plan name from uri
+    let (plan_name, teacher_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Get db client
-        let client = db_pool
-            .get()
+    println!("Teacher - {} Student - {}", teacher_name, username);
+
+    // Check that the student has the teacher added
+    // Or that it's the teacher themselves
+    if teacher_name != username {
+        let rows = client
+            .query(
+                "SELECT * FROM codeharmony.student_teacher WHERE student_un=$1 and teacher_un=$2",
+                &[&username, &teacher_name],
+            )
             .await
             .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        println!("Teacher - {} Student - {}", teacher_name, username);
-
-        // Check that the student has the teacher added
-        // Or that it's the teacher themselves
-        if teacher_name != username {
-            let rows = client
-                .query(
-                    "SELECT * FROM codeharmony.student_teacher WHERE student_un=$1 and teacher_un=$2",
-                    &[&username, &teacher_name],
-                )
-                .await
-                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-            if rows.is_empty() {
-                return Err(CodeHarmonyResponseError::BadRequest(
-                    0,
-                    "Not registered to this teacher".to_string(),
-                ));
-            }
+        if rows.is_empty() {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Not registered to this teacher".to_string(),
+            ));
         }
-
-        // Get sections from database
-        let sections = get_plan_info_query(&client, &plan_name, &teacher_name).await?;
-
-        // Return list of plans
-        return Ok(HttpResponse::Ok().json(sections));
     }
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Get sections from database
+    let sections = get_plan_info_query(&client, &plan_name, &teacher_name).await?;
+
+    // Return list of plans
+    Ok(HttpResponse::Ok().json(sections))
 }
 
 // Get sections from the database
@@ -337,42 +314,37 @@ async fn update_plan_section_name(
     db_pool: web::Data<Pool>,
     path: web::Path<String>,
     section_names: web::Json<RenameSectionJSON>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    // Get username
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Get plan name from uri
-        let plan_name = path.into_inner();
-
-        const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_name = $1 WHERE section_name = $2 and plan_name = $3 and username = $4";
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        client
-            .query(
-                STATEMENT,
-                &[
-                    &section_names.new_section_name,
-                    &section_names.old_section_name,
-                    &plan_name,
-                    &username,
-                ],
-            )
-            .await
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(1, "Couldn't update database".to_string())
-            })?;
+    // Get plan name from uri
+    let plan_name = path.into_inner();
 
-        // Return Ok!
-        return Ok(HttpResponse::Ok());
-    }
+    const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_name = $1 WHERE section_name = $2 and plan_name = $3 and username = $4";
+
+    client
+        .query(
+            STATEMENT,
+            &[
+                &section_names.new_section_name,
+                &section_names.old_section_name,
+                &plan_name,
+                &username,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            println!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(1, "Couldn't update database".to_string())
+        })?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return Ok!
+    Ok(HttpResponse::Ok())
 }
 
 // Update the json data for a plan section
@@ -381,65 +353,60 @@ async fn set_plan_section(
     db_pool: web::Data<Pool>,
     path: web::Path<String>,
     section: web::Json<PlanSection>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    // Get username
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get plan name from uri
-        let plan_name = path.into_inner();
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Get the json values from body
-        let section_json = serde_json::to_value(&section.elements).map_err(|_| {
-            CodeHarmonyResponseError::BadRequest(0, "Couldn't deserialise elements".to_string())
-        })?;
+    // Get plan name from uri
+    let plan_name = path.into_inner();
 
-        let coding_data = serde_json::to_value(&section.codingData).map_err(|_| {
-            CodeHarmonyResponseError::BadRequest(1, "Couldn't deserialise coding data".to_string())
-        })?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Check format of values math structs
-        let _section_json_formatted: Vec<JSXElement> = serde_json::from_value(section_json.clone())
-            .map_err(|_| {
-                CodeHarmonyResponseError::BadRequest(2, "Invalid elements format".to_string())
-            })?;
+    // Get the json values from body
+    let section_json = serde_json::to_value(&section.elements).map_err(|_| {
+        CodeHarmonyResponseError::BadRequest(0, "Couldn't deserialise elements".to_string())
+    })?;
 
-        let _coding_data_formatted: CodingData = serde_json::from_value(coding_data.clone())
-            .map_err(|_| {
-                CodeHarmonyResponseError::BadRequest(2, "Invalid coding data format".to_string())
-            })?;
+    let coding_data = serde_json::to_value(&section.codingData).map_err(|_| {
+        CodeHarmonyResponseError::BadRequest(1, "Couldn't deserialise coding data".to_string())
+    })?;
 
-        // Send the update query with the new section json data
-        const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_elements = $1, order_pos = $2, coding_data = $5 WHERE plan_name = $3 and section_name=$4 and username=$6";
+    // Check format of values math structs
+    let _section_json_formatted: Vec<JSXElement> = serde_json::from_value(section_json.clone())
+        .map_err(|_| {
+            CodeHarmonyResponseError::BadRequest(2, "Invalid elements format".to_string())
+        })?;
 
-        client
-            .query(
-                STATEMENT,
-                &[
-                    &section_json,
-                    &section.orderPos,
-                    &plan_name,
-                    &section.name,
-                    &coding_data,
-                    &username,
-                ],
-            )
-            .await
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(4, "Couldn't update database".to_string())
-            })?;
+    let _coding_data_formatted: CodingData =
+        serde_json::from_value(coding_data.clone()).map_err(|_| {
+            CodeHarmonyResponseError::BadRequest(2, "Invalid coding data format".to_string())
+        })?;
 
-        // Return Ok!
-        return Ok(HttpResponse::Ok());
-    }
+    // Send the update query with the new section json data
+    const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_elements = $1, order_pos = $2, coding_data = $5 WHERE plan_name = $3 and section_name=$4 and username=$6";
+
+    client
+        .query(
+            STATEMENT,
+            &[
+                &section_json,
+                &section.orderPos,
+                &plan_name,
+                &section.name,
+                &coding_data,
+                &username,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            println!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(4, "Couldn't update database".to_string())
+        })?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return Ok!
+    Ok(HttpResponse::Ok())
 }
 
 // Perform plan operation
@@ -448,60 +415,54 @@ async fn perform_plan_operation(
     db_pool: web::Data<Pool>,
     req: HttpRequest,
     operation: web::Json<PlanOperation>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    // Get username
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get plan name from uri
-        let plan_name = match req.match_info().get("plan_name") {
-            Some(plan_name) => plan_name,
-            None => {
-                return Err(CodeHarmonyResponseError::BadRequest(
-                    0,
-                    "Expected plan name in uri".to_string(),
-                ))
-            }
-        };
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        if &operation.request == "new-section" {
-            let data: NewSectionData =
-                serde_json::from_value(operation.data.to_owned()).map_err(|_| {
-                    CodeHarmonyResponseError::BadRequest(1, "Invalid operation data".to_string())
-                })?;
-
-            const STATEMENT:&str = "INSERT INTO codeharmony.lesson_plan_section(plan_name,username,order_pos,section_name,section_type) VALUES($1,$2,$3,$4,'LECTURE ')";
-
-            client
-                .query(
-                    STATEMENT,
-                    &[&plan_name, &username, &data.orderPos, &data.sectionName],
-                )
-                .await
-                .map_err(|err| match err.as_db_error() {
-                    Some(err) => match *err.code() {
-                        SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
-                            0,
-                            "Name already in use".to_string(),
-                        ),
-                        SqlState::CHECK_VIOLATION => {
-                            CodeHarmonyResponseError::BadRequest(1, "Too short".to_string())
-                        }
-                        _ => CodeHarmonyResponseError::DatabaseConnection,
-                    },
-                    None => CodeHarmonyResponseError::DatabaseConnection,
-                })?;
+    // Get plan name from uri
+    let plan_name = match req.match_info().get("plan_name") {
+        Some(plan_name) => plan_name,
+        None => {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Expected plan name in uri".to_string(),
+            ))
         }
+    };
 
-        return Ok(HttpResponse::Ok());
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    if &operation.request == "new-section" {
+        let data: NewSectionData =
+            serde_json::from_value(operation.data.to_owned()).map_err(|_| {
+                CodeHarmonyResponseError::BadRequest(1, "Invalid operation data".to_string())
+            })?;
+
+        const STATEMENT:&str = "INSERT INTO codeharmony.lesson_plan_section(plan_name,username,order_pos,section_name,section_type) VALUES($1,$2,$3,$4,'LECTURE ')";
+
+        client
+            .query(
+                STATEMENT,
+                &[&plan_name, &username, &data.orderPos, &data.sectionName],
+            )
+            .await
+            .map_err(|err| match err.as_db_error() {
+                Some(err) => match *err.code() {
+                    SqlState::UNIQUE_VIOLATION => {
+                        CodeHarmonyResponseError::BadRequest(0, "Name already in use".to_string())
+                    }
+                    SqlState::CHECK_VIOLATION => {
+                        CodeHarmonyResponseError::BadRequest(1, "Too short".to_string())
+                    }
+                    _ => CodeHarmonyResponseError::DatabaseConnection,
+                },
+                None => CodeHarmonyResponseError::DatabaseConnection,
+            })?;
     }
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(Deserialize)]
@@ -517,88 +478,79 @@ async fn update_plan_section_type(
     db_pool: web::Data<Pool>,
     req: HttpRequest,
     update_details: web::Json<UpdateTypeJSON>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    // Get username
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Get plan name from uri
-        let plan_name = match req.match_info().get("plan_name") {
-            Some(plan_name) => plan_name,
-            None => {
-                return Err(CodeHarmonyResponseError::BadRequest(
-                    0,
-                    "Expected plan name in uri".to_string(),
-                ))
-            }
-        };
-
-        let new_type = if update_details.new_type.starts_with('L') {
-            "LECTURE "
-        } else {
-            "CODING  "
-        };
-
-        const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_type = $1 WHERE section_name = $2 and plan_name = $3 and username = $4";
-
-        client
-            .query(
-                STATEMENT,
-                &[
-                    &new_type,
-                    &update_details.section_name,
-                    &plan_name,
-                    &username,
-                ],
-            )
-            .await
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(1, "Couldn't update database".to_string())
-            })?;
-
-        // Return Ok!
-        return Ok(HttpResponse::Ok());
-    }
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Get plan name from uri
+    let plan_name = match req.match_info().get("plan_name") {
+        Some(plan_name) => plan_name,
+        None => {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Expected plan name in uri".to_string(),
+            ))
+        }
+    };
+
+    let new_type = if update_details.new_type.starts_with('L') {
+        "LECTURE "
+    } else {
+        "CODING  "
+    };
+
+    const STATEMENT:&str = "UPDATE codeharmony.lesson_plan_section SET section_type = $1 WHERE section_name = $2 and plan_name = $3 and username = $4";
+
+    client
+        .query(
+            STATEMENT,
+            &[
+                &new_type,
+                &update_details.section_name,
+                &plan_name,
+                &username,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            println!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(1, "Couldn't update database".to_string())
+        })?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return Ok!
+    Ok(HttpResponse::Ok())
 }
 
 #[post("/plan/delete/{plan_name}")]
 async fn delete_plan(
     path: web::Path<String>,
     db_pool: web::Data<Pool>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
     // Get plan_name from path
     let plan_name = path.into_inner();
 
-    // Get username
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        const STATEMENT: &str =
-            "DELETE FROM codeharmony.lesson_plan WHERE plan_name=$1 AND username = $2";
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.lesson_plan WHERE plan_name=$1 AND username = $2";
 
-        client
-            .query(STATEMENT, &[&plan_name, &username])
-            .await
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(0, "Couldn't delete plan".to_string())
-            })?;
+    client
+        .query(STATEMENT, &[&plan_name, &username])
+        .await
+        .map_err(|e| {
+            println!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(0, "Couldn't delete plan".to_string())
+        })?;
 
-        // Return Ok!
-        return Ok(HttpResponse::Ok());
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // Return Ok!
+    Ok(HttpResponse::Ok())
 }
@@ -1,7 +1,6 @@
 use std::convert::TryFrom;
 
 use actix::Addr;
-use actix_session::Session;
 use actix_web::{get, post, web, HttpResponse, Responder};
 use chrono::NaiveDateTime;
 use deadpool_postgres::{Object, Pool};
@@ -11,9 +10,15 @@ use serde_json::json;
 use tokio_postgres::error::SqlState;
 
 use crate::{
-    actors::ws_server::{GetStudentData, SessionIdentifier, SessionServer},
+    actors::{
+        cluster::Cluster,
+        ws_server::{GetStudentData, SessionIdentifier},
+    },
     lesson_plan::get_plan_info_query,
-    utils::error::CodeHarmonyResponseError,
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Teacher},
+    },
 };
 
 #[derive(Serialize)]
@@ -34,42 +39,36 @@ pub fn init(cfg: &mut web::ServiceConfig) {
 async fn create_session(
     db_pool: web::Data<Pool>,
     path: web::Path<(String, String)>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
     // Get vars from path
     let (plan_name, session_name) = path.into_inner();
 
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        // Try to insert the new plan into to the db
-        client
-    .query(
-        "INSERT INTO codeharmony.lesson_session(plan_name,session_name,username) VALUES ($1,$2,$3)",
-        &[&plan_name, &session_name,&username],
-    )
-    .await
-    .map_err(|err| match err.as_db_error() {
-        Some(err) => match *err.code() {
-            SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
-                0,
-                "Session already exists under this name".to_string(),
-            ),
-            _ => CodeHarmonyResponseError::DatabaseConnection,
-        },
-        None => CodeHarmonyResponseError::DatabaseConnection,
-    })?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        return Ok(
-            HttpResponse::Ok().json(json!({"plan_name":plan_name,"session_name":session_name}))
-        );
-    }
+    // Try to insert the new plan into to the db
+    client
+.query(
+    "INSERT INTO codeharmony.lesson_session(plan_name,session_name,username) VALUES ($1,$2,$3)",
+    &[&plan_name, &session_name,&username],
+)
+.await
+.map_err(|err| match err.as_db_error() {
+    Some(err) => match *err.code() {
+        SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
+            0,
+            "Session already exists under this name".to_string(),
+        ),
+        _ => CodeHarmonyResponseError::DatabaseConnection,
+    },
+    None => CodeHarmonyResponseError::DatabaseConnection,
+})?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok().json(json!({"plan_name":plan_name,"session_name":session_name})))
 }
 
 // Get info about a specific session
@@ -134,6 +133,61 @@ async fn get_session_info_query(
     })
 }
 
+// Check that a user is allowed to see a session hosted by a teacher
+// Either they are the teacher, or they have the teacher added and are in one of the session's classes
+pub async fn check_session_access(
+    client: &Object,
+    username: &str,
+    host: &str,
+    plan_name: &str,
+    session_name: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    if username == host {
+        return Ok(());
+    }
+
+    let statement = format!(
+        "SELECT 1 FROM codeharmony.lesson_session ls
+        JOIN codeharmony.student_teacher st ON ls.username = st.teacher_un
+        WHERE st.student_un = $1 AND ls.username = $2 AND ls.plan_name = $3 AND ls.session_name = $4
+        AND {}",
+        SESSION_CLASS_FILTER
+    );
+    let rows = client
+        .query(
+            statement.as_str(),
+            &[&username, &host, &plan_name, &session_name],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    if rows.is_empty() {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "Not registered to this session".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Sessions aimed at classes are only open to students in one of those classes
+// Expects the session as ls and the student's username as $1
+const SESSION_CLASS_FILTER: &str = "(
+    NOT EXISTS (
+        SELECT 1 FROM codeharmony.lesson_session_class lsc
+        WHERE lsc.teacher_un = ls.username AND lsc.plan_name = ls.plan_name AND lsc.session_name = ls.session_name
+    ) OR EXISTS (
+        SELECT 1 FROM codeharmony.lesson_session_class lsc
+        JOIN codeharmony.class_member cm ON lsc.class_id = cm.class_id
+        WHERE lsc.teacher_un = ls.username AND lsc.plan_name = ls.plan_name AND lsc.session_name = ls.session_name
+        AND cm.student_un = $1
+    )
+)";
+
 #[derive(pg_mapper::TryFromRow, Serialize)]
 struct ActiveSession {
     session_name: String,
@@ -146,21 +200,27 @@ struct ActiveSession {
 #[get("session/active")]
 async fn get_active_sessions_for_user(
     db_pool: web::Data<Pool>,
-    session: Session,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        const STATEMENT:&str = "SELECT session_name,plan_name,username,session_date FROM codeharmony.lesson_session
-JOIN codeharmony.student_teacher ON codeharmony.lesson_session.username = codeharmony.student_teacher.teacher_un 
-WHERE codeharmony.student_teacher.student_un = $1";
-
-        // Get rows from database
-        let rows = client.query(STATEMENT, &[&username]).await.map_err(|e| {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Teachers always see their own sessions
+    let statement = format!(
+        "SELECT ls.session_name, ls.plan_name, ls.username, ls.session_date FROM codeharmony.lesson_session ls
+        JOIN codeharmony.student_teacher st ON ls.username = st.teacher_un
+        WHERE st.student_un = $1 AND (ls.username = $1 OR {})",
+        SESSION_CLASS_FILTER
+    );
+
+    // Get rows from database
+    let rows = client
+        .query(statement.as_str(), &[&username])
+        .await
+        .map_err(|e| {
             println!("{:?}", e);
             CodeHarmonyResponseError::InternalError(
                 1,
@@ -168,43 +228,39 @@ WHERE codeharmony.student_teacher.student_un = $1";
             )
         })?;
 
-        let sessions = rows
-            .into_iter()
-            .map(ActiveSession::try_from)
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| {
-                println!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(0, "Invalid rows".to_string())
-            })?;
-
-        // Return Ok!
-        return Ok(HttpResponse::Ok().json(sessions));
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    let sessions = rows
+        .into_iter()
+        .map(ActiveSession::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            println!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(0, "Invalid rows".to_string())
+        })?;
+
+    // Return Ok!
+    Ok(HttpResponse::Ok().json(sessions))
 }
 
 #[get("session/connected/{host}/{plan_name}/{session_name}")]
 async fn get_session_students(
-    session: Session,
-    session_server: web::Data<Addr<SessionServer>>,
+    Teacher(Principal { username, .. }): Teacher,
+    cluster: web::Data<Addr<Cluster>>,
     path: web::Path<(String, String, String)>,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
     let (host, plan_name, session_name) = path.into_inner();
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        let identifier = SessionIdentifier {
-            host,
-            session_name,
-            plan_name,
-        };
-        let usernames = session_server
-            .send(GetStudentData {
-                username,
-                identifier,
-            })
-            .await
-            .map_err(|_| CodeHarmonyResponseError::WebsocketsUnavailable)?;
-
-        return Ok(HttpResponse::Ok().json(usernames));
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    let identifier = SessionIdentifier {
+        host,
+        session_name,
+        plan_name,
+    };
+    // The room might be hosted on another node
+    let usernames = cluster
+        .send(GetStudentData {
+            username,
+            identifier,
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::WebsocketsUnavailable)??;
+
+    Ok(HttpResponse::Ok().json(usernames))
 }
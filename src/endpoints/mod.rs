@@ -1,7 +1,20 @@
 pub mod account_management;
+pub mod account_sessions;
+pub mod account_settings;
+pub mod api_tokens;
+pub mod classes;
 pub mod code_execution;
 pub mod lesson_plan;
 pub mod lesson_session;
+pub mod password_reset;
 pub mod publish_plan;
+pub mod roster;
+pub mod session_chat;
+pub mod session_help;
+pub mod session_poll;
+pub mod session_questions;
+pub mod session_replay;
+pub mod session_stream;
 pub mod student_code;
+pub mod student_import;
 pub mod student_teacher;
@@ -0,0 +1,326 @@
+use std::env;
+
+use actix::Addr;
+use actix_web::{post, web, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use deadpool_postgres::Pool;
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    actors::cluster::{BroadcastLogOut, Cluster, LoggedOut},
+    endpoints::account_management::hash_password,
+    utils::{
+        error::CodeHarmonyResponseError,
+        mailer::{Email, Mailer},
+    },
+};
+
+// How long a reset link works for unless PASSWORD_RESET_EXPIRY_MINUTES says otherwise
+const DEFAULT_RESET_EXPIRY_MINUTES: i64 = 60;
+
+// Stops the reset form being used to flood someone's inbox
+const RESET_COOLDOWN_SECONDS: i32 = 60;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(request_password_reset)
+        .service(confirm_password_reset);
+}
+
+// Only a hash of the token is kept, so the table leaking doesn't let anyone reset passwords
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn check_new_password(password: &str) -> Result<(), CodeHarmonyResponseError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            4,
+            format!(
+                "Password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ResetRequest {
+    // Either the username or the email on the account
+    login: String,
+}
+
+// Email a reset link to the owner of an account
+// Always looks like it worked, so it can't be used to find out who has an account
+#[post("/account/password-reset")]
+async fn request_password_reset(
+    payload: web::Json<ResetRequest>,
+    db_pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+) -> impl Responder {
+    // Done without waiting, or how long the response takes would give away that the account exists
+    // Failures are logged rather than reported, like an unknown account
+    let login = payload.into_inner().login;
+    actix_web::rt::spawn(async move {
+        if let Err(e) = send_reset_link(&db_pool, mailer, &login).await {
+            eprintln!("Couldn't make a reset link: {}", e);
+        }
+    });
+
+    HttpResponse::Ok().finish()
+}
+
+// Make a new reset token for the account and email it a link, unless one was sent very recently
+async fn send_reset_link(
+    db_pool: &Pool,
+    mailer: web::Data<dyn Mailer>,
+    login: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // A username can look like someone else's email, the exact username wins
+    const USER_STATEMENT: &str = "
+        SELECT username, email FROM codeharmony.users WHERE username = $1 OR email = $1
+        ORDER BY username = $1 DESC LIMIT 1
+        FOR UPDATE
+    ";
+    let rows = transaction
+        .query(USER_STATEMENT, &[&login.trim()])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    let (username, email) = match rows.first() {
+        Some(row) => (row.get::<_, String>(0), row.get::<_, String>(1)),
+        None => return Ok(()),
+    };
+
+    const RECENT_STATEMENT: &str = "
+        SELECT 1 FROM codeharmony.password_reset
+        WHERE username = $1 AND created_at > current_timestamp - make_interval(secs => $2)
+    ";
+    let recent = transaction
+        .query(
+            RECENT_STATEMENT,
+            &[&username, &f64::from(RESET_COOLDOWN_SECONDS)],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    if !recent.is_empty() {
+        return Ok(());
+    }
+
+    // Only the newest link works
+    const CLEAR_STATEMENT: &str =
+        "DELETE FROM codeharmony.password_reset WHERE username = $1 AND used_at IS NULL";
+    transaction
+        .execute(CLEAR_STATEMENT, &[&username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let expiry_minutes = env::var("PASSWORD_RESET_EXPIRY_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(DEFAULT_RESET_EXPIRY_MINUTES);
+    let token = generate_token();
+
+    const INSERT_STATEMENT: &str = "
+        INSERT INTO codeharmony.password_reset(token_hash, username, expires_at) VALUES ($1, $2, $3)
+    ";
+    transaction
+        .execute(
+            INSERT_STATEMENT,
+            &[
+                &hash_token(&token),
+                &username,
+                &(Utc::now() + Duration::minutes(expiry_minutes)).naive_utc(),
+            ],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    let reset_url = env::var("PASSWORD_RESET_URL")
+        .unwrap_or_else(|_| "http://localhost:3000/reset-password?token=".to_owned());
+    let email = Email {
+        to: email,
+        subject: "Reset your CodeHarmony password".to_owned(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password for your account. \
+            If it was you, use this link within {} minutes:\n\n{}{}\n\n\
+            If it wasn't, you can ignore this email.",
+            username, expiry_minutes, reset_url, token
+        ),
+    };
+
+    let sent = web::block(move || mailer.send(&email)).await;
+    if let Ok(Err(e)) | Err(e) = sent.map_err(|e| e.to_string()) {
+        eprintln!("Couldn't send reset email: {}", e);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ResetConfirm {
+    token: String,
+    password: String,
+}
+
+// Set a new password with a reset token, logging the account out everywhere
+#[post("/account/password-reset/confirm")]
+async fn confirm_password_reset(
+    payload: web::Json<ResetConfirm>,
+    db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    check_new_password(&payload.password)?;
+    let password_hash = hash_password(&payload.password)?;
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Using the token up straight away means it only ever works once
+    const TOKEN_STATEMENT: &str = "
+        UPDATE codeharmony.password_reset SET used_at = current_timestamp
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > current_timestamp
+        RETURNING username
+    ";
+    let rows = transaction
+        .query(TOKEN_STATEMENT, &[&hash_token(&payload.token)])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    let username = match rows.first() {
+        Some(row) => row.get::<_, String>(0),
+        None => {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                0,
+                "Reset link is invalid or has expired".to_owned(),
+            ))
+        }
+    };
+
+    // Moving the epoch on logs out every existing session
+    const USER_STATEMENT: &str = "
+        UPDATE codeharmony.users
+        SET hash = $1, must_change_password = false, session_epoch = session_epoch + 1
+        WHERE username = $2
+    ";
+    transaction
+        .execute(USER_STATEMENT, &[&password_hash, &username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    // Stolen cookies and API tokens stop working along with the old password
+    const SESSION_STATEMENT: &str = "DELETE FROM codeharmony.user_session WHERE username = $1";
+    transaction
+        .execute(SESSION_STATEMENT, &[&username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    const API_TOKEN_STATEMENT: &str = "DELETE FROM codeharmony.api_token WHERE username = $1";
+    transaction
+        .execute(API_TOKEN_STATEMENT, &[&username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    // Whoever locked the account out by guessing doesn't know the new password
+    const THROTTLE_STATEMENT: &str =
+        "DELETE FROM codeharmony.login_throttle WHERE kind = 'user' AND subject = $1";
+    transaction
+        .execute(THROTTLE_STATEMENT, &[&username])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Anyone still connected with an old login or token is cut off too
+    cluster.do_send(BroadcastLogOut {
+        username,
+        logged_out: LoggedOut::Everywhere,
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_random_and_hashed() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_token());
+
+        let hashed = hash_token(&token);
+        assert_eq!(hashed.len(), 64);
+        assert_ne!(hashed, token);
+        assert_eq!(hashed, hash_token(&token));
+    }
+}
@@ -0,0 +1,352 @@
+use std::convert::TryFrom;
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::{Pool, Transaction};
+use serde::Serialize;
+
+use crate::utils::{
+    error::CodeHarmonyResponseError,
+    principal::{Principal, Student, Teacher},
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_students)
+        .service(remove_student)
+        .service(leave_teacher)
+        .service(get_join_requests)
+        .service(accept_join_request)
+        .service(reject_join_request);
+}
+
+fn database_error(e: tokio_postgres::Error) -> CodeHarmonyResponseError {
+    eprintln!("{:?}", e);
+    CodeHarmonyResponseError::DatabaseQueryFailed
+}
+
+// Unlink a student from a teacher, taking them out of the teacher's classes as well
+// They lose access to the teacher's sessions, but what they've already submitted is kept
+async fn unlink_student(
+    transaction: &Transaction<'_>,
+    teacher_un: &str,
+    student_un: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const CLASS_STATEMENT: &str = "
+        DELETE FROM codeharmony.class_member cm USING codeharmony.teacher_class c
+        WHERE cm.class_id = c.class_id AND c.teacher_un = $1 AND cm.student_un = $2
+    ";
+    transaction
+        .execute(CLASS_STATEMENT, &[&teacher_un, &student_un])
+        .await
+        .map_err(database_error)?;
+
+    const REQUEST_STATEMENT: &str = "
+        DELETE FROM codeharmony.teacher_join_request WHERE teacher_un = $1 AND student_un = $2
+    ";
+    transaction
+        .execute(REQUEST_STATEMENT, &[&teacher_un, &student_un])
+        .await
+        .map_err(database_error)?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.student_teacher WHERE teacher_un = $1 AND student_un = $2";
+    let deleted = transaction
+        .execute(STATEMENT, &[&teacher_un, &student_un])
+        .await
+        .map_err(database_error)?;
+
+    if deleted == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+    Ok(())
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct StudentRow {
+    student_un: String,
+    joined_at: NaiveDateTime,
+    last_active: Option<NaiveDateTime>,
+    class_ids: Vec<String>,
+    class_names: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StudentClass {
+    class_id: String,
+    class_name: String,
+}
+
+#[derive(Serialize)]
+struct RosterStudent {
+    student_un: String,
+    joined_at: i64,
+    last_active: Option<i64>,
+    classes: Vec<StudentClass>,
+}
+
+// Get everyone who has added the teacher, with when they were last seen in one of their sessions
+#[get("account/students")]
+async fn get_students(
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT st.student_un, st.joined_at,
+        (SELECT max(e.occurred_at) FROM codeharmony.session_event e
+            WHERE e.teacher_un = st.teacher_un AND e.username = st.student_un) AS last_active,
+        array(SELECT c.class_id::VARCHAR FROM codeharmony.teacher_class c
+            JOIN codeharmony.class_member cm ON c.class_id = cm.class_id
+            WHERE c.teacher_un = st.teacher_un AND cm.student_un = st.student_un
+            ORDER BY c.class_name) AS class_ids,
+        array(SELECT c.class_name FROM codeharmony.teacher_class c
+            JOIN codeharmony.class_member cm ON c.class_id = cm.class_id
+            WHERE c.teacher_un = st.teacher_un AND cm.student_un = st.student_un
+            ORDER BY c.class_name) AS class_names
+        FROM codeharmony.student_teacher st
+        WHERE st.teacher_un = $1
+        ORDER BY st.student_un ASC
+    ";
+    let rows = client
+        .query(STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+
+    let students = rows
+        .into_iter()
+        .map(StudentRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|student| RosterStudent {
+            student_un: student.student_un,
+            joined_at: student.joined_at.timestamp_millis(),
+            last_active: student.last_active.map(|date| date.timestamp_millis()),
+            classes: student
+                .class_ids
+                .into_iter()
+                .zip(student.class_names)
+                .map(|(class_id, class_name)| StudentClass {
+                    class_id,
+                    class_name,
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(students))
+}
+
+// Take a student off the teacher's roster, so they stop seeing the teacher's sessions
+#[delete("account/students/{student_un}")]
+async fn remove_student(
+    student_un: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    unlink_student(&transaction, &username, &student_un).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Let a student drop one of their teachers
+#[delete("account/teachers/{teacher_un}")]
+async fn leave_teacher(
+    teacher_un: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Student(Principal { username, .. }): Student,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    unlink_student(&transaction, &teacher_un, &username).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct JoinRequestRow {
+    request_id: String,
+    student_un: String,
+    class_id: Option<String>,
+    class_name: Option<String>,
+    requested_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct JoinRequest {
+    request_id: String,
+    student_un: String,
+    class_id: Option<String>,
+    class_name: Option<String>,
+    requested_at: i64,
+}
+
+// Get the students waiting for the teacher to accept them
+#[get("account/students/requests")]
+async fn get_join_requests(
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT r.request_id, r.student_un, r.class_id, c.class_name, r.requested_at
+        FROM codeharmony.teacher_join_request r
+        LEFT JOIN codeharmony.teacher_class c ON r.class_id = c.class_id
+        WHERE r.teacher_un = $1
+        ORDER BY r.requested_at ASC
+    ";
+    let rows = client
+        .query(STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+
+    let requests = rows
+        .into_iter()
+        .map(JoinRequestRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|request| JoinRequest {
+            request_id: request.request_id,
+            student_un: request.student_un,
+            class_id: request.class_id,
+            class_name: request.class_name,
+            requested_at: request.requested_at.timestamp_millis(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(requests))
+}
+
+// Let a waiting student in, putting them in the class their code was for
+#[post("account/students/requests/{request_id}/accept")]
+async fn accept_join_request(
+    request_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const REQUEST_STATEMENT: &str = "
+        DELETE FROM codeharmony.teacher_join_request WHERE request_id = $1 AND teacher_un = $2
+        RETURNING student_un, class_id
+    ";
+    let rows = transaction
+        .query(REQUEST_STATEMENT, &[&request_id.as_str(), &username])
+        .await
+        .map_err(database_error)?;
+    let (student_un, class_id) = match rows.first() {
+        Some(row) => (row.get::<_, String>(0), row.get::<_, Option<String>>(1)),
+        None => return Err(CodeHarmonyResponseError::NotFound),
+    };
+
+    const STUDENT_STATEMENT: &str = "
+        INSERT INTO codeharmony.student_teacher (teacher_un, student_un) VALUES ($1, $2)
+        ON CONFLICT ON CONSTRAINT student_teacher_pk DO NOTHING
+    ";
+    transaction
+        .execute(STUDENT_STATEMENT, &[&username, &student_un])
+        .await
+        .map_err(database_error)?;
+
+    if let Some(class_id) = class_id {
+        const CLASS_STATEMENT: &str = "
+            INSERT INTO codeharmony.class_member (class_id, student_un) VALUES ($1, $2)
+            ON CONFLICT ON CONSTRAINT class_member_pk DO NOTHING
+        ";
+        transaction
+            .execute(CLASS_STATEMENT, &[&class_id, &student_un])
+            .await
+            .map_err(database_error)?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("account/students/requests/{request_id}")]
+async fn reject_join_request(
+    request_id: web::Path<String>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.teacher_join_request WHERE request_id = $1 AND teacher_un = $2";
+    let deleted = client
+        .execute(STATEMENT, &[&request_id.as_str(), &username])
+        .await
+        .map_err(database_error)?;
+
+    if deleted == 0 {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,75 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+
+use crate::{
+    actors::ws_server::{
+        chat::{get_chat_history, ChatMessage},
+        SessionIdentifier,
+    },
+    endpoints::lesson_session::check_session_access,
+    utils::{error::CodeHarmonyResponseError, principal::Principal},
+};
+
+// Most messages that can be requested in one page
+const MAX_PAGE_SIZE: i64 = 100;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_session_chat);
+}
+
+#[derive(Deserialize)]
+struct ChatPageQuery {
+    before: Option<String>,
+    limit: Option<i64>,
+}
+
+// Get a page of chat history for a session
+// Pass the uuid of the oldest message seen as before to get the next page
+#[get("session/chat/{host}/{plan_name}/{session_name}")]
+async fn get_session_chat(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<ChatPageQuery>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (host, plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_session_access(&client, &username, &host, &plan_name, &session_name).await?;
+
+    let identifier = SessionIdentifier {
+        host,
+        plan_name,
+        session_name,
+    };
+    let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut messages = get_chat_history(
+        &db_pool,
+        &identifier,
+        &username,
+        query.before.as_deref(),
+        limit,
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("{:?}", e);
+        CodeHarmonyResponseError::DatabaseQueryFailed
+    })?;
+
+    // Return the page oldest first
+    messages.reverse();
+
+    Ok(HttpResponse::Ok().json(
+        messages
+            .iter()
+            .map(ChatMessage::to_json)
+            .collect::<Vec<_>>(),
+    ))
+}
@@ -0,0 +1,118 @@
+use std::convert::TryFrom;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::utils::{
+    error::CodeHarmonyResponseError,
+    principal::{Principal, Teacher},
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_help_requests);
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct HelpRequestRow {
+    request_id: String,
+    student_un: String,
+    note: String,
+    raised_at: NaiveDateTime,
+    claimed_at: Option<NaiveDateTime>,
+    resolved_at: Option<NaiveDateTime>,
+    resolved_by: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HelpRequestReport {
+    request_id: String,
+    student_un: String,
+    note: String,
+    raised_at: i64,
+    claimed_at: Option<i64>,
+    resolved_at: Option<i64>,
+    resolved_by: Option<String>,
+    wait_seconds: Option<i64>,
+}
+
+impl From<HelpRequestRow> for HelpRequestReport {
+    fn from(row: HelpRequestRow) -> Self {
+        // Time spent waiting is up until the teacher picked it up,
+        // or until it was resolved if nobody claimed it
+        let answered_at = row.claimed_at.or(row.resolved_at);
+
+        HelpRequestReport {
+            wait_seconds: answered_at
+                .map(|answered_at| (answered_at - row.raised_at).num_seconds()),
+            request_id: row.request_id,
+            student_un: row.student_un,
+            note: row.note,
+            raised_at: row.raised_at.timestamp_millis(),
+            claimed_at: row.claimed_at.map(|date| date.timestamp_millis()),
+            resolved_at: row.resolved_at.map(|date| date.timestamp_millis()),
+            resolved_by: row.resolved_by,
+        }
+    }
+}
+
+// Get every help request from one of the teacher's sessions along with wait times
+#[get("session/help/{plan_name}/{session_name}")]
+async fn get_help_requests(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String)>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT request_id, student_un, note, raised_at, claimed_at, resolved_at, resolved_by
+        FROM codeharmony.session_help_request
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+        ORDER BY raised_at ASC
+    ";
+
+    let rows = client
+        .query(STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let requests = rows
+        .into_iter()
+        .map(HelpRequestRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(HelpRequestReport::from)
+        .collect::<Vec<_>>();
+
+    let waits = requests
+        .iter()
+        .filter_map(|request| request.wait_seconds)
+        .collect::<Vec<_>>();
+    let average_wait_seconds = if waits.is_empty() {
+        None
+    } else {
+        Some(waits.iter().sum::<i64>() / waits.len() as i64)
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "requests": requests,
+        "average_wait_seconds": average_wait_seconds,
+        "longest_wait_seconds": waits.iter().max(),
+    })))
+}
@@ -0,0 +1,154 @@
+use std::convert::TryFrom;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::utils::{
+    error::CodeHarmonyResponseError,
+    principal::{Principal, Teacher},
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_session_polls);
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct PollRow {
+    poll_id: String,
+    kind: String,
+    question: String,
+    options: Value,
+    created_at: NaiveDateTime,
+    closed_at: Option<NaiveDateTime>,
+    revealed: bool,
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct PollAnswerRow {
+    poll_id: String,
+    student_un: String,
+    answer: i16,
+}
+
+#[derive(Serialize)]
+struct PollAnswer {
+    student_un: String,
+    answer: i16,
+}
+
+#[derive(Serialize)]
+struct PollReview {
+    poll_id: String,
+    kind: String,
+    question: String,
+    options: Value,
+    created_at: i64,
+    closed_at: Option<i64>,
+    revealed: bool,
+    counts: Vec<usize>,
+    answers: Vec<PollAnswer>,
+}
+
+// Get every poll from one of the teacher's sessions along with the answers
+#[get("session/polls/{plan_name}/{session_name}")]
+async fn get_session_polls(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String)>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const POLL_STATEMENT: &str = "
+        SELECT poll_id, kind, question, options, created_at, closed_at, revealed
+        FROM codeharmony.session_poll
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+        ORDER BY created_at ASC
+    ";
+
+    const ANSWER_STATEMENT: &str = "
+        SELECT a.poll_id, a.student_un, a.answer
+        FROM codeharmony.session_poll_answer a
+        JOIN codeharmony.session_poll p ON a.poll_id = p.poll_id
+        WHERE p.teacher_un = $1 AND p.plan_name = $2 AND p.session_name = $3
+        ORDER BY a.answered_at ASC
+    ";
+
+    let poll_rows = client
+        .query(POLL_STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let answer_rows = client
+        .query(ANSWER_STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let polls = poll_rows
+        .into_iter()
+        .map(PollRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    let answers = answer_rows
+        .into_iter()
+        .map(PollAnswerRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    let review = polls
+        .into_iter()
+        .map(|poll| {
+            let option_count = poll.options.as_array().map_or(0, |options| options.len());
+            let answers = answers
+                .iter()
+                .filter(|answer| answer.poll_id == poll.poll_id)
+                .map(|answer| PollAnswer {
+                    student_un: answer.student_un.to_owned(),
+                    answer: answer.answer,
+                })
+                .collect::<Vec<_>>();
+
+            let mut counts = vec![0; option_count];
+            for answer in answers.iter() {
+                if let Some(count) = counts.get_mut(answer.answer as usize) {
+                    *count += 1;
+                }
+            }
+
+            PollReview {
+                poll_id: poll.poll_id,
+                kind: poll.kind,
+                question: poll.question,
+                options: poll.options,
+                created_at: poll.created_at.timestamp_millis(),
+                closed_at: poll.closed_at.map(|date| date.timestamp_millis()),
+                revealed: poll.revealed,
+                counts,
+                answers,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(review))
+}
@@ -0,0 +1,145 @@
+use std::convert::TryFrom;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+
+use crate::utils::{
+    error::CodeHarmonyResponseError,
+    principal::{Principal, Teacher},
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_session_questions)
+        .service(get_session_pulse);
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct QuestionRow {
+    question_id: String,
+    student_un: String,
+    text: String,
+    asked_at: NaiveDateTime,
+    answered_at: Option<NaiveDateTime>,
+    dismissed_at: Option<NaiveDateTime>,
+    votes: i64,
+}
+
+#[derive(Serialize)]
+struct QuestionReview {
+    question_id: String,
+    student_un: String,
+    text: String,
+    asked_at: i64,
+    answered_at: Option<i64>,
+    dismissed_at: Option<i64>,
+    votes: i64,
+}
+
+// Get every question asked in one of the teacher's sessions, with who asked it
+// Students never see this, it's kept for safeguarding
+#[get("session/questions/{plan_name}/{session_name}")]
+async fn get_session_questions(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String)>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT q.question_id, q.student_un, q.text, q.asked_at, q.answered_at, q.dismissed_at,
+        (SELECT count(*) FROM codeharmony.session_question_vote v WHERE v.question_id = q.question_id) AS votes
+        FROM codeharmony.session_question q
+        WHERE q.teacher_un = $1 AND q.plan_name = $2 AND q.session_name = $3
+        ORDER BY q.asked_at ASC
+    ";
+
+    let rows = client
+        .query(STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let questions = rows
+        .into_iter()
+        .map(QuestionRow::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?
+        .into_iter()
+        .map(|question| QuestionReview {
+            question_id: question.question_id,
+            student_un: question.student_un,
+            text: question.text,
+            asked_at: question.asked_at.timestamp_millis(),
+            answered_at: question.answered_at.map(|date| date.timestamp_millis()),
+            dismissed_at: question.dismissed_at.map(|date| date.timestamp_millis()),
+            votes: question.votes,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(questions))
+}
+
+#[derive(pg_mapper::TryFromRow, Serialize)]
+struct PulseSummary {
+    section_index: i32,
+    lost: i64,
+    good: i64,
+}
+
+// How many students said they were lost or good at the end of each section of a session
+#[get("session/pulse/{plan_name}/{session_name}")]
+async fn get_session_pulse(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String)>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT section_index,
+        count(*) FILTER (WHERE state = 'lost') AS lost,
+        count(*) FILTER (WHERE state = 'good') AS good
+        FROM codeharmony.session_pulse
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+        GROUP BY section_index
+        ORDER BY section_index ASC
+    ";
+
+    let rows = client
+        .query(STATEMENT, &[&username, &plan_name, &session_name])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let summary = rows
+        .into_iter()
+        .map(PulseSummary::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
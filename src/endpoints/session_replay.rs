@@ -0,0 +1,331 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use actix_web::{get, web, web::Bytes, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    endpoints::lesson_session::check_session_access,
+    utils::{
+        error::CodeHarmonyResponseError, operational_transform::Operation, principal::Principal,
+    },
+};
+
+// How many events are read from the database at a time while streaming
+const REPLAY_PAGE_SIZE: i64 = 500;
+
+// Events about the whole room that every student in it saw as it happened
+// Students only get other kinds of event when they're about them
+const PUBLIC_KINDS: &[&str] = &[
+    "join",
+    "leave",
+    "section",
+    "poll",
+    "pollClose",
+    "pollReveal",
+    "question",
+    "questionAnswered",
+    "questionDismissed",
+];
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_session_replay).service(get_code_evolution);
+}
+
+#[derive(pg_mapper::TryFromRow)]
+struct SessionEvent {
+    event_id: i64,
+    kind: String,
+    username: Option<String>,
+    data: serde_json::Value,
+    occurred_at: NaiveDateTime,
+}
+
+impl SessionEvent {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "event_id": self.event_id,
+            "kind": self.kind,
+            "username": self.username,
+            "data": self.data,
+            "at": self.occurred_at.timestamp_millis(),
+        })
+    }
+}
+
+fn from_millis(millis: i64) -> Option<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(
+        millis.div_euclid(1000),
+        (millis.rem_euclid(1000) * 1_000_000) as u32,
+    )
+}
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    from: Option<i64>,
+}
+
+// Where the stream has got up to
+struct ReplayCursor {
+    db_pool: Pool,
+    params: (String, String, String, String),
+    occurred_at: NaiveDateTime,
+    event_id: i64,
+    finished: bool,
+}
+
+// Get the next page of events a user can see after the cursor
+async fn next_replay_page(
+    mut cursor: ReplayCursor,
+) -> Option<(Result<Bytes, CodeHarmonyResponseError>, ReplayCursor)> {
+    if cursor.finished {
+        return None;
+    }
+
+    // Students see what happened to the whole room, room chat, their own events and code,
+    // and messages meant for them. Nobody sees chat the teacher deleted
+    const STATEMENT: &str = "
+        SELECT event_id, kind, username, data, occurred_at FROM codeharmony.session_event e
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+        AND (occurred_at, event_id) > ($5, $6)
+        AND ($4 = teacher_un OR kind = ANY($8) OR username = $4
+            OR (kind IN ('doc', 'op') AND data->>'owner' = $4)
+            OR (kind = 'chat' AND (
+                (data->>'recipient' IS NULL AND data->>'group' IS NULL) OR data->>'recipient' = $4
+            ))
+        )
+        AND NOT (kind = 'chat' AND EXISTS (
+            SELECT 1 FROM codeharmony.session_chat_message m
+            WHERE m.message_id = e.data->>'message_id' AND m.deleted_at IS NOT NULL
+        ))
+        ORDER BY occurred_at, event_id
+        LIMIT $7
+    ";
+
+    let page = async {
+        let client = cursor.db_pool.get().await.map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseConnection
+        })?;
+        let (host, plan_name, session_name, username) = &cursor.params;
+        let rows = client
+            .query(
+                STATEMENT,
+                &[
+                    host,
+                    plan_name,
+                    session_name,
+                    username,
+                    &cursor.occurred_at,
+                    &cursor.event_id,
+                    &REPLAY_PAGE_SIZE,
+                    &PUBLIC_KINDS,
+                ],
+            )
+            .await
+            .map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            })?;
+
+        rows.into_iter()
+            .map(SessionEvent::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::CouldntParseRows
+            })
+    };
+
+    match page.await {
+        Ok(events) => {
+            cursor.finished = (events.len() as i64) < REPLAY_PAGE_SIZE;
+            if let Some(last) = events.last() {
+                cursor.occurred_at = last.occurred_at;
+                cursor.event_id = last.event_id;
+            }
+
+            // One event per line
+            let mut body = String::new();
+            for event in events.iter() {
+                body.push_str(&event.to_json().to_string());
+                body.push('\n');
+            }
+            Some((Ok(Bytes::from(body)), cursor))
+        }
+        Err(e) => {
+            cursor.finished = true;
+            Some((Err(e), cursor))
+        }
+    }
+}
+
+// Stream back everything that happened in a session as newline delimited JSON
+// Pass from to start part way through
+#[get("session/replay/{host}/{plan_name}/{session_name}")]
+async fn get_session_replay(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<ReplayQuery>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (host, plan_name, session_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_session_access(&client, &username, &host, &plan_name, &session_name).await?;
+
+    let cursor = ReplayCursor {
+        db_pool: db_pool.get_ref().clone(),
+        params: (host, plan_name, session_name, username),
+        occurred_at: query
+            .from
+            .and_then(from_millis)
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0)),
+        event_id: 0,
+        finished: false,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream::unfold(cursor, next_replay_page)))
+}
+
+#[derive(Deserialize)]
+struct CodeEvolutionQuery {
+    section: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CodeSnapshot {
+    at: i64,
+    section: Option<String>,
+    code: String,
+}
+
+// Get every version of a student's code from a session, oldest first
+// Code synced with operations is rebuilt by playing them back in order, if a batch
+// doesn't fit the document the section is skipped until its next full copy
+#[get("session/replay/{host}/{plan_name}/{session_name}/code/{student_un}")]
+async fn get_code_evolution(
+    db_pool: web::Data<Pool>,
+    path: web::Path<(String, String, String, String)>,
+    query: web::Query<CodeEvolutionQuery>,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (host, plan_name, session_name, student_un) = path.into_inner();
+
+    // Only the teacher or the student themselves can look at their code
+    if username != host && username != student_un {
+        return Err(CodeHarmonyResponseError::NotFound);
+    }
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_session_access(&client, &username, &host, &plan_name, &session_name).await?;
+
+    const STATEMENT: &str = "
+        SELECT event_id, kind, username, data, occurred_at FROM codeharmony.session_event
+        WHERE teacher_un = $1 AND plan_name = $2 AND session_name = $3
+        AND ((kind = 'code' AND username = $4) OR (kind IN ('doc', 'op') AND data->>'owner' = $4))
+        AND ($5::VARCHAR IS NULL OR data->>'section' = $5)
+        ORDER BY occurred_at, event_id
+    ";
+
+    let rows = client
+        .query(
+            STATEMENT,
+            &[
+                &host,
+                &plan_name,
+                &session_name,
+                &student_un,
+                &query.section,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+
+    let events = rows
+        .into_iter()
+        .map(SessionEvent::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut snapshots = vec![];
+    for event in events {
+        let at = event.occurred_at.timestamp_millis();
+        let section = event.data["section"].as_str().map(str::to_owned);
+
+        match (event.kind.as_str(), section) {
+            ("code", _) => {
+                if let Some(code) = event.data["code"].as_str() {
+                    snapshots.push(CodeSnapshot {
+                        at,
+                        section: None,
+                        code: code.to_owned(),
+                    });
+                }
+            }
+            ("doc", Some(section)) => {
+                let code = event.data["code"].as_str().unwrap_or_default().to_owned();
+                documents.insert(section.to_owned(), code.to_owned());
+                snapshots.push(CodeSnapshot {
+                    at,
+                    section: Some(section),
+                    code,
+                });
+            }
+            ("op", Some(section)) => {
+                let document = match documents.remove(&section) {
+                    Some(document) => document,
+                    None => continue,
+                };
+                let operations =
+                    serde_json::from_value::<Vec<Operation>>(event.data["operations"].to_owned());
+
+                let code = operations
+                    .map_err(|e| e.to_string())
+                    .and_then(|operations| {
+                        operations.iter().try_fold(document, |document, operation| {
+                            operation.apply(&document).map_err(|e| format!("{:?}", e))
+                        })
+                    });
+
+                match code {
+                    Ok(code) => {
+                        documents.insert(section.to_owned(), code.to_owned());
+                        snapshots.push(CodeSnapshot {
+                            at,
+                            section: Some(section),
+                            code,
+                        });
+                    }
+                    // Left out of documents so nothing more is built on top of it
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(snapshots))
+}
@@ -0,0 +1,72 @@
+use actix::Addr;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use futures::{channel::mpsc, StreamExt};
+
+use crate::{
+    actors::{
+        cluster::{Cluster, SendStreamCommand},
+        sse_session::{OpenStream, SseSessionManager},
+        ws_server::SessionServer,
+    },
+    utils::{error::CodeHarmonyResponseError, principal::Principal},
+};
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(open_event_stream).service(send_stream_command);
+}
+
+// Receive the same messages as the websocket as server sent events
+// The first event is "connected", carrying the id to send commands with
+#[get("sse")]
+async fn open_event_stream(
+    session_server: web::Data<Addr<SessionServer>>,
+    cluster: web::Data<Addr<Cluster>>,
+    manager: web::Data<Addr<SseSessionManager>>,
+    Principal {
+        username,
+        role,
+        login_session,
+    }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (sender, receiver) = mpsc::unbounded();
+
+    manager.do_send(OpenStream {
+        addr: session_server.get_ref().clone(),
+        cluster: cluster.get_ref().clone(),
+        username,
+        role,
+        login_session,
+        sender,
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Stop nginx holding events back
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(receiver.map(Ok::<_, CodeHarmonyResponseError>)))
+}
+
+// Send a command for an event stream, in the same "command payload" form as the websocket
+// It can go to any node, the stream's own node is asked if it's open somewhere else
+#[post("sse/{stream_id}")]
+async fn send_stream_command(
+    cluster: web::Data<Addr<Cluster>>,
+    stream_id: web::Path<String>,
+    body: String,
+    Principal { username, .. }: Principal,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    cluster
+        .send(SendStreamCommand {
+            stream_id: stream_id.into_inner(),
+            username,
+            text: body,
+        })
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(0, "Couldn't send command".into())
+        })??;
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -1,7 +1,17 @@
 use std::convert::TryFrom;
 
-use crate::utils::error::CodeHarmonyResponseError;
-use actix_session::Session;
+use crate::{
+    actors::{
+        cluster::Cluster,
+        ws_server::{timers::CheckSubmissionOpen, SessionIdentifier},
+    },
+    endpoints::lesson_session::check_session_access,
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Teacher},
+    },
+};
+use actix::Addr;
 use actix_web::{get, http::header::ContentType, post, web, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use pg_mapper::TryFromRow;
@@ -19,107 +29,117 @@ pub fn init(cfg: &mut web::ServiceConfig) {
 async fn save_code(
     path: web::Path<(String, String, String, String)>,
     code: String,
-    session: Session,
+    Principal { username, .. }: Principal,
     db_pool: web::Data<Pool>,
+    cluster: web::Data<Addr<Cluster>>,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Check code length
-        if code.len() > 10000 {
-            return Err(CodeHarmonyResponseError::BadRequest(
-                0,
-                "Code too long!".to_owned(),
-            ));
-        }
+    // Check code length
+    if code.len() > 10000 {
+        return Err(CodeHarmonyResponseError::BadRequest(
+            0,
+            "Code too long!".to_owned(),
+        ));
+    }
 
-        // Get vars from path
-        let (plan_name, session_name, host, section_name) = path.into_inner();
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        const STATEMENT: &str = "
-            INSERT INTO codeharmony.code_submission(teacher_un, plan_name, section_name, session_name, student_un, code)
-            VALUES($1,$2,$3,$4,$5,$6)
-            ON CONFLICT ON CONSTRAINT code_submission_pk
-            DO UPDATE SET code=$6
-        ";
-
-        // Do insert query
-        client
-            .query(
-                STATEMENT,
-                &[
-                    &host,
-                    &plan_name,
-                    &section_name,
-                    &session_name,
-                    &username,
-                    &code,
-                ],
-            )
-            .await
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::DatabaseConnection
-            })?;
+    // Get vars from path
+    let (plan_name, session_name, host, section_name) = path.into_inner();
 
-        return Ok(HttpResponse::Ok());
-    }
+    // Don't take late code if the section is being timed, or code from a locked editor
+    // Asks the room wherever it's hosted, and refuses the code if it can't
+    cluster
+        .send(CheckSubmissionOpen {
+            identifier: SessionIdentifier {
+                plan_name: plan_name.to_owned(),
+                session_name: session_name.to_owned(),
+                host: host.to_owned(),
+            },
+            username: username.to_owned(),
+            section_name: section_name.to_owned(),
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::WebsocketsUnavailable)??;
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    check_session_access(&client, &username, &host, &plan_name, &session_name).await?;
+
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.code_submission(teacher_un, plan_name, section_name, session_name, student_un, code)
+        VALUES($1,$2,$3,$4,$5,$6)
+        ON CONFLICT ON CONSTRAINT code_submission_pk
+        DO UPDATE SET code=$6
+    ";
+
+    // Do insert query
+    client
+        .query(
+            STATEMENT,
+            &[
+                &host,
+                &plan_name,
+                &section_name,
+                &session_name,
+                &username,
+                &code,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseConnection
+        })?;
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok())
 }
 
-// Get code from db
+// Get the code the user saved for a section
 #[get("session/save/{plan_name}/{session_name}/{host}/{section_name}")]
 async fn get_code(
     db_pool: web::Data<Pool>,
     path: web::Path<(String, String, String, String)>,
-    session: Session,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get vars from path
-        let (plan_name, session_name, host, section_name) = path.into_inner();
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        const STATEMENT: &str = "SELECT code FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4 AND student_un = $5";
-
-        let rows = client
-            .query(
-                STATEMENT,
-                &[&host, &plan_name, &section_name, &session_name, &username],
+    // Get vars from path
+    let (plan_name, session_name, host, section_name) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "SELECT code FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4 AND student_un = $5";
+
+    let rows = client
+        .query(
+            STATEMENT,
+            &[&host, &plan_name, &section_name, &session_name, &username],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(
+                1,
+                "Couldn't get rows from database".to_string(),
             )
-            .await
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(
-                    1,
-                    "Couldn't get rows from database".to_string(),
-                )
-            })?;
-
-        if let Some(row) = rows.first() {
-            if let Ok(code) = row.try_get::<usize, String>(0) {
-                // Return Ok!
-                return Ok(HttpResponse::Ok()
-                    .content_type(ContentType::json())
-                    .body(code));
-            }
-        }
+        })?;
 
-        return Ok(HttpResponse::Ok()
-            .content_type(ContentType::json())
-            .body("[]"));
+    if let Some(row) = rows.first() {
+        if let Ok(code) = row.try_get::<usize, String>(0) {
+            // Return Ok!
+            return Ok(HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(code));
+        }
     }
 
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body("[]"))
 }
 
 #[derive(TryFromRow, Serialize)]
@@ -132,95 +152,153 @@ struct SubmittedList {
 async fn get_submitted_list(
     db_pool: web::Data<Pool>,
     path: web::Path<(String, String, String)>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        let (plan_name, session_name, section_name) = path.into_inner();
+    let (plan_name, session_name, section_name) = path.into_inner();
 
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        const STATEMENT: &str = "SELECT student_un, correct FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4";
+    const STATEMENT: &str = "SELECT student_un, correct FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4";
 
-        let rows = client
-            .query(
-                STATEMENT,
-                &[&username, &plan_name, &section_name, &session_name],
+    let rows = client
+        .query(
+            STATEMENT,
+            &[&username, &plan_name, &section_name, &session_name],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(
+                1,
+                "Couldn't get rows from database".to_string(),
             )
-            .await
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(
-                    1,
-                    "Couldn't get rows from database".to_string(),
-                )
-            })?;
-
-        let submitted = rows
-            .into_iter()
-            .map(SubmittedList::try_from)
-            .collect::<Result<Vec<SubmittedList>, _>>()
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::CouldntParseRows
-            })?;
-
-        return Ok(HttpResponse::Ok().json(submitted));
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+        })?;
+
+    let submitted = rows
+        .into_iter()
+        .map(SubmittedList::try_from)
+        .collect::<Result<Vec<SubmittedList>, _>>()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::CouldntParseRows
+        })?;
+
+    Ok(HttpResponse::Ok().json(submitted))
 }
 
 #[get("session/submitted/{plan_name}/{session_name}/{section_name}/{student_name}")]
 async fn get_student_code(
     db_pool: web::Data<Pool>,
     path: web::Path<(String, String, String, String)>,
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        let (plan_name, session_name, section_name, student_un) = path.into_inner();
-
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-        const STATEMENT: &str = "SELECT code FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4 AND student_un = $5";
-
-        let rows = client
-            .query(
-                STATEMENT,
-                &[
-                    &username,
-                    &plan_name,
-                    &section_name,
-                    &session_name,
-                    &student_un,
-                ],
+    let (plan_name, session_name, section_name, student_un) = path.into_inner();
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "SELECT code FROM codeharmony.code_submission WHERE teacher_un = $1 AND plan_name = $2 AND section_name = $3 AND session_name = $4 AND student_un = $5";
+
+    let rows = client
+        .query(
+            STATEMENT,
+            &[
+                &username,
+                &plan_name,
+                &section_name,
+                &session_name,
+                &student_un,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(
+                1,
+                "Couldn't get rows from database".to_string(),
             )
-            .await
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                CodeHarmonyResponseError::InternalError(
-                    1,
-                    "Couldn't get rows from database".to_string(),
-                )
-            })?;
-
-        if let Some(row) = rows.get(0) {
-            if let Ok(code) = row.try_get::<usize, String>(0) {
-                return Ok(HttpResponse::Ok()
-                    .content_type(ContentType::json())
-                    .body(code));
-            }
+        })?;
+
+    if let Some(row) = rows.first() {
+        if let Ok(code) = row.try_get::<usize, String>(0) {
+            return Ok(HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(code));
         }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body("[]"))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::json;
+
+    use super::*;
+
+    use crate::{create_postgres_pool, create_session_middleware, endpoints::account_management};
+
+    const USERNAME: &str = "code_test_student";
+    const PASSWORD: &str = "password_test";
+
+    #[actix_web::test]
+    async fn students_can_get_their_own_code() {
+        let (db_pool, _, _) = create_postgres_pool().await;
+        let app = test::init_service(
+            App::new()
+                .wrap(create_session_middleware(db_pool.clone()))
+                .app_data(web::Data::new(db_pool))
+                .configure(account_management::init)
+                .service(get_code),
+        )
+        .await;
+
+        // Students are who register makes, it doesn't matter if they're already there
+        let req = test::TestRequest::post()
+            .uri("/account/register")
+            .set_json(json!({
+                "username": USERNAME,
+                "password": PASSWORD,
+                "email": "code_test@testmail.com",
+            }))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/account/login")
+            .set_json(json!({ "username": USERNAME, "password": PASSWORD }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let cookie = resp
+            .response()
+            .cookies()
+            .next()
+            .expect("login should set a session cookie")
+            .into_owned();
+
+        let uri = "/session/save/plan/session/teacher/section";
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
 
-        return Ok(HttpResponse::Ok()
-            .content_type(ContentType::json())
-            .body("[]"));
+        // Still needs someone logged in
+        let req = test::TestRequest::get().uri(uri).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
     }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
 }
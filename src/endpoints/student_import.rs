@@ -0,0 +1,408 @@
+use std::collections::HashSet;
+
+use actix_web::{http::header, post, web, HttpResponse, Responder};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHasher,
+};
+use deadpool_postgres::Pool;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    endpoints::{
+        account_settings::{check_email, check_username},
+        classes::check_class_owner,
+    },
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Teacher},
+    },
+};
+
+// Most students that can be made at once, a few classes' worth
+const MAX_IMPORT_ROWS: usize = 500;
+
+// Generated passwords leave out characters that look alike when printed
+const PASSWORD_LENGTH: usize = 10;
+const PASSWORD_CHARACTERS: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(import_students);
+}
+
+// A student account waiting to be made
+#[derive(Debug)]
+struct ImportRow {
+    line: u64,
+    name: Option<String>,
+    username: String,
+    email: String,
+}
+
+// Why a line of the CSV didn't become an account
+#[derive(Serialize, Debug)]
+struct RowError {
+    line: u64,
+    username: Option<String>,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Credentials {
+    line: u64,
+    name: Option<String>,
+    username: String,
+    email: String,
+    password: String,
+}
+
+fn row_error(line: u64, username: Option<&str>, reason: &str) -> RowError {
+    RowError {
+        line,
+        username: username.map(|username| username.to_owned()),
+        reason: reason.to_owned(),
+    }
+}
+
+fn validate_row(row: &ImportRow) -> Result<(), &'static str> {
+    check_username(&row.username)?;
+    check_email(&row.email)?;
+    if row
+        .name
+        .as_ref()
+        .is_some_and(|name| name.chars().count() > 64)
+    {
+        return Err("Name must be at most 64 characters");
+    }
+    Ok(())
+}
+
+// Read the students out of a CSV with a header row
+// username and email columns are needed, name is optional and the order doesn't matter
+fn parse_students(csv: &str) -> Result<(Vec<ImportRow>, Vec<RowError>), CodeHarmonyResponseError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(csv.as_bytes());
+
+    let headers = reader
+        .headers()
+        .map_err(|_| CodeHarmonyResponseError::BadRequest(0, "Couldn't read CSV header".into()))?
+        .iter()
+        .map(|header| header.to_lowercase())
+        .collect::<Vec<_>>();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let (username_column, email_column, name_column) =
+        match (column("username"), column("email"), column("name")) {
+            (Some(username), Some(email), name) => (username, email, name),
+            _ => {
+                return Err(CodeHarmonyResponseError::BadRequest(
+                    1,
+                    "CSV needs username and email columns".into(),
+                ))
+            }
+        };
+
+    let mut rows = vec![];
+    let mut errors = vec![];
+    let mut usernames = HashSet::new();
+    let mut emails = HashSet::new();
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map(|position| position.line()).unwrap_or(0);
+                errors.push(row_error(line, None, "Couldn't read line"));
+                continue;
+            }
+        };
+        let line = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or(0);
+
+        // Skip blank lines at the end of spreadsheet exports
+        if record.iter().all(|field| field.is_empty()) {
+            continue;
+        }
+
+        if rows.len() + errors.len() >= MAX_IMPORT_ROWS {
+            return Err(CodeHarmonyResponseError::BadRequest(
+                2,
+                format!(
+                    "At most {} students can be imported at once",
+                    MAX_IMPORT_ROWS
+                ),
+            ));
+        }
+
+        let row = ImportRow {
+            line,
+            name: name_column
+                .and_then(|column| record.get(column))
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_owned()),
+            username: record.get(username_column).unwrap_or("").to_owned(),
+            email: record.get(email_column).unwrap_or("").to_owned(),
+        };
+
+        if let Err(reason) = validate_row(&row) {
+            errors.push(row_error(line, Some(&row.username), reason));
+        } else if !usernames.insert(row.username.to_lowercase()) {
+            errors.push(row_error(
+                line,
+                Some(&row.username),
+                "Username appears twice",
+            ));
+        } else if !emails.insert(row.email.to_lowercase()) {
+            errors.push(row_error(line, Some(&row.username), "Email appears twice"));
+        } else {
+            rows.push(row);
+        }
+    }
+
+    Ok((rows, errors))
+}
+
+fn generate_password() -> String {
+    let mut rng = rand::thread_rng();
+    (0..PASSWORD_LENGTH)
+        .map(|_| PASSWORD_CHARACTERS[rng.gen_range(0..PASSWORD_CHARACTERS.len())] as char)
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// A page the teacher can print and cut up, one card per student
+// Lines that didn't become accounts are listed first so they can be fixed and imported again
+fn credentials_sheet(credentials: &[Credentials], errors: &[RowError]) -> String {
+    let mut sheet = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Student accounts</title>\
+        <style>body{font-family:sans-serif}.card{display:inline-block;width:30%;margin:4px;padding:8px;\
+        border:1px dashed #888;page-break-inside:avoid}.password{font-family:monospace;font-size:1.2em}\
+        .errors{border-collapse:collapse;margin-bottom:1em}.errors td,.errors th{border:1px solid #888;padding:4px}</style>\
+        </head><body>",
+    );
+    if !errors.is_empty() {
+        sheet.push_str(
+            "<h2>Lines that weren't imported</h2><table class=\"errors\">\
+            <tr><th>Line</th><th>Username</th><th>Reason</th></tr>",
+        );
+        for error in errors.iter() {
+            sheet.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                error.line,
+                escape_html(error.username.as_deref().unwrap_or("")),
+                escape_html(&error.reason),
+            ));
+        }
+        sheet.push_str("</table>");
+    }
+    for student in credentials.iter() {
+        sheet.push_str(&format!(
+            "<div class=\"card\"><div>{}</div><div>Username: <b>{}</b></div>\
+            <div>Password: <span class=\"password\">{}</span></div></div>",
+            escape_html(student.name.as_deref().unwrap_or("")),
+            escape_html(&student.username),
+            escape_html(&student.password),
+        ));
+    }
+    sheet.push_str("</body></html>");
+    sheet
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    class_id: Option<String>,
+    // html gives back a printable sheet rather than JSON
+    format: Option<String>,
+}
+
+// Make accounts for a CSV of students, all linked to the teacher and optionally put in a class
+// Every account gets a one-time password, and login tells the student to pick a new one
+// Lines that can't be used are reported back without stopping the rest
+#[post("account/students/import")]
+async fn import_students(
+    body: String,
+    query: web::Query<ImportQuery>,
+    db_pool: web::Data<Pool>,
+    Teacher(Principal { username, .. }): Teacher,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (rows, mut errors) = parse_students(&body)?;
+
+    // Get db client
+    let mut client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    if let Some(class_id) = &query.class_id {
+        check_class_owner(&client, &username, class_id).await?;
+    }
+
+    // Hashing is slow on purpose, so keep it off the async workers
+    let hashed = web::block(move || {
+        rows.into_iter()
+            .map(|row| {
+                let password = generate_password();
+                let salt = SaltString::generate(&mut OsRng);
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| (row, password, hash.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map_err(|_| CodeHarmonyResponseError::InternalError(0, "Couldn't hash passwords".into()))?
+    .map_err(|_| CodeHarmonyResponseError::InternalError(0, "Couldn't hash passwords".into()))?;
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Taken usernames and emails are skipped rather than failing the batch
+    const USER_STATEMENT: &str = "
+        INSERT INTO codeharmony.users(username, hash, email, display_name, must_change_password)
+        VALUES ($1, $2, $3, $4, true)
+        ON CONFLICT DO NOTHING
+        RETURNING username
+    ";
+    const TEACHER_STATEMENT: &str =
+        "INSERT INTO codeharmony.student_teacher(teacher_un, student_un) VALUES ($1, $2)";
+    const CLASS_STATEMENT: &str =
+        "INSERT INTO codeharmony.class_member(class_id, student_un) VALUES ($1, $2)";
+
+    let mut credentials = vec![];
+    for (row, password, hash) in hashed {
+        let inserted = transaction
+            .query(
+                USER_STATEMENT,
+                &[&row.username, &hash, &row.email, &row.name],
+            )
+            .await
+            .map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            })?;
+        if inserted.is_empty() {
+            errors.push(row_error(
+                row.line,
+                Some(&row.username),
+                "Username or email already taken",
+            ));
+            continue;
+        }
+
+        transaction
+            .execute(TEACHER_STATEMENT, &[&username, &row.username])
+            .await
+            .map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            })?;
+        if let Some(class_id) = &query.class_id {
+            transaction
+                .execute(CLASS_STATEMENT, &[class_id, &row.username])
+                .await
+                .map_err(|e| {
+                    eprintln!("{:?}", e);
+                    CodeHarmonyResponseError::DatabaseQueryFailed
+                })?;
+        }
+
+        credentials.push(Credentials {
+            line: row.line,
+            name: row.name,
+            username: row.username,
+            email: row.email,
+            password,
+        });
+    }
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    errors.sort_by_key(|error| error.line);
+
+    // The passwords are only ever shown here, so nothing should keep a copy
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::CACHE_CONTROL, "no-store"));
+    if query.format.as_deref() == Some("html") {
+        return Ok(response
+            .content_type("text/html; charset=utf-8")
+            .body(credentials_sheet(&credentials, &errors)));
+    }
+    Ok(response.json(json!({"created": credentials, "errors": errors})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rows_and_reports_bad_ones() {
+        let csv = "Email,Name,Username\n\
+            amy@school.org,Amy Pond,amyp\n\
+            bad-email,Rory,rory\n\
+            amy2@school.org,Amy Again,AMYP\n\
+            ,,\n\
+            clara@school.org,,clara\n";
+        let (rows, errors) = parse_students(csv).unwrap();
+
+        assert_eq!(
+            rows.iter()
+                .map(|row| row.username.as_str())
+                .collect::<Vec<_>>(),
+            vec!["amyp", "clara"]
+        );
+        assert_eq!(rows[0].name.as_deref(), Some("Amy Pond"));
+        assert_eq!(rows[1].name, None);
+        assert_eq!(
+            errors.iter().map(|error| error.line).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+    }
+
+    #[test]
+    fn needs_username_and_email_columns() {
+        assert!(parse_students("name,username\nAmy,amyp\n").is_err());
+    }
+
+    #[test]
+    fn sheet_lists_lines_that_failed() {
+        let credentials = vec![Credentials {
+            line: 2,
+            name: Some("Amy Pond".to_owned()),
+            username: "amyp".to_owned(),
+            email: "amy@school.org".to_owned(),
+            password: "abc".to_owned(),
+        }];
+        let errors = vec![row_error(3, Some("<rory>"), "Username already taken")];
+
+        let sheet = credentials_sheet(&credentials, &errors);
+        assert!(sheet.contains("<td>3</td><td>&lt;rory&gt;</td><td>Username already taken</td>"));
+        assert!(sheet.contains("<b>amyp</b>"));
+        assert!(!credentials_sheet(&credentials, &[]).contains("<table"));
+    }
+
+    #[test]
+    fn passwords_avoid_lookalike_characters() {
+        let password = generate_password();
+        assert_eq!(password.len(), PASSWORD_LENGTH);
+        assert!(!password.contains(|c| "01lIoO".contains(c)));
+    }
+}
@@ -1,118 +1,146 @@
 use actix::Addr;
-use actix_session::Session;
-use actix_web::{get, post, web, HttpResponse, Responder};
-use serde_json::json;
-use tokio_postgres::error::SqlState;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
 
 use crate::{
-    actors::teacher_code_manager::{GetCode, GetTeacher, TeacherCodeManager},
-    utils::error::CodeHarmonyResponseError,
+    actors::teacher_code_manager::{
+        GetCode, GetTeacher, RegenerateCode, RevokeCode, TeacherCodeManager,
+    },
+    utils::{
+        error::CodeHarmonyResponseError,
+        principal::{Principal, Student, Teacher},
+    },
 };
 
 // Group all of the services together into a single init
 pub fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(get_teachers)
         .service(create_teacher_code)
+        .service(regenerate_teacher_code)
+        .service(revoke_teacher_code)
         .service(add_teacher);
 }
 
 #[get("account/teachers")]
 async fn get_teachers(
     db_pool: web::Data<deadpool_postgres::Pool>,
-    session: Session,
+    Principal { username, .. }: Principal,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    // If the user is logged in and their username is availabe
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get db client
-        let client = db_pool
-            .get()
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
 
-        // Get rows
-        const STATEMENT: &str =
-            "SELECT teacher_un from codeharmony.student_teacher WHERE student_un = $1";
+    // Get rows
+    const STATEMENT: &str =
+        "SELECT teacher_un from codeharmony.student_teacher WHERE student_un = $1";
 
-        let rows = client
-            .query(STATEMENT, &[&username])
-            .await
-            .map_err(|_| CodeHarmonyResponseError::DatabaseQueryFailed)?;
+    let rows = client
+        .query(STATEMENT, &[&username])
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseQueryFailed)?;
 
-        // For each row, try and get teacherUN, collect into option, return usernames if Some
-        if let Some(parsed) = rows
-            .into_iter()
-            .map(|row| row.get("teacher_un"))
-            .collect::<Option<Vec<String>>>()
-        {
-            return Ok(HttpResponse::Ok().json(parsed));
-        }
-        // If None, return CouldntParseRowsError
-        return Err(CodeHarmonyResponseError::CouldntParseRows);
+    // For each row, try and get teacherUN, collect into option, return usernames if Some
+    if let Some(parsed) = rows
+        .into_iter()
+        .map(|row| row.get("teacher_un"))
+        .collect::<Option<Vec<String>>>()
+    {
+        return Ok(HttpResponse::Ok().json(parsed));
     }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    // If None, return CouldntParseRowsError
+    Err(CodeHarmonyResponseError::CouldntParseRows)
 }
 
+// Get the teacher's current join code, making one if they need it
 #[get("account/my-code")]
 async fn create_teacher_code(
-    session: Session,
+    Teacher(Principal { username, .. }): Teacher,
     code_manager: web::Data<Addr<TeacherCodeManager>>,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        // Get the code map
-        let code = code_manager.send(GetCode { username }).await.map_err(|_| {
+    let code = code_manager
+        .send(GetCode {
+            username,
+            class_id: None,
+        })
+        .await
+        .map_err(|_| {
             CodeHarmonyResponseError::InternalError(0, "Couldn't generate code".into())
-        })?;
-        return Ok(HttpResponse::Ok().json(json!({ "code": code })));
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+        })??;
+    Ok(HttpResponse::Ok().json(code))
+}
+
+#[derive(Deserialize)]
+struct RegenerateCodeRequest {
+    expires_in_hours: Option<i64>,
+    max_uses: Option<i32>,
+    requires_approval: Option<bool>,
+}
+
+// Replace the teacher's join code, so the old one stops working
+#[post("account/my-code/regenerate")]
+async fn regenerate_teacher_code(
+    payload: Option<web::Json<RegenerateCodeRequest>>,
+    Teacher(Principal { username, .. }): Teacher,
+    code_manager: web::Data<Addr<TeacherCodeManager>>,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    let (expires_in_hours, max_uses, requires_approval) = payload
+        .map(|payload| {
+            (
+                payload.expires_in_hours,
+                payload.max_uses,
+                payload.requires_approval,
+            )
+        })
+        .unwrap_or_default();
+
+    let code = code_manager
+        .send(RegenerateCode {
+            username,
+            class_id: None,
+            expires_in_hours,
+            max_uses,
+            requires_approval,
+        })
+        .await
+        .map_err(|_| {
+            CodeHarmonyResponseError::InternalError(0, "Couldn't generate code".into())
+        })??;
+    Ok(HttpResponse::Ok().json(code))
+}
+
+// Stop the teacher's join code working
+#[delete("account/my-code")]
+async fn revoke_teacher_code(
+    Teacher(Principal { username, .. }): Teacher,
+    code_manager: web::Data<Addr<TeacherCodeManager>>,
+) -> Result<impl Responder, CodeHarmonyResponseError> {
+    code_manager
+        .send(RevokeCode {
+            username,
+            class_id: None,
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::InternalError(0, "Couldn't revoke code".into()))??;
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[post("account/add-teacher/{code}")]
 async fn add_teacher(
     path: web::Path<String>,
-    session: Session,
+    Student(Principal { username, .. }): Student,
     code_manager: web::Data<Addr<TeacherCodeManager>>,
-    db_pool: web::Data<deadpool_postgres::Pool>,
 ) -> Result<impl Responder, CodeHarmonyResponseError> {
     let code = path.into_inner();
-    if let Ok(Some(username)) = session.get::<String>("username") {
-        if let Ok(Some(teacher_un)) = code_manager.send(GetTeacher { code }).await {
-            // If the code is valid, add the record to the DB
+    // If the code is valid, this adds the teacher
+    let owner = code_manager
+        .send(GetTeacher {
+            code,
+            student_un: username,
+        })
+        .await
+        .map_err(|_| CodeHarmonyResponseError::InternalError(0, "Couldn't use code".into()))??;
 
-            // Get db client
-            let client = db_pool
-                .get()
-                .await
-                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
-
-            const STATEMENT: &str =
-                "INSERT INTO codeharmony.student_teacher (teacher_un, student_un) VALUES ($1, $2)";
-
-            // Insert the record,
-            // Return teacher aleardy added if not unique
-            client
-                .query(STATEMENT, &[&teacher_un, &username])
-                .await
-                .map_err(|err| -> CodeHarmonyResponseError {
-                    match err.as_db_error() {
-                        Some(err) => match *err.code() {
-                            SqlState::UNIQUE_VIOLATION => CodeHarmonyResponseError::BadRequest(
-                                0,
-                                "Teacher already added".into(),
-                            ),
-                            _ => CodeHarmonyResponseError::DatabaseQueryFailed,
-                        },
-                        None => CodeHarmonyResponseError::DatabaseQueryFailed,
-                    }
-                })?;
-
-            return Ok(HttpResponse::Ok().json(json!({ "teacher_un": teacher_un })));
-        } else {
-            return Err(CodeHarmonyResponseError::InternalError(
-                0,
-                "Invalid code".into(),
-            ));
-        }
-    }
-    Err(CodeHarmonyResponseError::NotLoggedIn)
+    Ok(HttpResponse::Ok().json(owner))
 }
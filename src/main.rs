@@ -1,7 +1,7 @@
 use std::{env, fs};
 
 use actix::Actor;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_session::SessionMiddleware;
 use actix_web::{
     cookie::Key,
     middleware::Logger,
@@ -9,19 +9,27 @@ use actix_web::{
     App, HttpServer,
 };
 use actors::{
+    cluster::Cluster,
+    sse_session::SseSessionManager,
     teacher_code_manager::TeacherCodeManager,
     ws_server::{session_service, SessionServer},
 };
 
 use native_tls::{Certificate, TlsConnector};
 use url::Url;
+use utils::{mailer::mailer_from_env, session_store::PostgresSessionStore};
 
 use endpoints::{account_management, lesson_plan, lesson_session, student_teacher};
 
-use deadpool_postgres::{ManagerConfig, RecyclingMethod};
+use deadpool_postgres::{ManagerConfig, RecyclingMethod, SslMode};
 use dotenv::dotenv;
+use postgres_native_tls::MakeTlsConnector;
 
-use crate::endpoints::{code_execution, publish_plan, student_code};
+use crate::endpoints::{
+    account_sessions, account_settings, api_tokens, classes, code_execution, password_reset,
+    publish_plan, roster, session_chat, session_help, session_poll, session_questions,
+    session_replay, session_stream, student_code, student_import,
+};
 
 mod actors;
 mod endpoints;
@@ -38,13 +46,33 @@ async fn main() -> std::io::Result<()> {
 
     println!("Hosting on {}:{}", &host, &port);
 
-    let postgres_pool = create_postgres_pool().await;
+    let (postgres_pool, postgres_config, postgres_connector) = create_postgres_pool().await;
 
     // Setup lesson session server
-    let ws_session_server = SessionServer::new().start();
+    let ws_session_server = SessionServer::new(postgres_pool.clone()).start();
+
+    // Share rooms with any other instances using the same database
+    let node_id = env::var("NODE_ID").unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+    println!("Cluster node id {}", &node_id);
+
+    // Event streams for clients that can't use websockets
+    let sse_session_manager = SseSessionManager::new(&node_id).start();
+
+    let cluster = Cluster::new(
+        &node_id,
+        postgres_config,
+        postgres_connector,
+        postgres_pool.clone(),
+        ws_session_server.clone(),
+        sse_session_manager.clone(),
+    )
+    .start();
 
     // Teacher code actor
-    let teacher_code_actor = TeacherCodeManager::new().start();
+    let teacher_code_actor = TeacherCodeManager::new(postgres_pool.clone()).start();
+
+    // Password reset emails
+    let mailer = web::Data::from(mailer_from_env());
 
     // Error logger
     env_logger::init();
@@ -53,37 +81,59 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         let logger = Logger::default();
         App::new()
-            .wrap(create_cookie_session())
+            .wrap(create_session_middleware(postgres_pool.clone()))
             .wrap(logger)
             .app_data(web::Data::new(postgres_pool.clone()))
             .app_data(web::Data::new(ws_session_server.clone()))
+            .app_data(web::Data::new(cluster.clone()))
+            .app_data(web::Data::new(sse_session_manager.clone()))
             .app_data(web::Data::new(teacher_code_actor.clone()))
+            .app_data(mailer.clone())
             .route("/ws", web::get().to(session_service))
             .configure(lesson_plan::init)
             .configure(lesson_session::init)
             .configure(account_management::init)
+            .configure(account_sessions::init)
+            .configure(api_tokens::init)
+            .configure(account_settings::init)
+            .configure(password_reset::init)
             .configure(student_teacher::init)
+            .configure(classes::init)
+            .configure(roster::init)
+            .configure(student_import::init)
             .configure(code_execution::init)
             .configure(publish_plan::init)
             .configure(student_code::init)
+            .configure(session_chat::init)
+            .configure(session_help::init)
+            .configure(session_poll::init)
+            .configure(session_questions::init)
+            .configure(session_replay::init)
+            .configure(session_stream::init)
     })
     .bind(format!("{}:{}", host, port))?
     .run()
     .await
 }
 
-fn create_cookie_session() -> SessionMiddleware<CookieSessionStore> {
+fn create_session_middleware(
+    db_pool: deadpool_postgres::Pool,
+) -> SessionMiddleware<PostgresSessionStore> {
     // Load .ENV file
     dotenv().ok();
 
-    // Get session key
+    // Get session key, which signs the cookies holding each session's key
     let session_key = env::var("SESSION_KEY").expect("SESSION_KEY not set!");
     let session_key = Key::from(session_key.as_bytes());
 
-    SessionMiddleware::new(CookieSessionStore::default(), session_key)
+    SessionMiddleware::new(PostgresSessionStore::new(db_pool), session_key)
 }
 
-async fn create_postgres_pool() -> deadpool_postgres::Pool {
+async fn create_postgres_pool() -> (
+    deadpool_postgres::Pool,
+    tokio_postgres::Config,
+    MakeTlsConnector,
+) {
     // Load .ENV file
     dotenv().ok();
 
@@ -116,7 +166,11 @@ async fn create_postgres_pool() -> deadpool_postgres::Pool {
     cfg.manager = Some(ManagerConfig {
         recycling_method: RecyclingMethod::Fast,
     });
-    cfg.ssl_mode = Some(deadpool_postgres::SslMode::Require);
+    cfg.ssl_mode = Some(match env::var("DATABASE_SSL_MODE").as_deref() {
+        Ok("disable") => SslMode::Disable,
+        Ok("prefer") => SslMode::Prefer,
+        _ => SslMode::Require,
+    });
     cfg.application_name = Some("Code_Harmony server".to_owned());
 
     println!("Created config");
@@ -134,12 +188,12 @@ async fn create_postgres_pool() -> deadpool_postgres::Pool {
         .danger_accept_invalid_hostnames(true)
         .build()
         .expect("Couldn't build connector");
-    let connector = postgres_native_tls::MakeTlsConnector::new(connector);
+    let connector = MakeTlsConnector::new(connector);
 
     println!("Created connector");
 
     let pool = cfg
-        .create_pool(None, connector)
+        .create_pool(None, connector.clone())
         .expect("Couldn't start postgres_pool");
 
     println!("Postgres pool created");
@@ -154,5 +208,9 @@ async fn create_postgres_pool() -> deadpool_postgres::Pool {
 
     println!("Postgres pool valid");
 
-    pool
+    let pg_config = cfg
+        .get_pg_config()
+        .expect("Couldn't create postgres config");
+
+    (pool, pg_config, connector)
 }
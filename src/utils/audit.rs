@@ -0,0 +1,30 @@
+use chrono::Utc;
+use deadpool_postgres::Pool;
+
+use crate::actors::ws_server::execute_in_background;
+
+// Save a security event, like an account being locked, without holding up the request
+// username is the account it was about, even if no such account exists
+pub fn audit(
+    db_pool: &Pool,
+    kind: &str,
+    username: Option<&str>,
+    ip: Option<&str>,
+    detail: serde_json::Value,
+) {
+    const STATEMENT: &str = "
+        INSERT INTO codeharmony.audit_log(kind, username, ip, detail, logged_at)
+        VALUES ($1, $2, $3, $4, $5)
+    ";
+    execute_in_background(
+        db_pool,
+        STATEMENT,
+        vec![
+            Box::new(kind.to_owned()),
+            Box::new(username.map(str::to_owned)),
+            Box::new(ip.map(str::to_owned)),
+            Box::new(detail),
+            Box::new(Utc::now().naive_utc()),
+        ],
+    );
+}
@@ -3,10 +3,12 @@ use actix_web::{
     http::{header, StatusCode},
     HttpResponse, HttpResponseBuilder,
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[allow(dead_code)]
-#[derive(Error, Debug)]
+// Serializable so a room on another node can send one back through the cluster
+#[derive(Error, Debug, Serialize, Deserialize)]
 pub enum CodeHarmonyResponseError {
     #[error("{{\"errcode\": {0}, \"msg\": \"{1}\"}}")]
     InternalError(i32, String),
@@ -26,6 +28,15 @@ pub enum CodeHarmonyResponseError {
     WebsocketsUnavailable,
     #[error("{{\"errcode\":404, \"msg\": \"No content found\"}}")]
     NotFound,
+    #[error("{{\"errcode\":403, \"msg\": \"Time is up for this section\"}}")]
+    SubmissionsClosed,
+    #[error("{{\"errcode\":403, \"msg\": \"Your editor is locked\"}}")]
+    EditorLocked,
+    #[error("{{\"errcode\":403, \"msg\": \"Not allowed\"}}")]
+    Forbidden,
+    // How many seconds until another attempt is allowed
+    #[error("{{\"errcode\":429, \"msg\": \"Too many attempts, try again in {0} seconds\"}}")]
+    TooManyAttempts(i64),
 }
 
 impl error::ResponseError for CodeHarmonyResponseError {
@@ -40,12 +51,19 @@ impl error::ResponseError for CodeHarmonyResponseError {
             CodeHarmonyResponseError::CouldntParseRows => StatusCode::INTERNAL_SERVER_ERROR,
             CodeHarmonyResponseError::WebsocketsUnavailable => StatusCode::INTERNAL_SERVER_ERROR,
             CodeHarmonyResponseError::NotFound => StatusCode::NOT_FOUND,
+            CodeHarmonyResponseError::SubmissionsClosed => StatusCode::FORBIDDEN,
+            CodeHarmonyResponseError::EditorLocked => StatusCode::FORBIDDEN,
+            CodeHarmonyResponseError::Forbidden => StatusCode::FORBIDDEN,
+            CodeHarmonyResponseError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code())
-            .insert_header(header::ContentType(mime::APPLICATION_JSON))
-            .body(self.to_string())
+        let mut response = HttpResponseBuilder::new(self.status_code());
+        response.insert_header(header::ContentType(mime::APPLICATION_JSON));
+        if let CodeHarmonyResponseError::TooManyAttempts(seconds) = self {
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        response.body(self.to_string())
     }
 }
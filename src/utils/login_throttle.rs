@@ -0,0 +1,277 @@
+use std::env;
+
+use actix_web::HttpRequest;
+use chrono::{Duration, NaiveDateTime, Utc};
+use deadpool_postgres::{Object, Pool};
+use serde_json::json;
+
+use crate::utils::{audit::audit, error::CodeHarmonyResponseError};
+
+// Failures older than this are forgotten
+const FAILURE_WINDOW_MINUTES: i64 = 15;
+
+// Longest wait between attempts short of being locked out
+const MAX_DELAY_SECONDS: i64 = 30;
+
+// How hard failed logins are held against one username or address
+struct Limits {
+    kind: &'static str,
+    // Failures allowed before every attempt has to wait a little longer
+    free_attempts: i32,
+    // Failures that lock it out completely for a while
+    lockout_after: i32,
+    lockout_minutes: i64,
+}
+
+// A username is only guessed at by one person, an address can be a whole school behind one router
+const USER_LIMITS: Limits = Limits {
+    kind: "user",
+    free_attempts: 3,
+    lockout_after: 10,
+    lockout_minutes: 15,
+};
+const IP_LIMITS: Limits = Limits {
+    kind: "ip",
+    free_attempts: 20,
+    lockout_after: 100,
+    lockout_minutes: 15,
+};
+
+impl Limits {
+    // The wait after the latest failure, doubling with each failure past the free ones
+    fn delay_seconds(&self, failures: i32) -> i64 {
+        if failures < self.free_attempts {
+            return 0;
+        }
+        let doublings = (failures - self.free_attempts).min(6) as u32;
+        (1_i64 << doublings).min(MAX_DELAY_SECONDS)
+    }
+
+    // Seconds until another attempt is allowed, 0 if one is allowed now
+    fn wait_seconds(
+        &self,
+        failures: i32,
+        last_failure_at: NaiveDateTime,
+        locked_until: Option<NaiveDateTime>,
+        now: NaiveDateTime,
+    ) -> i64 {
+        let locked = locked_until
+            .map(|locked_until| (locked_until - now).num_seconds())
+            .unwrap_or(0);
+        let delayed = if now - last_failure_at > Duration::minutes(FAILURE_WINDOW_MINUTES) {
+            0
+        } else {
+            (last_failure_at + Duration::seconds(self.delay_seconds(failures)) - now).num_seconds()
+        };
+        locked.max(delayed).max(0)
+    }
+}
+
+// Where a request came from
+// Behind a reverse proxy set TRUST_FORWARDED_FOR, otherwise everyone looks like the proxy
+// Without one it has to stay off, as anyone can send the header
+pub fn client_ip(req: &HttpRequest) -> String {
+    let trust_forwarded = env::var("TRUST_FORWARDED_FOR")
+        .map(|trust| trust == "true")
+        .unwrap_or(false);
+    if trust_forwarded {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.to_owned();
+        }
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// A login attempt that's already been counted as a failure against its username and address
+pub struct Attempt {
+    user_failures: i32,
+    ip_failures: i32,
+}
+
+// Turn a login away if its username or address has failed too often lately,
+// otherwise count it as a failure before the password is checked
+// The rows are locked while that happens, so attempts made at the same time can't all get in
+// before any of them is counted
+pub async fn reserve_attempt(
+    client: &mut Object,
+    username: &str,
+    ip: &str,
+) -> Result<Attempt, CodeHarmonyResponseError> {
+    // Makes sure there's a row to lock, without changing one that's there
+    const LOCK_STATEMENT: &str = "
+        INSERT INTO codeharmony.login_throttle AS t (kind, subject, failures, last_failure_at)
+        VALUES ($1, $2, 0, $3)
+        ON CONFLICT ON CONSTRAINT login_throttle_pk DO UPDATE SET kind = t.kind
+        RETURNING failures, last_failure_at, locked_until
+    ";
+    const RESERVE_STATEMENT: &str = "
+        UPDATE codeharmony.login_throttle
+        SET failures = CASE WHEN last_failure_at < $4 THEN 1 ELSE failures + 1 END,
+        last_failure_at = $3
+        WHERE kind = $1 AND subject = $2
+        RETURNING failures
+    ";
+    // Tidies away anything that's been forgotten while it's here, skipping rows other logins hold
+    const CLEANUP_STATEMENT: &str = "
+        DELETE FROM codeharmony.login_throttle WHERE (kind, subject) IN (
+            SELECT kind, subject FROM codeharmony.login_throttle
+            WHERE last_failure_at < $2 AND (locked_until IS NULL OR locked_until < $1)
+            FOR UPDATE SKIP LOCKED
+        )
+    ";
+
+    let now = Utc::now().naive_utc();
+    let window_start = now - Duration::minutes(FAILURE_WINDOW_MINUTES);
+    let subjects = [(&USER_LIMITS, username), (&IP_LIMITS, ip)];
+
+    // Start transaction
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Always username then address, so two logins can't each hold the row the other wants
+    let mut wait = 0;
+    for (limits, subject) in subjects {
+        let rows = transaction
+            .query(LOCK_STATEMENT, &[&limits.kind, &subject, &now])
+            .await
+            .map_err(database_error)?;
+        if let Some(row) = rows.first() {
+            wait = wait.max(limits.wait_seconds(row.get(0), row.get(1), row.get(2), now));
+        }
+    }
+    if wait > 0 {
+        return Err(CodeHarmonyResponseError::TooManyAttempts(wait));
+    }
+
+    let mut failures = [0; 2];
+    for (count, (limits, subject)) in failures.iter_mut().zip(subjects) {
+        let rows = transaction
+            .query(
+                RESERVE_STATEMENT,
+                &[&limits.kind, &subject, &now, &window_start],
+            )
+            .await
+            .map_err(database_error)?;
+        *count = rows.first().map(|row| row.get::<_, i32>(0)).unwrap_or(0);
+    }
+
+    transaction
+        .execute(CLEANUP_STATEMENT, &[&now, &window_start])
+        .await
+        .map_err(database_error)?;
+
+    // Commit transaction, everything went well
+    transaction
+        .commit()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    Ok(Attempt {
+        user_failures: failures[0],
+        ip_failures: failures[1],
+    })
+}
+
+// Lock out the username or address of a failed attempt if it's had too many
+pub async fn record_failure(
+    client: &Object,
+    db_pool: &Pool,
+    username: &str,
+    ip: &str,
+    attempt: Attempt,
+) -> Result<(), CodeHarmonyResponseError> {
+    const LOCK_STATEMENT: &str = "
+        UPDATE codeharmony.login_throttle SET failures = 0, locked_until = $3
+        WHERE kind = $1 AND subject = $2
+    ";
+
+    let now = Utc::now().naive_utc();
+    for (limits, subject, failures) in [
+        (&USER_LIMITS, username, attempt.user_failures),
+        (&IP_LIMITS, ip, attempt.ip_failures),
+    ] {
+        if failures < limits.lockout_after {
+            continue;
+        }
+
+        let locked_until = now + Duration::minutes(limits.lockout_minutes);
+        client
+            .execute(LOCK_STATEMENT, &[&limits.kind, &subject, &locked_until])
+            .await
+            .map_err(database_error)?;
+        audit(
+            db_pool,
+            "login_lockout",
+            Some(username),
+            Some(ip),
+            json!({
+                "locked": limits.kind,
+                "failures": failures,
+                "minutes": limits.lockout_minutes,
+            }),
+        );
+    }
+    Ok(())
+}
+
+// Forget a username's failures once someone gets its password right, and hand back the
+// attempt the address had counted against it
+// The address keeps its other failures, or logging into your own account would reset someone
+// else's guessing
+pub async fn record_success(
+    client: &Object,
+    username: &str,
+    ip: &str,
+) -> Result<(), CodeHarmonyResponseError> {
+    const STATEMENT: &str =
+        "DELETE FROM codeharmony.login_throttle WHERE kind = 'user' AND subject = $1";
+    const IP_STATEMENT: &str = "
+        UPDATE codeharmony.login_throttle SET failures = GREATEST(failures - 1, 0)
+        WHERE kind = 'ip' AND subject = $1
+    ";
+    client
+        .execute(STATEMENT, &[&username])
+        .await
+        .map_err(database_error)?;
+    client
+        .execute(IP_STATEMENT, &[&ip])
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+fn database_error(e: tokio_postgres::Error) -> CodeHarmonyResponseError {
+    eprintln!("{:?}", e);
+    CodeHarmonyResponseError::DatabaseQueryFailed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_grow_then_lock() {
+        let now = Utc::now().naive_utc();
+        assert_eq!(USER_LIMITS.wait_seconds(2, now, None, now), 0);
+        assert_eq!(USER_LIMITS.wait_seconds(3, now, None, now), 1);
+        assert_eq!(USER_LIMITS.wait_seconds(5, now, None, now), 4);
+        assert_eq!(
+            USER_LIMITS.wait_seconds(9, now, None, now),
+            MAX_DELAY_SECONDS
+        );
+        assert_eq!(IP_LIMITS.wait_seconds(9, now, None, now), 0);
+
+        // Old failures don't count, but a lockout still does
+        let old = now - Duration::minutes(FAILURE_WINDOW_MINUTES + 1);
+        assert_eq!(USER_LIMITS.wait_seconds(9, old, None, now), 0);
+        let locked_until = now + Duration::minutes(5);
+        assert_eq!(
+            USER_LIMITS.wait_seconds(0, old, Some(locked_until), now),
+            5 * 60
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    env,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+
+// An email ready to go out
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Anything that can deliver emails
+// Sending blocks, so call it from web::block rather than straight from a handler
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+// Sends email through an SMTP server
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<SmtpMailer, String> {
+        let mut builder = SmtpTransport::starttls_relay(host).map_err(|e| e.to_string())?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .map_err(|e: lettre::address::AddressError| e.to_string())?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email
+                .to
+                .parse()
+                .map_err(|e: lettre::address::AddressError| e.to_string())?)
+            .subject(email.subject.to_owned())
+            .body(email.body.to_owned())
+            .map_err(|e| e.to_string())?;
+
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Writes emails to a file, or stdout without one, for running without a mail server
+pub struct LogMailer {
+    path: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+impl LogMailer {
+    pub fn new(path: Option<PathBuf>) -> LogMailer {
+        LogMailer {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        let entry = format!(
+            "To: {}\nSubject: {}\n\n{}\n----\n",
+            email.to, email.subject, email.body
+        );
+
+        match &self.path {
+            Some(path) => {
+                let _guard = self.lock.lock().map_err(|e| e.to_string())?;
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(entry.as_bytes()))
+                    .map_err(|e| e.to_string())
+            }
+            None => {
+                println!("{}", entry);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Use SMTP when SMTP_HOST is set, otherwise log emails to MAIL_LOG_FILE or stdout
+pub fn mailer_from_env() -> Arc<dyn Mailer> {
+    match env::var("SMTP_HOST") {
+        Ok(host) => {
+            let port = env::var("SMTP_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok());
+            let credentials = env::var("SMTP_USERNAME")
+                .ok()
+                .zip(env::var("SMTP_PASSWORD").ok());
+            let from = env::var("MAIL_FROM").expect("MAIL_FROM not set!");
+
+            println!("Sending mail through {}", &host);
+            Arc::new(
+                SmtpMailer::new(&host, port, credentials, &from)
+                    .expect("Couldn't set up SMTP mailer"),
+            )
+        }
+        Err(_) => {
+            let path = env::var("MAIL_LOG_FILE").ok().map(PathBuf::from);
+            println!("SMTP_HOST not set, logging mail instead");
+            Arc::new(LogMailer::new(path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn log_mailer_appends_to_file() {
+        let path = env::temp_dir().join(format!("mail-{}.log", uuid::Uuid::new_v4()));
+        let mailer = LogMailer::new(Some(path.clone()));
+
+        for subject in ["First", "Second"] {
+            mailer
+                .send(&Email {
+                    to: "amy@school.org".into(),
+                    subject: subject.into(),
+                    body: "Hello".into(),
+                })
+                .unwrap();
+        }
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert!(log.contains("To: amy@school.org\nSubject: First"));
+        assert!(log.contains("Subject: Second"));
+    }
+}
@@ -1,2 +1,8 @@
+pub mod audit;
 pub mod error;
 pub mod jsx_element;
+pub mod login_throttle;
+pub mod mailer;
+pub mod operational_transform;
+pub mod principal;
+pub mod session_store;
@@ -0,0 +1,334 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Text operations in the same shape ot.js uses on the wire:
+// a positive number retains that many characters, a negative number deletes
+// that many, and a string is inserted. Lengths are counted in chars.
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OperationError {
+    #[error("Operation is for a document of length {expected}, got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("Operations can't be transformed against each other")]
+    Incompatible,
+    #[error("Operation counts are longer than a document of length {max_len}")]
+    TooLong { max_len: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "EncodedComponent", into = "EncodedComponent")]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EncodedComponent {
+    Count(i64),
+    Insert(String),
+}
+
+impl TryFrom<EncodedComponent> for Component {
+    type Error = &'static str;
+
+    fn try_from(encoded: EncodedComponent) -> Result<Self, Self::Error> {
+        match encoded {
+            EncodedComponent::Count(count) if count > 0 => Ok(Component::Retain(count as usize)),
+            EncodedComponent::Count(count) if count < 0 => {
+                Ok(Component::Delete(count.unsigned_abs() as usize))
+            }
+            EncodedComponent::Count(_) => Err("Operation components can't be empty"),
+            EncodedComponent::Insert(text) => Ok(Component::Insert(text)),
+        }
+    }
+}
+
+impl From<Component> for EncodedComponent {
+    fn from(component: Component) -> Self {
+        match component {
+            Component::Retain(count) => EncodedComponent::Count(count as i64),
+            Component::Delete(count) => EncodedComponent::Count(-(count as i64)),
+            Component::Insert(text) => EncodedComponent::Insert(text),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct Operation {
+    components: Vec<Component>,
+}
+
+impl Operation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn retain(&mut self, count: usize) -> &mut Self {
+        if count == 0 {
+            return self;
+        }
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += count;
+        } else {
+            self.components.push(Component::Retain(count));
+        }
+        self
+    }
+
+    pub fn insert(&mut self, text: &str) -> &mut Self {
+        if text.is_empty() {
+            return self;
+        }
+
+        // Inserts always go before deletes so equivalent operations look the same
+        let len = self.components.len();
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] => last.push_str(text),
+            [.., Component::Insert(before), Component::Delete(_)] => before.push_str(text),
+            [.., Component::Delete(_)] => self
+                .components
+                .insert(len - 1, Component::Insert(text.to_owned())),
+            _ => self.components.push(Component::Insert(text.to_owned())),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, count: usize) -> &mut Self {
+        if count == 0 {
+            return self;
+        }
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += count;
+        } else {
+            self.components.push(Component::Delete(count));
+        }
+        self
+    }
+
+    // Counts come from clients, so they're added up checked rather than trusted
+    fn checked_len(&self, len: impl Fn(&Component) -> usize) -> Option<usize> {
+        self.components
+            .iter()
+            .try_fold(0_usize, |total, component| {
+                total.checked_add(len(component))
+            })
+    }
+
+    // Length of the document the operation can be applied to, None if it's too big to count
+    pub fn base_len(&self) -> Option<usize> {
+        self.checked_len(|component| match component {
+            Component::Retain(count) | Component::Delete(count) => *count,
+            Component::Insert(_) => 0,
+        })
+    }
+
+    // Length of the document after the operation has been applied, None if it's too big to count
+    pub fn target_len(&self) -> Option<usize> {
+        self.checked_len(|component| match component {
+            Component::Retain(count) => *count,
+            Component::Insert(text) => text.chars().count(),
+            Component::Delete(_) => 0,
+        })
+    }
+
+    // Make sure every retain and delete, and all of them together, fit in a document of max_len chars
+    // Each count is checked on its own first so they're never summed past max_len
+    pub fn check_fits(&self, max_len: usize) -> Result<(), OperationError> {
+        let counts_fit = self.components.iter().all(|component| match component {
+            Component::Retain(count) | Component::Delete(count) => *count <= max_len,
+            Component::Insert(_) => true,
+        });
+        if !counts_fit {
+            return Err(OperationError::TooLong { max_len });
+        }
+        match self.base_len() {
+            Some(len) if len <= max_len => Ok(()),
+            _ => Err(OperationError::TooLong { max_len }),
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> Result<String, OperationError> {
+        let actual = text.chars().count();
+        let expected = self
+            .base_len()
+            .ok_or(OperationError::TooLong { max_len: actual })?;
+        if actual != expected {
+            return Err(OperationError::LengthMismatch { expected, actual });
+        }
+
+        let mut chars = text.chars();
+        let mut result = String::with_capacity(text.len());
+        for component in &self.components {
+            match component {
+                Component::Retain(count) => result.extend(chars.by_ref().take(*count)),
+                Component::Insert(inserted) => result.push_str(inserted),
+                Component::Delete(count) => {
+                    chars.by_ref().take(*count).for_each(drop);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // Transform two operations made against the same document so that
+    // a then b' and b then a' both end up at the same document
+    // a wins when both insert at the same place
+    pub fn transform(
+        a: &Operation,
+        b: &Operation,
+    ) -> Result<(Operation, Operation), OperationError> {
+        match (a.base_len(), b.base_len()) {
+            (Some(a_len), Some(b_len)) if a_len == b_len => {}
+            _ => return Err(OperationError::Incompatible),
+        }
+
+        let mut a_prime = Operation::new();
+        let mut b_prime = Operation::new();
+
+        let mut a_iter = a.components.iter().cloned();
+        let mut b_iter = b.components.iter().cloned();
+        let mut a_next = a_iter.next();
+        let mut b_next = b_iter.next();
+
+        loop {
+            match (a_next.take(), b_next.take()) {
+                (None, None) => break,
+                (Some(Component::Insert(text)), b_component) => {
+                    let len = text.chars().count();
+                    a_prime.insert(&text);
+                    b_prime.retain(len);
+                    a_next = a_iter.next();
+                    b_next = b_component;
+                }
+                (a_component, Some(Component::Insert(text))) => {
+                    let len = text.chars().count();
+                    a_prime.retain(len);
+                    b_prime.insert(&text);
+                    a_next = a_component;
+                    b_next = b_iter.next();
+                }
+                (Some(Component::Retain(a_count)), Some(Component::Retain(b_count))) => {
+                    let count = a_count.min(b_count);
+                    a_prime.retain(count);
+                    b_prime.retain(count);
+                    a_next = remainder(Component::Retain(a_count), count, &mut a_iter);
+                    b_next = remainder(Component::Retain(b_count), count, &mut b_iter);
+                }
+                (Some(Component::Delete(a_count)), Some(Component::Delete(b_count))) => {
+                    // Both deleted the same text, there's nothing left to do
+                    let count = a_count.min(b_count);
+                    a_next = remainder(Component::Delete(a_count), count, &mut a_iter);
+                    b_next = remainder(Component::Delete(b_count), count, &mut b_iter);
+                }
+                (Some(Component::Delete(a_count)), Some(Component::Retain(b_count))) => {
+                    let count = a_count.min(b_count);
+                    a_prime.delete(count);
+                    a_next = remainder(Component::Delete(a_count), count, &mut a_iter);
+                    b_next = remainder(Component::Retain(b_count), count, &mut b_iter);
+                }
+                (Some(Component::Retain(a_count)), Some(Component::Delete(b_count))) => {
+                    let count = a_count.min(b_count);
+                    b_prime.delete(count);
+                    a_next = remainder(Component::Retain(a_count), count, &mut a_iter);
+                    b_next = remainder(Component::Delete(b_count), count, &mut b_iter);
+                }
+                _ => return Err(OperationError::Incompatible),
+            }
+        }
+
+        Ok((a_prime, b_prime))
+    }
+}
+
+// What's left of a retain or delete once count chars have been used,
+// moving on to the next component if it's all gone
+fn remainder(
+    component: Component,
+    count: usize,
+    iter: &mut impl Iterator<Item = Component>,
+) -> Option<Component> {
+    match component {
+        Component::Retain(total) if total > count => Some(Component::Retain(total - count)),
+        Component::Delete(total) if total > count => Some(Component::Delete(total - count)),
+        _ => iter.next(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let operation: Operation = serde_json::from_str(r#"[6, -5, "Rust", 1]"#).unwrap();
+
+        assert_eq!(operation.apply("Hello world!").unwrap(), "Hello Rust!");
+        assert_eq!(
+            operation.apply("Hello"),
+            Err(OperationError::LengthMismatch {
+                expected: 12,
+                actual: 5
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&operation).unwrap(),
+            r#"[6,-5,"Rust",1]"#
+        );
+    }
+
+    #[test]
+    fn test_overflowing_counts() {
+        let operation: Operation =
+            serde_json::from_str(r#"[9223372036854775807, 9223372036854775807, 2]"#).unwrap();
+
+        assert_eq!(operation.base_len(), None);
+        assert_eq!(
+            operation.apply("ab"),
+            Err(OperationError::TooLong { max_len: 2 })
+        );
+        assert_eq!(
+            Operation::transform(&operation, &operation),
+            Err(OperationError::Incompatible)
+        );
+    }
+
+    #[test]
+    fn test_transform_converges() {
+        let text = "fn main() {}";
+
+        // One side renames the function while the other fills in the body
+        let mut a = Operation::new();
+        a.retain(3).delete(4).insert("start").retain(5);
+        let mut b = Operation::new();
+        b.retain(11).insert(" run(); ").retain(1);
+
+        let (a_prime, b_prime) = Operation::transform(&a, &b).unwrap();
+        let a_then_b = b_prime.apply(&a.apply(text).unwrap()).unwrap();
+        let b_then_a = a_prime.apply(&b.apply(text).unwrap()).unwrap();
+
+        assert_eq!(a_then_b, "fn start() { run(); }");
+        assert_eq!(a_then_b, b_then_a);
+    }
+
+    #[test]
+    fn test_transform_overlapping_deletes() {
+        let text = "abcdef";
+
+        let mut a = Operation::new();
+        a.retain(1).delete(3).retain(2);
+        let mut b = Operation::new();
+        b.retain(2).delete(3).insert("X").retain(1);
+
+        let (a_prime, b_prime) = Operation::transform(&a, &b).unwrap();
+        let a_then_b = b_prime.apply(&a.apply(text).unwrap()).unwrap();
+        let b_then_a = a_prime.apply(&b.apply(text).unwrap()).unwrap();
+
+        assert_eq!(a_then_b, "aXf");
+        assert_eq!(a_then_b, b_then_a);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{future::Future, ops::Deref, pin::Pin};
+
+use actix_session::SessionExt;
+use actix_web::{
+    dev::Payload,
+    http::{header, Method},
+    web, FromRequest, HttpRequest,
+};
+use chrono::{NaiveDateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::ws_server::execute_in_background,
+    endpoints::password_reset::hash_token,
+    utils::{error::CodeHarmonyResponseError, session_store::current_session_id},
+};
+
+// What an account is allowed to do
+// Admins can do everything a teacher can
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Teacher,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Teacher => "teacher",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "student" => Some(Role::Student),
+            "teacher" => Some(Role::Teacher),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn can_teach(&self) -> bool {
+        matches!(self, Role::Teacher | Role::Admin)
+    }
+}
+
+// What an API token can be used for
+// Each one covers a group of routes, and routes outside every group can't be used with a token
+#[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Scope {
+    #[serde(rename = "plans:read")]
+    PlansRead,
+    #[serde(rename = "plans:write")]
+    PlansWrite,
+    #[serde(rename = "sessions:read")]
+    SessionsRead,
+    #[serde(rename = "sessions:write")]
+    SessionsWrite,
+    #[serde(rename = "submissions:read")]
+    SubmissionsRead,
+    #[serde(rename = "roster:read")]
+    RosterRead,
+    #[serde(rename = "roster:write")]
+    RosterWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::PlansRead => "plans:read",
+            Scope::PlansWrite => "plans:write",
+            Scope::SessionsRead => "sessions:read",
+            Scope::SessionsWrite => "sessions:write",
+            Scope::SubmissionsRead => "submissions:read",
+            Scope::RosterRead => "roster:read",
+            Scope::RosterWrite => "roster:write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "plans:read" => Some(Scope::PlansRead),
+            "plans:write" => Some(Scope::PlansWrite),
+            "sessions:read" => Some(Scope::SessionsRead),
+            "sessions:write" => Some(Scope::SessionsWrite),
+            "submissions:read" => Some(Scope::SubmissionsRead),
+            "roster:read" => Some(Scope::RosterRead),
+            "roster:write" => Some(Scope::RosterWrite),
+            _ => None,
+        }
+    }
+
+    // The scope a token needs for a route, if tokens can use it at all
+    // Account routes are left out so a token can't be used to make more tokens or take over the account
+    pub fn required_for(method: &Method, path: &str) -> Option<Scope> {
+        let read = method == Method::GET;
+        let mut segments = path.trim_start_matches('/').split('/');
+        match (segments.next(), segments.next()) {
+            (Some("plan"), _) if read => Some(Scope::PlansRead),
+            (Some("plan"), _) => Some(Scope::PlansWrite),
+            (Some("session"), Some("submitted")) if read => Some(Scope::SubmissionsRead),
+            // Saving code is for students in the session
+            (Some("session"), Some("save")) => None,
+            (Some("session"), _) if read => Some(Scope::SessionsRead),
+            (Some("session"), Some("new" | "classes")) => Some(Scope::SessionsWrite),
+            (Some("class"), _) | (Some("account"), Some("students")) if read => {
+                Some(Scope::RosterRead)
+            }
+            (Some("class"), _) | (Some("account"), Some("students")) => Some(Scope::RosterWrite),
+            _ => None,
+        }
+    }
+}
+
+// last_used_at is only moved on when it's at least this old, so scripts don't write on every call
+const TOKEN_LAST_USED_INTERVAL_SECONDS: i64 = 60;
+
+// Whoever is logged in, with their role as it is now rather than when they logged in
+// Taking one of these in a handler means it needs a logged in user
+// Sessions from before the account's epoch last moved on, like a password reset, are turned away
+// Scripts can send an API token as a bearer token instead, which only works on routes in its scopes
+#[derive(Debug)]
+pub struct Principal {
+    pub username: String,
+    pub role: Role,
+    // The login session behind the request, None for API tokens
+    pub login_session: Option<String>,
+}
+
+impl FromRequest for Principal {
+    type Error = CodeHarmonyResponseError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let db_pool = req.app_data::<web::Data<Pool>>().cloned();
+
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        if let Some(token) = bearer {
+            let scope = Scope::required_for(req.method(), req.path());
+            return Box::pin(async move { principal_from_token(db_pool, &token, scope).await });
+        }
+
+        let session = req.get_session();
+        let username = session.get::<String>("username");
+        let epoch = session
+            .get::<i32>("session_epoch")
+            .ok()
+            .flatten()
+            .unwrap_or(0);
+
+        Box::pin(async move {
+            let username = match username {
+                Ok(Some(username)) => username,
+                _ => return Err(CodeHarmonyResponseError::NotLoggedIn),
+            };
+
+            // Get db client
+            let client = db_pool
+                .ok_or(CodeHarmonyResponseError::DatabaseConnection)?
+                .get()
+                .await
+                .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+            const STATEMENT: &str =
+                "SELECT role, session_epoch FROM codeharmony.users WHERE username = $1";
+            let rows = client.query(STATEMENT, &[&username]).await.map_err(|e| {
+                eprintln!("{:?}", e);
+                CodeHarmonyResponseError::DatabaseQueryFailed
+            })?;
+
+            // The account might have gone since the session was made
+            let role = rows
+                .first()
+                .filter(|row| row.get::<_, i32>(1) == epoch)
+                .and_then(|row| Role::parse(row.get(0)));
+            let role = match role {
+                Some(role) => role,
+                None => {
+                    session.purge();
+                    return Err(CodeHarmonyResponseError::NotLoggedIn);
+                }
+            };
+
+            Ok(Principal {
+                username,
+                role,
+                login_session: current_session_id(&session),
+            })
+        })
+    }
+}
+
+// Look up who an API token belongs to, making sure it covers the route
+async fn principal_from_token(
+    db_pool: Option<web::Data<Pool>>,
+    token: &str,
+    scope: Option<Scope>,
+) -> Result<Principal, CodeHarmonyResponseError> {
+    let db_pool = db_pool.ok_or(CodeHarmonyResponseError::DatabaseConnection)?;
+
+    // Get db client
+    let client = db_pool
+        .get()
+        .await
+        .map_err(|_| CodeHarmonyResponseError::DatabaseConnection)?;
+
+    const STATEMENT: &str = "
+        SELECT t.token_id, t.username, u.role, t.scopes, t.last_used_at
+        FROM codeharmony.api_token t JOIN codeharmony.users u ON t.username = u.username
+        WHERE t.token_hash = $1 AND t.expires_at > $2
+    ";
+    const USED_STATEMENT: &str =
+        "UPDATE codeharmony.api_token SET last_used_at = $2 WHERE token_id = $1";
+
+    let now = Utc::now().naive_utc();
+    let rows = client
+        .query(STATEMENT, &[&hash_token(token), &now])
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::DatabaseQueryFailed
+        })?;
+    let row = rows.first().ok_or(CodeHarmonyResponseError::NotLoggedIn)?;
+
+    let scopes: Vec<String> = row.get(3);
+    let allowed = scope.is_some_and(|scope| scopes.iter().any(|granted| granted == scope.as_str()));
+    if !allowed {
+        return Err(CodeHarmonyResponseError::Forbidden);
+    }
+    let role = Role::parse(row.get(2)).ok_or(CodeHarmonyResponseError::NotLoggedIn)?;
+
+    let last_used_at: Option<NaiveDateTime> = row.get(4);
+    if last_used_at
+        .map(|last_used_at| (now - last_used_at).num_seconds() >= TOKEN_LAST_USED_INTERVAL_SECONDS)
+        .unwrap_or(true)
+    {
+        execute_in_background(
+            &db_pool,
+            USED_STATEMENT,
+            vec![Box::new(row.get::<_, String>(0)), Box::new(now)],
+        );
+    }
+
+    Ok(Principal {
+        username: row.get(1),
+        role,
+        login_session: None,
+    })
+}
+
+// Pull out the principal and make sure their role is allowed on the route
+async fn principal_with(
+    req: HttpRequest,
+    allowed: fn(Role) -> bool,
+) -> Result<Principal, CodeHarmonyResponseError> {
+    let principal = Principal::extract(&req).await?;
+    if !allowed(principal.role) {
+        return Err(CodeHarmonyResponseError::Forbidden);
+    }
+    Ok(principal)
+}
+
+macro_rules! role_extractor {
+    ($(#[$meta:meta])* $name:ident, $allowed:expr) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        pub struct $name(pub Principal);
+
+        impl FromRequest for $name {
+            type Error = CodeHarmonyResponseError;
+            type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+            fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+                let req = req.clone();
+                Box::pin(async move { principal_with(req, $allowed).await.map($name) })
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Principal;
+
+            fn deref(&self) -> &Principal {
+                &self.0
+            }
+        }
+    };
+}
+
+role_extractor!(
+    // Routes only students use, like joining a teacher
+    Student,
+    |role| role == Role::Student
+);
+role_extractor!(
+    // Routes for running lessons, open to teachers and admins
+    Teacher,
+    |role| role.can_teach()
+);
+role_extractor!(
+    // Routes for managing other accounts
+    Admin,
+    |role| role == Role::Admin
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_round_trip_through_the_database_names() {
+        for role in [Role::Student, Role::Teacher, Role::Admin] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(Role::parse("headteacher"), None);
+    }
+
+    #[test]
+    fn tokens_only_reach_routes_in_their_scopes() {
+        assert_eq!(
+            Scope::required_for(&Method::GET, "/plan/list"),
+            Some(Scope::PlansRead)
+        );
+        assert_eq!(
+            Scope::required_for(&Method::PUT, "/plan/info/Python"),
+            Some(Scope::PlansWrite)
+        );
+        assert_eq!(
+            Scope::required_for(&Method::GET, "/session/submitted/Python/Monday/Intro"),
+            Some(Scope::SubmissionsRead)
+        );
+        assert_eq!(
+            Scope::required_for(&Method::GET, "/account/students"),
+            Some(Scope::RosterRead)
+        );
+        assert_eq!(Scope::required_for(&Method::POST, "/account/tokens"), None);
+        assert_eq!(
+            Scope::required_for(&Method::POST, "/session/save/Python/Monday/amy/Intro"),
+            None
+        );
+        assert_eq!(Scope::required_for(&Method::GET, "/ws"), None);
+
+        for scope in [Scope::PlansRead, Scope::SubmissionsRead, Scope::RosterWrite] {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+    }
+}
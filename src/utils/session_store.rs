@@ -0,0 +1,263 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use actix_session::{
+    storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    Session,
+};
+use actix_web::{cookie::time::Duration, http::header, HttpRequest};
+use chrono::Utc;
+use deadpool_postgres::Pool;
+
+use crate::{
+    actors::ws_server::execute_in_background,
+    endpoints::password_reset::{generate_token, hash_token},
+    utils::{error::CodeHarmonyResponseError, login_throttle::client_ip},
+};
+
+// last_seen is only moved on when it's at least this old, so most requests don't write
+const LAST_SEEN_INTERVAL_SECONDS: i64 = 60;
+
+// Longest user agent kept, they can be very long and are only there to tell devices apart
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+// Keeps sessions in Postgres so they can be listed and revoked
+// The cookie only holds a random key, and only a hash of the key is stored
+pub struct PostgresSessionStore {
+    db_pool: Pool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(db_pool: Pool) -> Self {
+        Self { db_pool }
+    }
+}
+
+// The parts of a session's state that get their own columns, so sessions can be listed
+struct SessionMetadata {
+    session_id: Option<String>,
+    username: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+impl SessionMetadata {
+    // Session values are stored as JSON
+    fn from_state(state: &HashMap<String, String>) -> Self {
+        let get = |key: &str| {
+            state
+                .get(key)
+                .and_then(|value| serde_json::from_str::<String>(value).ok())
+        };
+        Self {
+            session_id: get("session_id"),
+            username: get("username"),
+            user_agent: get("user_agent"),
+            ip: get("ip"),
+        }
+    }
+}
+
+// Log a session in, storing where it came from so it can be recognised in the session list
+// A fresh key is made too, so a key picked before logging in can't be used after
+pub fn start_session(
+    session: &Session,
+    req: &HttpRequest,
+    username: &str,
+    epoch: i32,
+) -> Result<(), CodeHarmonyResponseError> {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or("")
+        .chars()
+        .take(MAX_USER_AGENT_LENGTH)
+        .collect::<String>();
+
+    session.renew();
+    session
+        .insert("username", username)
+        .and_then(|_| session.insert("session_epoch", epoch))
+        .and_then(|_| session.insert("session_id", uuid::Uuid::new_v4().to_string()))
+        .and_then(|_| session.insert("user_agent", user_agent))
+        .and_then(|_| session.insert("ip", client_ip(req)))
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            CodeHarmonyResponseError::InternalError(5, "Couldn't save session".to_owned())
+        })
+}
+
+// The id the session list knows this session by, if it's been logged in since sessions had them
+pub fn current_session_id(session: &Session) -> Option<String> {
+    session.get::<String>("session_id").ok().flatten()
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for PostgresSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        const STATEMENT: &str = "
+            SELECT state, last_seen FROM codeharmony.user_session
+            WHERE key_hash = $1 AND expires_at > $2
+        ";
+        const SEEN_STATEMENT: &str =
+            "UPDATE codeharmony.user_session SET last_seen = $2 WHERE key_hash = $1";
+
+        let client = self
+            .db_pool
+            .get()
+            .await
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?;
+
+        let key_hash = hash_token(session_key.as_ref());
+        let now = Utc::now().naive_utc();
+        let rows = client
+            .query(STATEMENT, &[&key_hash, &now])
+            .await
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?;
+        let row = match rows.first() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let state = serde_json::from_str(row.get(0))
+            .map_err(|e| LoadError::Deserialization(anyhow::anyhow!(e)))?;
+
+        let last_seen: chrono::NaiveDateTime = row.get(1);
+        if (now - last_seen).num_seconds() >= LAST_SEEN_INTERVAL_SECONDS {
+            execute_in_background(
+                &self.db_pool,
+                SEEN_STATEMENT,
+                vec![Box::new(key_hash), Box::new(now)],
+            );
+        }
+
+        Ok(Some(state))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        // Tidies away expired sessions while it's here
+        const STATEMENT: &str = "
+            WITH expired AS (
+                DELETE FROM codeharmony.user_session WHERE expires_at < $8
+            )
+            INSERT INTO codeharmony.user_session
+            (key_hash, session_id, username, state, user_agent, ip, created_at, last_seen, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $8, $8, $7)
+        ";
+
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| SaveError::Serialization(anyhow::anyhow!(e)))?;
+        let metadata = SessionMetadata::from_state(&session_state);
+
+        let client = self
+            .db_pool
+            .get()
+            .await
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+
+        let session_key = generate_token();
+        let now = Utc::now().naive_utc();
+        let expires_at = now + chrono::Duration::seconds(ttl.whole_seconds());
+        client
+            .execute(
+                STATEMENT,
+                &[
+                    &hash_token(&session_key),
+                    &metadata.session_id,
+                    &metadata.username,
+                    &state,
+                    &metadata.user_agent,
+                    &metadata.ip,
+                    &expires_at,
+                    &now,
+                ],
+            )
+            .await
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+
+        SessionKey::try_from(session_key).map_err(|e| SaveError::Other(anyhow::anyhow!(e)))
+    }
+
+    // A session revoked while its request was running stays revoked rather than being saved again
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        const STATEMENT: &str = "
+            UPDATE codeharmony.user_session
+            SET session_id = $2, username = $3, state = $4, user_agent = $5, ip = $6,
+            last_seen = $7, expires_at = $8
+            WHERE key_hash = $1
+        ";
+
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| UpdateError::Serialization(anyhow::anyhow!(e)))?;
+        let metadata = SessionMetadata::from_state(&session_state);
+
+        let client = self
+            .db_pool
+            .get()
+            .await
+            .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?;
+
+        let now = Utc::now().naive_utc();
+        let expires_at = now + chrono::Duration::seconds(ttl.whole_seconds());
+        client
+            .execute(
+                STATEMENT,
+                &[
+                    &hash_token(session_key.as_ref()),
+                    &metadata.session_id,
+                    &metadata.username,
+                    &state,
+                    &metadata.user_agent,
+                    &metadata.ip,
+                    &now,
+                    &expires_at,
+                ],
+            )
+            .await
+            .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?;
+
+        Ok(session_key)
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        const STATEMENT: &str = "DELETE FROM codeharmony.user_session WHERE key_hash = $1";
+
+        let client = self.db_pool.get().await?;
+        client
+            .execute(STATEMENT, &[&hash_token(session_key.as_ref())])
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_comes_from_json_values() {
+        let state = HashMap::from([
+            ("username".to_owned(), "\"amyp\"".to_owned()),
+            ("session_epoch".to_owned(), "2".to_owned()),
+            ("user_agent".to_owned(), "\"Firefox\"".to_owned()),
+        ]);
+        let metadata = SessionMetadata::from_state(&state);
+
+        assert_eq!(metadata.username.as_deref(), Some("amyp"));
+        assert_eq!(metadata.user_agent.as_deref(), Some("Firefox"));
+        assert_eq!(metadata.session_id, None);
+        assert_eq!(metadata.ip, None);
+    }
+}